@@ -0,0 +1,123 @@
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use futures::StreamExt;
+use hyper::header::{self, HeaderName, HeaderValue};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use worky_common::access_log::{new_request_id, AccessEntry};
+use worky_common::workers::WorkerHandle;
+use worky_ops::ext::console::{push_log, LogType};
+
+use crate::client_ip;
+
+static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Records each request in the worker's access log once its body has been
+/// sent, tagging request and response with an `x-request-id`.
+pub(crate) async fn access_log(
+  State(handle): State<Arc<WorkerHandle>>,
+  mut req: Request,
+  next: Next,
+) -> Response {
+  if handle.access_log.is_none() {
+    return next.run(req).await;
+  }
+
+  let start = Instant::now();
+  let header = |name| {
+    req
+      .headers()
+      .get(name)
+      .and_then(|v| v.to_str().ok())
+      .map(str::to_owned)
+  };
+
+  let request_id = header(&X_REQUEST_ID).unwrap_or_else(new_request_id);
+  let mut entry = AccessEntry {
+    time: SystemTime::now(),
+    request_id,
+    client_ip: client_ip(&req),
+    caller: None,
+    method: req.method().to_string(),
+    path: req
+      .uri()
+      .path_and_query()
+      .map_or("/".to_string(), |p| p.to_string()),
+    version: format!("{:?}", req.version()),
+    status: 0,
+    bytes: 0,
+    duration: Default::default(),
+    referer: header(&header::REFERER),
+    user_agent: header(&header::USER_AGENT),
+  };
+
+  let request_id = HeaderValue::from_str(&entry.request_id).ok();
+  if let Some(id) = &request_id {
+    req.headers_mut().insert(X_REQUEST_ID.clone(), id.clone());
+  }
+
+  let mut res = next.run(req).await;
+  entry.status = res.status().as_u16();
+  if let Some(id) = request_id {
+    res.headers_mut().insert(X_REQUEST_ID.clone(), id);
+  }
+
+  let mut pending = PendingEntry {
+    handle,
+    entry,
+    start,
+  };
+  res.map(|body| {
+    Body::from_stream(body.into_data_stream().map(move |chunk| {
+      if let Ok(chunk) = &chunk {
+        pending.entry.bytes += chunk.len() as u64;
+      }
+      chunk
+    }))
+  })
+}
+
+/// The entry of a connection to a `tcp` worker, completed once it closed.
+/// There is no request line, so it reads as a `CONNECT` to the address the
+/// connection came in on; the bytes are those sent to the client.
+pub(crate) fn connection_entry(
+  remote_address: SocketAddr,
+  local_address: SocketAddr,
+) -> AccessEntry {
+  AccessEntry {
+    time: SystemTime::now(),
+    request_id: new_request_id(),
+    client_ip: Some(remote_address.ip()),
+    caller: None,
+    method: "CONNECT".to_string(),
+    path: local_address.to_string(),
+    version: "TCP".to_string(),
+    status: 0,
+    bytes: 0,
+    duration: Default::default(),
+    referer: None,
+    user_agent: None,
+  }
+}
+
+/// An entry waiting for its body to finish; written out when dropped, so
+/// responses the client abandons are logged too.
+struct PendingEntry {
+  handle: Arc<WorkerHandle>,
+  entry: AccessEntry,
+  start: Instant,
+}
+
+impl Drop for PendingEntry {
+  fn drop(&mut self) {
+    let Some(log) = &self.handle.access_log else {
+      return;
+    };
+    self.entry.duration = self.start.elapsed();
+    let line = log.record(&self.entry);
+    push_log(&self.handle.addr, &self.handle.name, &line, LogType::Access);
+  }
+}
@@ -0,0 +1,86 @@
+use hyper::header::{self, HeaderMap};
+use hyper::{StatusCode, Version};
+use tower_http::compression::predicate::{Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use worky_common::config::{CompressionAlgorithm, CompressionConfig};
+
+/// Builds the compression middleware for a worker's listener, `None` when the
+/// worker has compression turned off.
+pub(crate) fn layer(config: &CompressionConfig) -> Option<CompressionLayer<impl Predicate>> {
+  if !config.enabled || config.algorithms.is_empty() {
+    return None;
+  }
+
+  let offers = |algorithm| config.algorithms.contains(&algorithm);
+  let layer = CompressionLayer::new()
+    .gzip(offers(CompressionAlgorithm::Gzip))
+    .br(offers(CompressionAlgorithm::Br))
+    .zstd(offers(CompressionAlgorithm::Zstd))
+    .deflate(false)
+    .compress_when(SizeAbove::new(config.min_size).and(compressible));
+  Some(layer)
+}
+
+/// Whether the response's content type is worth compressing. Media and
+/// archives are compressed already, and event streams must not be buffered.
+fn compressible(
+  _: StatusCode,
+  _: Version,
+  headers: &HeaderMap,
+  _: &hyper::http::Extensions,
+) -> bool {
+  let Some(content_type) = headers
+    .get(header::CONTENT_TYPE)
+    .and_then(|v| v.to_str().ok())
+  else {
+    return false;
+  };
+  let essence = content_type
+    .split(';')
+    .next()
+    .unwrap_or_default()
+    .trim()
+    .to_ascii_lowercase();
+
+  match essence.split_once('/') {
+    Some(("text", "event-stream")) => false,
+    Some(("text", _)) => true,
+    Some(("font", subtype)) => matches!(subtype, "ttf" | "otf"),
+    Some(("application", subtype)) => {
+      matches!(
+        subtype,
+        "json" | "javascript" | "xml" | "wasm" | "manifest+json" | "x-www-form-urlencoded"
+      ) || subtype.ends_with("+json")
+        || subtype.ends_with("+xml")
+    }
+    Some(("image", subtype)) => matches!(subtype, "svg+xml" | "x-icon" | "bmp"),
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn check(content_type: &str) -> bool {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+    compressible(
+      StatusCode::OK,
+      Version::HTTP_11,
+      &headers,
+      &Default::default(),
+    )
+  }
+
+  #[test]
+  fn test_compressible_types() {
+    assert!(check("text/html; charset=utf-8"));
+    assert!(check("application/json"));
+    assert!(check("application/ld+json"));
+    assert!(check("image/svg+xml"));
+    assert!(!check("image/png"));
+    assert!(!check("text/event-stream"));
+    assert!(!check("application/zip"));
+  }
+}
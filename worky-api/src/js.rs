@@ -0,0 +1,178 @@
+use deno_core::error::{CoreError, CoreErrorKind, JsError};
+use deno_core::v8;
+use deno_core::JsRuntime;
+use hyper::header::{HeaderMap, HOST};
+use hyper::http::uri::Authority;
+use hyper::Uri;
+use worky_common::error::WorkerError;
+
+/// Looks up a function installed by the JS side with `registerHostHook`.
+pub(crate) fn host_hook<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  name: &str,
+) -> Result<v8::Local<'s, v8::Function>, WorkerError> {
+  let global = scope.get_current_context().global(scope);
+  let hooks_key = v8::String::new(scope, "__worky").unwrap();
+  let hooks: v8::Local<v8::Object> = global
+    .get(scope, hooks_key.into())
+    .and_then(|v| v.try_into().ok())
+    .ok_or_else(|| anyhow::anyhow!("host hooks are not installed"))?;
+
+  let key = v8::String::new(scope, name).unwrap();
+  let hook = hooks
+    .get(scope, key.into())
+    .and_then(|v| v.try_into().ok())
+    .ok_or_else(|| anyhow::anyhow!("host hook {name} is not installed"))?;
+  Ok(hook)
+}
+
+/// Reads `obj[key]`, `None` if the lookup threw.
+pub(crate) fn get_property<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  obj: v8::Local<v8::Object>,
+  key: &str,
+) -> Option<v8::Local<'s, v8::Value>> {
+  let key = v8::String::new(scope, key).unwrap();
+  obj.get(scope, key.into())
+}
+
+/// Turns whatever the `TryCatch` caught into a [`WorkerError::Exception`].
+pub(crate) fn exception_error(tc: &mut v8::TryCatch<v8::HandleScope>) -> WorkerError {
+  match tc.exception() {
+    Some(exception) => {
+      let js_error = JsError::from_v8_exception(tc, exception);
+      WorkerError::Exception {
+        message: js_error.exception_message.clone(),
+        stack: js_error.stack.clone(),
+      }
+    }
+    None => WorkerError::Internal(anyhow::anyhow!("execution was terminated")),
+  }
+}
+
+/// Keeps the JS exception and its stack when a core error came from JS.
+pub(crate) fn core_error(err: CoreError) -> WorkerError {
+  match err.into_kind() {
+    CoreErrorKind::Js(js_error) => WorkerError::Exception {
+      message: js_error.exception_message.clone(),
+      stack: js_error.stack.clone(),
+    },
+    kind => WorkerError::Internal(anyhow::anyhow!("{kind}")),
+  }
+}
+
+/// Module loading reports through `anyhow`; dig the core error back out so
+/// exceptions thrown while evaluating the module keep their stack.
+pub(crate) fn module_error(err: anyhow::Error) -> WorkerError {
+  match err.downcast::<CoreError>() {
+    Ok(err) => core_error(err),
+    Err(err) => WorkerError::Internal(err),
+  }
+}
+
+/// Moves `bytes` into a new `Uint8Array`.
+pub(crate) fn uint8_array<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  bytes: Vec<u8>,
+) -> v8::Local<'s, v8::Uint8Array> {
+  let len = bytes.len();
+  let store = v8::ArrayBuffer::new_backing_store_from_vec(bytes).make_shared();
+  let buffer = v8::ArrayBuffer::with_backing_store(scope, &store);
+  v8::Uint8Array::new(scope, buffer, 0, len).unwrap()
+}
+
+/// Awaits `value` if it is a promise, otherwise hands it back untouched.
+pub(crate) async fn await_value(
+  runtime: &mut JsRuntime,
+  value: v8::Global<v8::Value>,
+) -> Result<v8::Global<v8::Value>, WorkerError> {
+  let is_promise = {
+    let scope = &mut runtime.handle_scope();
+    v8::Local::new(scope, value.clone()).is_promise()
+  };
+
+  if is_promise {
+    runtime.resolve(value).await.map_err(core_error)
+  } else {
+    Ok(value)
+  }
+}
+
+/// The URL a worker sees for a request: its own authority under HTTP/2, or
+/// else the `Host` header, since the address the worker listens on may not
+/// be one a URL can hold (e.g. a Unix socket).
+pub(crate) fn request_url(uri: &Uri, headers: &HeaderMap) -> String {
+  let host = headers
+    .get(HOST)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.parse::<Authority>().ok());
+  let authority = uri
+    .authority()
+    .cloned()
+    .or(host)
+    .map_or_else(|| "localhost".to_string(), |a| a.to_string());
+  let path = uri.path_and_query().map_or("/", |p| p.as_str());
+  format!("http://{authority}{path}")
+}
+
+pub(crate) fn build_js_request<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  parts: &hyper::http::request::Parts,
+  body: &hyper::body::Bytes,
+) -> Result<v8::Local<'s, v8::Object>, WorkerError> {
+  let uint8 = uint8_array(scope, body.to_vec());
+
+  let global = scope.get_current_context().global(scope);
+
+  let req_key = v8::String::new(scope, "Request").unwrap();
+  let request_ctor: v8::Local<v8::Function> = global
+    .get(scope, req_key.into())
+    .and_then(|v| v.try_into().ok())
+    .ok_or_else(|| anyhow::anyhow!("Request is not defined"))?;
+
+  let url = v8::String::new(scope, &request_url(&parts.uri, &parts.headers)).unwrap();
+  let init_obj = v8::Object::new(scope);
+
+  let meth = &parts.method;
+
+  let method = v8::String::new(scope, meth.as_str()).unwrap();
+  let met_key = v8::String::new(scope, "method").unwrap();
+  init_obj.set(scope, met_key.into(), method.into());
+
+  let headers_key = v8::String::new(scope, "Headers").unwrap();
+  let headers_ctor: v8::Local<v8::Function> = global
+    .get(scope, headers_key.into())
+    .and_then(|v| v.try_into().ok())
+    .ok_or_else(|| anyhow::anyhow!("Headers is not defined"))?;
+
+  let js_headers = headers_ctor
+    .new_instance(scope, &[])
+    .ok_or_else(|| anyhow::anyhow!("could not construct Headers"))?;
+
+  let append_key = v8::String::new(scope, "append").unwrap();
+  let append_fn: v8::Local<v8::Function> = js_headers
+    .get(scope, append_key.into())
+    .and_then(|v| v.try_into().ok())
+    .ok_or_else(|| anyhow::anyhow!("Headers.append is not defined"))?;
+
+  for (key, value) in parts.headers.iter() {
+    let k = v8::String::new(scope, key.as_str()).unwrap();
+    let v = v8::String::new(scope, &String::from_utf8_lossy(value.as_bytes())).unwrap();
+
+    append_fn.call(scope, js_headers.into(), &[k.into(), v.into()]);
+  }
+
+  let headers_init_key = v8::String::new(scope, "headers").unwrap();
+  init_obj.set(scope, headers_init_key.into(), js_headers.into());
+
+  if meth != "GET" && meth != "HEAD" {
+    let bod_key = v8::String::new(scope, "body").unwrap();
+    init_obj.set(scope, bod_key.into(), uint8.into());
+  }
+
+  let tc = &mut v8::TryCatch::new(scope);
+  match request_ctor.new_instance(tc, &[url.into(), init_obj.into()]) {
+    Some(request) => Ok(request),
+    None => Err(WorkerError::BadRequest(exception_error(tc).to_string())),
+  }
+}
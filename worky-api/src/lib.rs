@@ -1,447 +1,1610 @@
-use axum::Router;
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::{ConnectInfo, FromRequestParts};
 use deno_core::v8;
+use futures::SinkExt;
+use http_body_util::{LengthLimitError, Limited};
 use hyper::Request;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel;
-use futures::SinkExt;
-
-use deno_core::JsRuntime;
-use worky_common::workers::{WorkerHandle, WorkerRequest};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::{oneshot, Semaphore};
+
+use deno_core::{serde_v8, JsRuntime, ResourceId, ToJsBuffer};
+use serde::Serialize;
+use worky_common::access_log::{AccessEntry, AccessLog};
+use worky_common::assets;
+use worky_common::broadcast::BroadcastMessage;
+use worky_common::config::{LimitsConfig, RateLimitKey, TailConsumer, WorkerConfig};
+use worky_common::error::WorkerError;
+use worky_common::queue::{QueueMessage, QueueOutcome};
+use worky_common::ratelimit::RateLimiter;
+use worky_common::tail::{TailEvent, TailEventInfo};
+use worky_common::websocket::{AcceptedSocket, HeldSockets, SocketEvent, SocketMessage};
+use worky_common::workers::{
+  CallChain, ConnectRequest, DurableObjectCall, DurableObjectTarget, WorkerHandle, WorkerMessage,
+  WorkerRequest,
+};
+use worky_ops::ext::broadcast::BroadcastHost;
+use worky_ops::ext::console::{push_log, LogType};
+use worky_ops::ext::sockets::{BytesSent, ConnectionHost};
 use worky_runtime::WorkyRuntime;
 
+mod access;
+mod compression;
+mod js;
+mod listen;
+mod tail;
+mod websocket;
+use js::{
+  await_value, build_js_request, core_error, exception_error, get_property, host_hook,
+  module_error, uint8_array,
+};
+pub use listen::{bind, listen_to_addr, serve, WorkerListener};
+use tail::Trace;
+
+type Pumper<'a> = std::pin::Pin<Box<dyn std::future::Future<Output = ()> + 'a>>;
+
+fn invalid_response(msg: &str) -> WorkerError {
+  WorkerError::InvalidResponse(msg.to_string())
+}
+
+/// Turns the `Response` a handler produced into a hyper response. The body
+/// goes through deno_fetch's own extraction, so anything `new Response()`
+/// accepts works. Streamed bodies come back with a pumper future that has to
+/// be driven on the worker thread while the client reads the body.
 pub async fn parse_js_response<'a>(
   runtime: &'a mut JsRuntime,
   res_val: v8::Global<v8::Value>,
-) -> anyhow::Result<(
-  hyper::Response<axum::body::Body>,
-  Option<std::pin::Pin<Box<dyn std::future::Future<Output = ()> + 'a>>>,
-)> {
-  let (status, headers, body_val_global) = {
+) -> Result<(hyper::Response<axum::body::Body>, Option<Pumper<'a>>), WorkerError> {
+  enum BodyState {
+    Empty,
+    Done(Vec<u8>),
+    Stream(v8::Global<v8::Object>),
+  }
+
+  let (status, headers, length, body_state, socket) = {
     let scope = &mut runtime.handle_scope();
     let res_val = v8::Local::new(scope, res_val);
-    let response_obj: v8::Local<v8::Object> = res_val
+    let hook = host_hook(scope, "extractResponse")?;
+    let recv = v8::undefined(scope).into();
+
+    let tc = &mut v8::TryCatch::new(scope);
+    let extracted = match hook.call(tc, recv, &[res_val]) {
+      Some(extracted) => extracted,
+      None => return Err(exception_error(tc)),
+    };
+    let extracted: v8::Local<v8::Object> = extracted
       .try_into()
-      .map_err(|_| anyhow::anyhow!("fetch() returned non-object"))?;
+      .map_err(|_| invalid_response("fetch() must return a Response"))?;
+
+    let status = get_property(tc, extracted, "status")
+      .and_then(|v| v.uint32_value(tc))
+      .unwrap_or(200);
+
+    let header_list: v8::Local<v8::Array> = get_property(tc, extracted, "headers")
+      .and_then(|v| v.try_into().ok())
+      .ok_or_else(|| invalid_response("Response has no header list"))?;
+    let mut headers = Vec::with_capacity(header_list.length() as usize);
+    for i in 0..header_list.length() {
+      let pair = header_list
+        .get_index(tc, i)
+        .and_then(|v| v8::Local::<v8::Array>::try_from(v).ok());
+      let Some(pair) = pair else {
+        continue;
+      };
+      if let (Some(key), Some(val)) = (pair.get_index(tc, 0), pair.get_index(tc, 1)) {
+        headers.push((key.to_rust_string_lossy(tc), val.to_rust_string_lossy(tc)));
+      }
+    }
 
-    let status_key = v8::String::new(scope, "status").unwrap();
-    let status_val = response_obj.get(scope, status_key.into()).unwrap();
-    let status = status_val.uint32_value(scope).unwrap_or(200);
+    let length = get_property(tc, extracted, "length")
+      .filter(|v| v.is_number())
+      .and_then(|v| v.integer_value(tc))
+      .and_then(|v| u64::try_from(v).ok());
 
-    let headers_key = v8::String::new(scope, "headers").unwrap();
-    let js_headers_val = response_obj.get(scope, headers_key.into()).unwrap();
-    let js_headers: v8::Local<v8::Object> = js_headers_val
-      .try_into()
-      .expect("Response.headers must be an object");
+    let body = get_property(tc, extracted, "body")
+      .and_then(|v| v8::Local::<v8::Uint8Array>::try_from(v).ok());
+    let reader =
+      get_property(tc, extracted, "reader").and_then(|v| v8::Local::<v8::Object>::try_from(v).ok());
 
-    let entries_key = v8::String::new(scope, "entries").unwrap();
-    let entries_fn: v8::Local<v8::Function> = js_headers
-      .get(scope, entries_key.into())
-      .unwrap()
-      .try_into()
-      .unwrap();
+    let body_state = match (body, reader) {
+      (Some(uint8), _) => {
+        let mut buf = vec![0u8; uint8.byte_length()];
+        uint8.copy_contents(&mut buf);
+        BodyState::Done(buf)
+      }
+      (None, Some(reader)) => BodyState::Stream(v8::Global::new(tc, reader)),
+      (None, None) => BodyState::Empty,
+    };
 
-    let entries_iter_val = entries_fn.call(scope, js_headers.into(), &[]).unwrap();
-    let entries_iter: v8::Local<v8::Object> = entries_iter_val
-      .try_into()
-      .expect("Response.headers must be an object");
+    // Responses from `new Response(null, { webSocket })` carry a socket.
+    let socket = match host_hook(tc, "responseWebSocket") {
+      Ok(hook) => hook
+        .call(tc, recv, &[res_val])
+        .filter(|id| id.is_number())
+        .and_then(|id| id.uint32_value(tc)),
+      Err(_) => None,
+    };
 
-    let next_key = v8::String::new(scope, "next").unwrap();
-    let mut headers = Vec::new();
+    (status, headers, length, body_state, socket)
+  };
 
-    loop {
-      let next_fn: v8::Local<v8::Function> = entries_iter
-        .get(scope, next_key.into())
-        .unwrap()
-        .try_into()
-        .unwrap();
+  let status = u16::try_from(status)
+    .ok()
+    .and_then(|status| hyper::StatusCode::from_u16(status).ok())
+    .ok_or_else(|| WorkerError::InvalidResponse(format!("invalid status code {status}")))?;
 
-      let item = next_fn.call(scope, entries_iter.into(), &[]).unwrap();
-      let item_obj = v8::Local::<v8::Object>::try_from(item).unwrap();
+  let mut builder = hyper::Response::builder().status(status);
+  for (k, v) in &headers {
+    builder = builder.header(k, v);
+  }
+  if let Some(socket) = socket {
+    builder = builder.extension(AcceptedSocket(socket));
+  }
 
-      let done_key = v8::String::new(scope, "done").unwrap();
-      let done_val = item_obj.get(scope, done_key.into()).unwrap();
-      if done_val.is_true() {
-        break;
-      }
+  // 1xx, 204 and 304 responses never carry a body, so no length either.
+  let bodiless = status.is_informational()
+    || status == hyper::StatusCode::NO_CONTENT
+    || status == hyper::StatusCode::NOT_MODIFIED;
+  let has_length = headers
+    .iter()
+    .any(|(k, _)| k.eq_ignore_ascii_case("content-length"));
+  if let Some(length) = length.filter(|_| !bodiless && !has_length) {
+    builder = builder.header(hyper::header::CONTENT_LENGTH, length);
+  }
+
+  let into_response = |builder: hyper::http::response::Builder, body: axum::body::Body| {
+    builder
+      .body(body)
+      .map_err(|e| WorkerError::InvalidResponse(e.to_string()))
+  };
+
+  match body_state {
+    BodyState::Empty => Ok((into_response(builder, axum::body::Body::empty())?, None)),
+    BodyState::Done(bytes) => Ok((into_response(builder, axum::body::Body::from(bytes))?, None)),
+    BodyState::Stream(reader_global) => {
+      let (mut tx, rx) =
+        futures::channel::mpsc::channel::<Result<axum::body::Bytes, WorkerError>>(10);
+      let body = axum::body::Body::from_stream(rx);
+
+      let pumper: Pumper<'a> = Box::pin(async move {
+        loop {
+          let read_result = {
+            let scope = &mut runtime.handle_scope();
+            let read_key = v8::String::new(scope, "read").unwrap();
+            let reader = v8::Local::new(scope, reader_global.clone());
+            let read_fn = reader
+              .get(scope, read_key.into())
+              .and_then(|v| v8::Local::<v8::Function>::try_from(v).ok());
+
+            match read_fn {
+              Some(read_fn) => {
+                let tc = &mut v8::TryCatch::new(scope);
+                match read_fn.call(tc, reader.into(), &[]) {
+                  Some(promise) => Ok(v8::Global::new(tc, promise)),
+                  None => Err(exception_error(tc)),
+                }
+              }
+              None => Err(invalid_response("reader has no read()")),
+            }
+          };
+
+          let promise_global = match read_result {
+            Ok(promise) => promise,
+            Err(e) => {
+              let _ = tx.send(Err(e)).await;
+              break;
+            }
+          };
+
+          let result_global = match await_value(runtime, promise_global).await {
+            Ok(r) => r,
+            Err(e) => {
+              let _ = tx.send(Err(e)).await;
+              break;
+            }
+          };
+
+          let chunk = {
+            let scope = &mut runtime.handle_scope();
+            let result: v8::Local<v8::Value> = v8::Local::new(scope, result_global);
+            let Some(result_obj) = result.to_object(scope) else {
+              break;
+            };
 
-      let value_key = v8::String::new(scope, "value").unwrap();
-      let pair = item_obj.get(scope, value_key.into()).unwrap();
-      let pair_arr = v8::Local::<v8::Array>::try_from(pair).unwrap();
+            let done_key = v8::String::new(scope, "done").unwrap();
+            let done = result_obj.get(scope, done_key.into());
+            if done.is_some_and(|done| done.is_true()) {
+              break;
+            }
+
+            let value_key = v8::String::new(scope, "value").unwrap();
+            let value = result_obj.get(scope, value_key.into());
+            match value.and_then(|v| v8::Local::<v8::Uint8Array>::try_from(v).ok()) {
+              Some(uint8) => {
+                let mut buf = vec![0u8; uint8.byte_length()];
+                uint8.copy_contents(&mut buf);
+                Ok(axum::body::Bytes::from(buf))
+              }
+              None => Err(invalid_response(
+                "ReadableStream chunks must be Uint8Arrays",
+              )),
+            }
+          };
 
-      let key = pair_arr.get_index(scope, 0).unwrap();
-      let val = pair_arr.get_index(scope, 1).unwrap();
+          let failed = chunk.is_err();
+          if tx.send(chunk).await.is_err() || failed {
+            break;
+          }
+        }
+      });
 
-      headers.push((
-        key.to_rust_string_lossy(scope),
-        val.to_rust_string_lossy(scope),
-      ));
+      Ok((into_response(builder, body)?, Some(pumper)))
     }
+  }
+}
 
-    let body_key = v8::String::new(scope, "body").unwrap();
-    let body_val = response_obj.get(scope, body_key.into()).unwrap();
-    let body_val_global = v8::Global::new(scope, body_val);
+/// Records a failure in the worker's log, keeping the JS stack when there is one.
+fn log_error(addr: &str, name: &str, err: &WorkerError) {
+  let message = match err.stack() {
+    Some(stack) => stack.to_string(),
+    None => err.to_string(),
+  };
+  eprintln!("Error: {message}");
+  push_log(addr, name, &message, LogType::Error);
+}
 
-    (status, headers, body_val_global)
+fn should_pass_through(runtime: &mut JsRuntime, ctx: &v8::Global<v8::Value>) -> bool {
+  let scope = &mut runtime.handle_scope();
+  let Ok(hook) = host_hook(scope, "shouldPassThrough") else {
+    return false;
   };
+  let recv = v8::undefined(scope).into();
+  let ctx = v8::Local::new(scope, ctx.clone());
+  hook
+    .call(scope, recv, &[ctx])
+    .is_some_and(|passed| passed.is_true())
+}
 
-  let mut builder = hyper::Response::builder();
-  for (k, v) in headers {
+/// Forwards the original request to the configured origin, used when the
+/// handler throws after calling `ctx.passThroughOnException()`.
+async fn pass_through(
+  origin: &str,
+  parts: &hyper::http::request::Parts,
+  body: hyper::body::Bytes,
+) -> Result<hyper::Response<axum::body::Body>, WorkerError> {
+  let path = parts
+    .uri
+    .path_and_query()
+    .map(|p| p.as_str())
+    .unwrap_or("/");
+  let url = format!("{}{}", origin.trim_end_matches('/'), path);
+
+  let mut headers = parts.headers.clone();
+  headers.remove(hyper::header::HOST);
+
+  let upstream = reqwest::Client::new()
+    .request(parts.method.clone(), url)
+    .headers(headers)
+    .body(body)
+    .send()
+    .await
+    .map_err(|e| WorkerError::Upstream(e.to_string()))?;
+
+  let mut builder = hyper::Response::builder().status(upstream.status());
+  for (k, v) in upstream.headers() {
     builder = builder.header(k, v);
   }
-  builder = builder.status(hyper::StatusCode::from_u16(status as u16).unwrap());
+  builder
+    .body(axum::body::Body::from_stream(upstream.bytes_stream()))
+    .map_err(|e| WorkerError::Upstream(e.to_string()))
+}
 
-  let body_bytes = {
-    enum BodyState {
-      Done(Vec<u8>),
-      Stream(v8::Global<v8::Object>),
-      Error(anyhow::Error),
-    }
+/// Builds the `env` object handed to every handler of this worker.
+fn create_env(runtime: &mut JsRuntime) -> Result<v8::Global<v8::Value>, WorkerError> {
+  let scope = &mut runtime.handle_scope();
+  let recv = v8::undefined(scope).into();
+  let hook = host_hook(scope, "createEnv")?;
+  let tc = &mut v8::TryCatch::new(scope);
+  match hook.call(tc, recv, &[]) {
+    Some(env) => Ok(v8::Global::new(tc, env)),
+    None => Err(exception_error(tc)),
+  }
+}
 
-    let body_state = {
-      let scope = &mut runtime.handle_scope();
-      let body_val = v8::Local::new(scope, body_val_global.clone());
+/// Hands the module's exports and `env` to the JS side, which builds the
+/// worker's durable objects out of them.
+fn set_worker_module(
+  runtime: &mut JsRuntime,
+  exports: &v8::Global<v8::Object>,
+  env_global: &v8::Global<v8::Value>,
+) -> Result<(), WorkerError> {
+  let scope = &mut runtime.handle_scope();
+  let exports = v8::Local::new(scope, exports.clone());
+  let env = v8::Local::new(scope, env_global.clone());
+  let recv = v8::undefined(scope).into();
+  let hook = host_hook(scope, "setWorkerModule")?;
+  let tc = &mut v8::TryCatch::new(scope);
+  match hook.call(tc, recv, &[exports.into(), env]) {
+    Some(_) => Ok(()),
+    None => Err(exception_error(tc)),
+  }
+}
 
-      if body_val.is_string() {
-        BodyState::Done(body_val.to_rust_string_lossy(scope).as_bytes().to_vec())
-      } else if body_val.is_uint8_array() {
-        let uint8: v8::Local<v8::Uint8Array> = body_val.try_into().unwrap();
-        let len = uint8.byte_length();
-        let mut buf = vec![0u8; len];
-        uint8.copy_contents(&mut buf);
-        BodyState::Done(buf)
-      } else {
-        let get_reader_key = v8::String::new(scope, "getReader").unwrap();
-        let has_reader = if let Some(body_obj) = body_val.to_object(scope) {
-          body_obj.has(scope, get_reader_key.into()).unwrap_or(false)
-        } else {
-          false
-        };
+/// Looks up the handler for an event on the module's default export, either
+/// an object of handlers or a `WorkerEntrypoint` class.
+fn find_handler(
+  runtime: &mut JsRuntime,
+  exports: &v8::Global<v8::Object>,
+  name: &str,
+) -> Result<Option<v8::Global<v8::Function>>, WorkerError> {
+  let scope = &mut runtime.handle_scope();
+  let exports = v8::Local::new(scope, exports.clone());
+  let name = v8::String::new(scope, name).unwrap();
+  let hook = host_hook(scope, "entrypointHandler")?;
+  let recv = v8::undefined(scope).into();
+
+  let tc = &mut v8::TryCatch::new(scope);
+  match hook.call(tc, recv, &[exports.into(), name.into()]) {
+    Some(handler) => Ok(
+      v8::Local::<v8::Function>::try_from(handler)
+        .ok()
+        .map(|handler| v8::Global::new(tc, handler)),
+    ),
+    None => Err(exception_error(tc)),
+  }
+}
 
-        if has_reader {
-          let body_obj = body_val.to_object(scope).unwrap();
-          let get_reader_fn: v8::Local<v8::Function> = body_obj
-            .get(scope, get_reader_key.into())
-            .unwrap()
-            .try_into()
-            .unwrap();
-          let reader_val = get_reader_fn.call(scope, body_obj.into(), &[]).unwrap();
-          let reader = reader_val.to_object(scope).unwrap();
-          let reader_global = v8::Global::new(scope, reader);
-          BodyState::Stream(reader_global)
-        } else {
-          BodyState::Error(anyhow::anyhow!(
-            "Response body is not String, Uint8Array, or ReadableStream"
-          ))
-        }
-      }
+/// Looks up the handler for `event`, logging lookups that throw.
+fn lookup_handler(
+  runtime: &mut JsRuntime,
+  exports: &v8::Global<v8::Object>,
+  event: &str,
+  addr: &str,
+  name: &str,
+) -> Option<v8::Global<v8::Function>> {
+  find_handler(runtime, exports, event).unwrap_or_else(|e| {
+    log_error(addr, name, &e);
+    None
+  })
+}
+
+/// Calls `handler(arg, env, ctx)` with a fresh `ExecutionContext`, or
+/// `handler(env, ctx)` for events without an argument, and waits for
+/// whatever it returns. `waitUntil` tasks keep running afterwards.
+async fn run_handler(
+  runtime: &mut JsRuntime,
+  handler: &v8::Global<v8::Function>,
+  env_global: &v8::Global<v8::Value>,
+  arg: Option<v8::Global<v8::Value>>,
+) -> Result<v8::Global<v8::Value>, WorkerError> {
+  let result = {
+    let scope = &mut runtime.handle_scope();
+    let recv = v8::undefined(scope).into();
+    let ctx = host_hook(scope, "createContext")?
+      .call(scope, recv, &[])
+      .ok_or_else(|| anyhow::anyhow!("could not create ExecutionContext"))?;
+    let env = v8::Local::new(scope, env_global.clone());
+    let args = match arg {
+      Some(arg) => vec![v8::Local::new(scope, arg), env, ctx],
+      None => vec![env, ctx],
     };
 
-    match body_state {
-      BodyState::Done(bytes) => (
-        builder.body(axum::body::Body::from(bytes)).unwrap(),
-        None,
-      ),
-      BodyState::Error(e) => return Err(e),
-      BodyState::Stream(reader_global) => {
-        let (mut tx, rx) = futures::channel::mpsc::channel::<Result<axum::body::Bytes, anyhow::Error>>(10);
-        let body = axum::body::Body::from_stream(rx);
-
-        let pumper: std::pin::Pin<Box<dyn std::future::Future<Output = ()> + 'a>> = Box::pin(async move {
-            let read_key_str = "read";
-
-            loop {
-              let promise_global = {
-                let scope = &mut runtime.handle_scope();
-                let read_key = v8::String::new(scope, read_key_str).unwrap();
-                let reader = v8::Local::new(scope, reader_global.clone());
-                let read_fn: v8::Local<v8::Function> = reader
-                  .get(scope, read_key.into())
-                  .unwrap()
-                  .try_into()
-                  .unwrap();
-                let promise_val = read_fn.call(scope, reader.into(), &[]).unwrap();
-                let promise: v8::Local<v8::Promise> = promise_val.try_into().unwrap();
-                let promise_value: v8::Local<v8::Value> = promise.into();
-                v8::Global::new(scope, promise_value)
-              };
-
-              #[allow(deprecated)]
-              let result_global_res = runtime.resolve_value(promise_global).await;
-              
-              let result_global = match result_global_res {
-                  Ok(r) => r,
-                  Err(e) => {
-                      let _ = tx.send(Err(e.into())).await;
-                      break;
-                  }
-              };
+    let func = v8::Local::new(scope, handler.clone());
+    let tc = &mut v8::TryCatch::new(scope);
+    match func.call(tc, recv, &args) {
+      Some(res) => v8::Global::new(tc, res),
+      None => return Err(exception_error(tc)),
+    }
+  };
+  await_value(runtime, result).await
+}
 
-              let scope = &mut runtime.handle_scope();
-              let result: v8::Local<v8::Value> = v8::Local::new(scope, result_global);
-              let result_obj = result.to_object(scope).unwrap();
+/// Runs the `scheduled()` handler for a cron trigger.
+async fn handle_scheduled(
+  runtime: &mut JsRuntime,
+  scheduled_global: Option<&v8::Global<v8::Function>>,
+  env_global: &v8::Global<v8::Value>,
+  cron: &str,
+  scheduled_time: SystemTime,
+) -> Result<(), WorkerError> {
+  let Some(scheduled_global) = scheduled_global else {
+    return Err(WorkerError::NoHandler("scheduled"));
+  };
 
-              let done_key = v8::String::new(scope, "done").unwrap();
-              let done = result_obj.get(scope, done_key.into()).unwrap();
-              if done.is_true() {
-                break;
-              }
+  let controller = {
+    let scope = &mut runtime.handle_scope();
+    let cron = v8::String::new(scope, cron).unwrap();
+    let time = v8::Number::new(scope, millis(scheduled_time));
+    let hook = host_hook(scope, "createScheduledController")?;
+    let recv = v8::undefined(scope).into();
+
+    let tc = &mut v8::TryCatch::new(scope);
+    match hook.call(tc, recv, &[cron.into(), time.into()]) {
+      Some(controller) => v8::Global::new(tc, controller),
+      None => return Err(exception_error(tc)),
+    }
+  };
 
-              let value_key = v8::String::new(scope, "value").unwrap();
-              let value = result_obj.get(scope, value_key.into()).unwrap();
-              let uint8: v8::Local<v8::Uint8Array> = value.try_into().unwrap();
-              let len = uint8.byte_length();
-              let mut buf = vec![0u8; len];
-              uint8.copy_contents(&mut buf);
-              
-              if tx.send(Ok(axum::body::Bytes::from(buf))).await.is_err() {
-                  break;
-              }
-            }
-        });
-        
-        (builder.body(body).unwrap(), Some(pumper))
-      }
+  run_handler(runtime, scheduled_global, env_global, Some(controller)).await?;
+  Ok(())
+}
+
+/// A queue message as `createMessageBatch` takes it.
+#[derive(Serialize)]
+struct JsQueueMessage {
+  id: String,
+  timestamp: f64,
+  body: ToJsBuffer,
+  attempts: u32,
+}
+
+/// Runs the `queue()` handler on a batch of messages. What becomes of each
+/// message is decided even when the handler throws, so messages it
+/// acknowledged before throwing are not retried.
+async fn handle_queue(
+  runtime: &mut JsRuntime,
+  queue_global: Option<&v8::Global<v8::Function>>,
+  env_global: &v8::Global<v8::Value>,
+  queue: &str,
+  messages: Vec<QueueMessage>,
+) -> Result<(Vec<QueueOutcome>, Option<WorkerError>), WorkerError> {
+  let Some(queue_global) = queue_global else {
+    return Err(WorkerError::NoHandler("queue"));
+  };
+
+  let batch = {
+    let scope = &mut runtime.handle_scope();
+    let messages: Vec<_> = messages
+      .into_iter()
+      .map(|message| JsQueueMessage {
+        id: format!("{:016x}", message.id),
+        timestamp: millis(message.timestamp),
+        body: message.body.into(),
+        attempts: message.attempts,
+      })
+      .collect();
+    let messages = serde_v8::to_v8(scope, messages).map_err(anyhow::Error::from)?;
+    let queue = v8::String::new(scope, queue).unwrap();
+    let hook = host_hook(scope, "createMessageBatch")?;
+    let recv = v8::undefined(scope).into();
+
+    let tc = &mut v8::TryCatch::new(scope);
+    match hook.call(tc, recv, &[queue.into(), messages]) {
+      Some(batch) => v8::Global::new(tc, batch),
+      None => return Err(exception_error(tc)),
     }
   };
 
-  Ok(body_bytes)
+  let failure = run_handler(runtime, queue_global, env_global, Some(batch.clone()))
+    .await
+    .err();
+
+  let scope = &mut runtime.handle_scope();
+  let batch = v8::Local::new(scope, batch);
+  let succeeded = v8::Boolean::new(scope, failure.is_none());
+  let hook = host_hook(scope, "settleMessageBatch")?;
+  let recv = v8::undefined(scope).into();
+  let tc = &mut v8::TryCatch::new(scope);
+  let outcomes = match hook.call(tc, recv, &[batch, succeeded.into()]) {
+    Some(outcomes) => outcomes,
+    None => return Err(exception_error(tc)),
+  };
+  let outcomes = serde_v8::from_v8(tc, outcomes).map_err(anyhow::Error::from)?;
+  Ok((outcomes, failure))
 }
 
-pub fn spawn_worker(
-  addr: String,
-  module_path: impl Into<PathBuf>,
-  name: Option<String>,
-) -> WorkerHandle {
-  let (tx, rx) = channel::<WorkerRequest>();
-  let path = module_path.into();
-  let addr_r = addr.clone();
-  let name_r = name.clone();
-  std::thread::spawn(move || {
-    let rt = tokio::runtime::Builder::new_current_thread()
-      .enable_all()
-      .build()
-      .unwrap();
-    let mut runtime = WorkyRuntime::new(Some(addr_r.clone()), name_r);
-    let module_future = runtime.run_module(Path::new(&path));
+fn millis(time: SystemTime) -> f64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_millis() as f64
+}
 
-    let fetch_global = {
-      let module_exports = rt.block_on(module_future);
-      if let Ok(module_exports) = module_exports {
-        let scope = &mut runtime.js_runtime.handle_scope();
-        let ns_local = module_exports.open(scope);
+/// Runs the `tail()` handler on events of the workers this one tails.
+async fn handle_tail(
+  runtime: &mut JsRuntime,
+  tail_global: Option<&v8::Global<v8::Function>>,
+  env_global: &v8::Global<v8::Value>,
+  events: Vec<TailEvent>,
+) -> Result<(), WorkerError> {
+  let Some(tail_global) = tail_global else {
+    return Err(WorkerError::NoHandler("tail"));
+  };
 
-        let default_key = v8::String::new(scope, "default").unwrap();
-        let default_val = ns_local.get(scope, default_key.into()).unwrap();
+  let events = {
+    let scope = &mut runtime.handle_scope();
+    let events = serde_v8::to_v8(scope, events).map_err(anyhow::Error::from)?;
+    v8::Global::new(scope, events)
+  };
+  run_handler(runtime, tail_global, env_global, Some(events)).await?;
+  Ok(())
+}
 
-        let default_obj =
-          v8::Local::<v8::Object>::try_from(default_val).expect("default export must be an object");
+/// A connection of a `tcp` worker, from when the worker adopted it until its
+/// `connect()` handler is done with it.
+struct OpenConnection {
+  rid: ResourceId,
+  remote_address: SocketAddr,
+  local_address: SocketAddr,
+  sent: BytesSent,
+  start: Instant,
+  entry: AccessEntry,
+  trace: Option<Trace>,
+}
 
-        let fetch_key = v8::String::new(scope, "fetch").unwrap();
-        let fetch_val = default_obj.get(scope, fetch_key.into()).unwrap();
+impl OpenConnection {
+  fn event(&self) -> TailEventInfo {
+    TailEventInfo::Connect {
+      remote_address: self.remote_address.to_string(),
+      local_address: self.local_address.to_string(),
+    }
+  }
+}
 
-        if !fetch_val.is_function() {
-          None
-        } else {
-          let func = v8::Local::<v8::Function>::try_from(fetch_val).unwrap();
-          Some(v8::Global::new(scope, func))
-        }
-      } else {
-        eprintln!("Error: {}", module_exports.err().unwrap());
-        None
-      }
+/// Makes a connection accepted by a `tcp` worker's listener a socket of the
+/// worker's isolate.
+fn accept_connection(
+  runtime: &mut JsRuntime,
+  connection: ConnectRequest,
+) -> Result<OpenConnection, WorkerError> {
+  let stream = tokio::net::TcpStream::from_std(connection.stream).map_err(anyhow::Error::from)?;
+  let remote_address = stream.peer_addr().map_err(anyhow::Error::from)?;
+  let local_address = stream.local_addr().map_err(anyhow::Error::from)?;
+  let sent = BytesSent::default();
+  let rid = worky_ops::ext::sockets::accept_socket(
+    &mut runtime.op_state().borrow_mut(),
+    stream,
+    connection.permit,
+    sent.clone(),
+  );
+  Ok(OpenConnection {
+    rid,
+    remote_address,
+    local_address,
+    sent,
+    start: Instant::now(),
+    entry: access::connection_entry(remote_address, local_address),
+    trace: None,
+  })
+}
+
+/// Hands a connection to the worker's `connect(socket, env, ctx)` handler.
+/// The handler keeps running on the event loop, so the worker goes on to its
+/// next message right away; a [`WorkerMessage::ConnectionEnd`] tells it when
+/// the handler is done.
+fn handle_connect(
+  runtime: &mut JsRuntime,
+  connect_global: Option<&v8::Global<v8::Function>>,
+  env_global: &v8::Global<v8::Value>,
+  connection: &OpenConnection,
+) -> Result<(), WorkerError> {
+  let Some(connect_global) = connect_global else {
+    return Err(WorkerError::NoHandler("connect"));
+  };
+
+  let scope = &mut runtime.handle_scope();
+  let recv = v8::undefined(scope).into();
+  let ctx = host_hook(scope, "createContext")?
+    .call(scope, recv, &[])
+    .ok_or_else(|| anyhow::anyhow!("could not create ExecutionContext"))?;
+  let handler = v8::Local::new(scope, connect_global.clone());
+  let rid = v8::Integer::new_from_unsigned(scope, connection.rid).into();
+  let remote_address = v8::String::new(scope, &connection.remote_address.to_string()).unwrap();
+  let local_address = v8::String::new(scope, &connection.local_address.to_string()).unwrap();
+  let env = v8::Local::new(scope, env_global.clone());
+  let hook = host_hook(scope, "acceptConnection")?;
+
+  let tc = &mut v8::TryCatch::new(scope);
+  let args = [
+    handler.into(),
+    rid,
+    remote_address.into(),
+    local_address.into(),
+    env,
+    ctx,
+  ];
+  match hook.call(tc, recv, &args) {
+    Some(_) => Ok(()),
+    None => Err(exception_error(tc)),
+  }
+}
+
+/// Records a connection the worker is done with in its access log and sends
+/// its trace. `error` is what the `connect()` handler failed with, if it did.
+fn end_connection(
+  runtime: &mut JsRuntime,
+  connection: OpenConnection,
+  error: Option<WorkerError>,
+  access_log: Option<&AccessLog>,
+  addr: &str,
+  name: &str,
+  consumers: &[TailConsumer],
+) {
+  if let Some(e) = &error {
+    log_error(addr, name, e);
+  }
+  tail::finish(connection.trace, runtime, name, consumers, error.as_ref());
+  if let Some(log) = access_log {
+    let mut entry = connection.entry;
+    entry.status = error.as_ref().map_or(200, |e| e.status().as_u16());
+    entry.bytes = connection.sent.get();
+    entry.duration = connection.start.elapsed();
+    let line = log.record(&entry);
+    push_log(addr, name, &line, LogType::Access);
+  }
+}
+
+/// Hands a message posted on a `BroadcastChannel` in another isolate to this
+/// isolate's channels of the same name.
+fn handle_broadcast(runtime: &mut JsRuntime, message: BroadcastMessage) -> Result<(), WorkerError> {
+  let scope = &mut runtime.handle_scope();
+  let channel = v8::String::new(scope, &message.channel).unwrap();
+  let data = uint8_array(scope, message.data);
+  let hook = host_hook(scope, "deliverBroadcast")?;
+  let recv = v8::undefined(scope).into();
+
+  let tc = &mut v8::TryCatch::new(scope);
+  match hook.call(tc, recv, &[channel.into(), data.into()]) {
+    Some(_) => Ok(()),
+    None => Err(exception_error(tc)),
+  }
+}
+
+/// Runs an RPC pipeline against one of the module's `WorkerEntrypoint`
+/// classes. Errors thrown by the method are part of the encoded outcome; only
+/// failures to run it at all come back as `Err`.
+async fn handle_rpc(
+  runtime: &mut JsRuntime,
+  exports: &v8::Global<v8::Object>,
+  env_global: &v8::Global<v8::Value>,
+  entrypoint: Option<&str>,
+  payload: Vec<u8>,
+) -> Result<Vec<u8>, WorkerError> {
+  let promise = {
+    let scope = &mut runtime.handle_scope();
+    let exports = v8::Local::new(scope, exports.clone());
+    let entrypoint: v8::Local<v8::Value> = match entrypoint {
+      Some(name) => v8::String::new(scope, name).unwrap().into(),
+      None => v8::undefined(scope).into(),
     };
+    let payload = uint8_array(scope, payload);
+    let env = v8::Local::new(scope, env_global.clone());
+    let hook = host_hook(scope, "handleRpc")?;
+    let recv = v8::undefined(scope).into();
+
+    let tc = &mut v8::TryCatch::new(scope);
+    match hook.call(tc, recv, &[exports.into(), entrypoint, payload.into(), env]) {
+      Some(promise) => v8::Global::new(tc, promise),
+      None => return Err(exception_error(tc)),
+    }
+  };
 
-    for req in rx {
-      let fut = async {
-        let res_global_result = {
-          let scope = &mut runtime.js_runtime.handle_scope();
-          println!("Global: {fetch_global:?}");
-          if let Some(fetch_global) = &fetch_global {
-            let js_result = {
-              let js_request_obj = {
-                let req_data = req.request_data.unwrap();
-
-                let req_body = req_data.body();
-                let mut body_bytes = req_body.to_vec();
-                let body_len = body_bytes.len();
-
-                let slice: &mut [u8] = &mut body_bytes[..];
-
-                let uint8 = {
-                  let ab = v8::ArrayBuffer::new(scope, body_len);
-                  let uint8 = v8::Uint8Array::new(scope, ab, 0, body_len).unwrap();
-
-                  uint8.copy_contents(slice);
-                  uint8
-                };
-
-                let global = scope.get_current_context().global(scope);
-
-                let req_key = v8::String::new(scope, "Request").unwrap();
-                let request_ctor: v8::Local<v8::Function> = global
-                  .get(scope, req_key.into())
-                  .unwrap()
-                  .try_into()
-                  .unwrap();
-
-                let url = v8::String::new(
-                  scope,
-                  &format!("http://{}{}", addr_r, req_data.uri().to_string()),
-                )
-                .unwrap();
-                let init_obj = v8::Object::new(scope);
+  let result = await_value(runtime, promise).await?;
+  read_bytes(runtime, result, "handleRpc")
+}
 
-                let meth = req_data.method();
+/// Copies out the `Uint8Array` a hook resolved to.
+fn read_bytes(
+  runtime: &mut JsRuntime,
+  value: v8::Global<v8::Value>,
+  hook: &str,
+) -> Result<Vec<u8>, WorkerError> {
+  let scope = &mut runtime.handle_scope();
+  let value = v8::Local::new(scope, value);
+  let bytes = v8::Local::<v8::Uint8Array>::try_from(value)
+    .map_err(|_| anyhow::anyhow!("{hook} did not return bytes"))?;
+  let mut buf = vec![0u8; bytes.byte_length()];
+  bytes.copy_contents(&mut buf);
+  Ok(buf)
+}
 
-                let method = v8::String::new(scope, meth.as_str()).unwrap();
-                let met_key = v8::String::new(scope, "method").unwrap();
-                init_obj.set(scope, met_key.into(), method.into());
+/// The arguments that pick out a durable object for the `durableObject*`
+/// hooks: its class, id and the name the id came from.
+fn target_args<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  target: &DurableObjectTarget,
+) -> [v8::Local<'s, v8::Value>; 3] {
+  let class = v8::String::new(scope, &target.class).unwrap().into();
+  let id = v8::String::new(scope, &target.id).unwrap().into();
+  let name = match &target.name {
+    Some(name) => v8::String::new(scope, name).unwrap().into(),
+    None => v8::undefined(scope).into(),
+  };
+  [class, id, name]
+}
 
-                let headers_key = v8::String::new(scope, "Headers").unwrap();
-                let headers_ctor: v8::Local<v8::Function> = global
-                  .get(scope, headers_key.into())
-                  .unwrap()
-                  .try_into()
-                  .unwrap();
+/// Runs a request from another worker through one of this worker's durable
+/// objects, creating it if it is not live yet.
+async fn handle_object_fetch<'a>(
+  runtime: &'a mut JsRuntime,
+  target: &DurableObjectTarget,
+  req_data: Request<hyper::body::Bytes>,
+) -> Result<(hyper::Response<axum::body::Body>, Option<Pumper<'a>>), WorkerError> {
+  let (parts, body) = req_data.into_parts();
+  let promise = {
+    let scope = &mut runtime.handle_scope();
+    let request = build_js_request(scope, &parts, &body)?;
+    let [class, id, name] = target_args(scope, target);
+    let hook = host_hook(scope, "durableObjectFetch")?;
+    let recv = v8::undefined(scope).into();
+
+    let tc = &mut v8::TryCatch::new(scope);
+    match hook.call(tc, recv, &[class, id, name, request.into()]) {
+      Some(promise) => v8::Global::new(tc, promise),
+      None => return Err(exception_error(tc)),
+    }
+  };
 
-                let js_headers = headers_ctor.new_instance(scope, &[]).unwrap();
+  let response = await_value(runtime, promise).await?;
+  parse_js_response(runtime, response).await
+}
 
-                let append_key = v8::String::new(scope, "append").unwrap();
-                let append_fn: v8::Local<v8::Function> = js_headers
-                  .get(scope, append_key.into())
-                  .unwrap()
-                  .try_into()
-                  .unwrap();
+/// Runs an RPC pipeline from another worker against one of this worker's
+/// durable objects, encoded as for [`handle_rpc`].
+async fn handle_object_rpc(
+  runtime: &mut JsRuntime,
+  target: &DurableObjectTarget,
+  payload: Vec<u8>,
+) -> Result<Vec<u8>, WorkerError> {
+  let promise = {
+    let scope = &mut runtime.handle_scope();
+    let [class, id, name] = target_args(scope, target);
+    let payload = uint8_array(scope, payload);
+    let hook = host_hook(scope, "durableObjectRpc")?;
+    let recv = v8::undefined(scope).into();
+
+    let tc = &mut v8::TryCatch::new(scope);
+    match hook.call(tc, recv, &[class, id, name, payload.into()]) {
+      Some(promise) => v8::Global::new(tc, promise),
+      None => return Err(exception_error(tc)),
+    }
+  };
 
-                for (key, value) in req_data.headers().iter() {
-                  let k = v8::String::new(scope, key.as_str()).unwrap();
-                  let v = v8::String::new(scope, value.to_str().unwrap()).unwrap();
+  let result = await_value(runtime, promise).await?;
+  read_bytes(runtime, result, "durableObjectRpc")
+}
 
-                  append_fn.call(scope, js_headers.into(), &[k.into(), v.into()]);
-                }
+/// Runs the `alarm()` method of one of this worker's durable objects.
+async fn handle_object_alarm(
+  runtime: &mut JsRuntime,
+  target: &DurableObjectTarget,
+  retry_count: u32,
+) -> Result<(), WorkerError> {
+  let promise = {
+    let scope = &mut runtime.handle_scope();
+    let [class, id, name] = target_args(scope, target);
+    let retry_count = v8::Integer::new_from_unsigned(scope, retry_count).into();
+    let hook = host_hook(scope, "durableObjectAlarm")?;
+    let recv = v8::undefined(scope).into();
+
+    let tc = &mut v8::TryCatch::new(scope);
+    match hook.call(tc, recv, &[class, id, name, retry_count]) {
+      Some(promise) => v8::Global::new(tc, promise),
+      None => return Err(exception_error(tc)),
+    }
+  };
 
-                let headers_init_key = v8::String::new(scope, "headers").unwrap();
-                init_obj.set(scope, headers_init_key.into(), js_headers.into());
+  await_value(runtime, promise).await?;
+  Ok(())
+}
 
-                if meth != "GET" && meth != "HEAD" {
-                  let bod_key = v8::String::new(scope, "body").unwrap();
-                  init_obj.set(scope, bod_key.into(), uint8.into());
-                }
+/// A socket event as `durableObjectWebSocket` takes it.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum JsSocketEvent {
+  Message {
+    data: JsSocketData,
+  },
+  Close {
+    code: u16,
+    reason: String,
+    #[serde(rename = "wasClean")]
+    was_clean: bool,
+  },
+  Error {
+    message: String,
+  },
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum JsSocketData {
+  Text(String),
+  Binary(ToJsBuffer),
+}
+
+/// Runs the handler of one of this worker's durable objects for an event on
+/// a socket it accepted.
+async fn handle_object_socket(
+  runtime: &mut JsRuntime,
+  target: &DurableObjectTarget,
+  socket: u32,
+  event: SocketEvent,
+) -> Result<(), WorkerError> {
+  let event = match event {
+    SocketEvent::Message(SocketMessage::Text(text)) => JsSocketEvent::Message {
+      data: JsSocketData::Text(text),
+    },
+    SocketEvent::Message(SocketMessage::Binary(data)) => JsSocketEvent::Message {
+      data: JsSocketData::Binary(data.into()),
+    },
+    SocketEvent::Close {
+      code,
+      reason,
+      was_clean,
+    } => JsSocketEvent::Close {
+      code,
+      reason,
+      was_clean,
+    },
+    SocketEvent::Error(message) => JsSocketEvent::Error { message },
+  };
+
+  let promise = {
+    let scope = &mut runtime.handle_scope();
+    let [class, id, name] = target_args(scope, target);
+    let socket = v8::Integer::new_from_unsigned(scope, socket).into();
+    let event = serde_v8::to_v8(scope, event).map_err(anyhow::Error::from)?;
+    let hook = host_hook(scope, "durableObjectWebSocket")?;
+    let recv = v8::undefined(scope).into();
+
+    let tc = &mut v8::TryCatch::new(scope);
+    match hook.call(tc, recv, &[class, id, name, socket, event]) {
+      Some(promise) => v8::Global::new(tc, promise),
+      None => return Err(exception_error(tc)),
+    }
+  };
+
+  await_value(runtime, promise).await?;
+  Ok(())
+}
+
+async fn handle_fetch<'a>(
+  runtime: &'a mut JsRuntime,
+  fetch_global: Option<&v8::Global<v8::Function>>,
+  env_global: &v8::Global<v8::Value>,
+  config: &WorkerConfig,
+  req_data: Request<hyper::body::Bytes>,
+) -> Result<(hyper::Response<axum::body::Body>, Option<Pumper<'a>>), WorkerError> {
+  let Some(fetch_global) = fetch_global else {
+    return Err(WorkerError::NoHandler("fetch"));
+  };
+  let (parts, body) = req_data.into_parts();
 
-                request_ctor
-                  .new_instance(scope, &[url.into(), init_obj.into()])
-                  .unwrap()
-              };
+  let (call_result, ctx_global) = {
+    let scope = &mut runtime.handle_scope();
+    let js_request_obj = build_js_request(scope, &parts, &body)?;
+
+    let recv = v8::undefined(scope).into();
+    let ctx = host_hook(scope, "createContext")?
+      .call(scope, recv, &[])
+      .ok_or_else(|| anyhow::anyhow!("could not create ExecutionContext"))?;
+    let env = v8::Local::new(scope, env_global.clone());
+
+    let func = v8::Local::new(scope, fetch_global.clone());
+    let tc = &mut v8::TryCatch::new(scope);
+    let call_result = match func.call(tc, recv, &[js_request_obj.into(), env, ctx]) {
+      Some(res) => Ok(v8::Global::new(tc, res)),
+      None => Err(exception_error(tc)),
+    };
 
-              let func = fetch_global.open(scope);
+    (call_result, v8::Global::new(tc, ctx))
+  };
 
-              let recv = v8::undefined(scope).into();
-              let call_result = func.call(scope, recv, &[js_request_obj.into()]);
+  let result = match call_result {
+    Ok(res) => await_value(runtime, res).await,
+    Err(e) => Err(e),
+  };
 
-              match call_result {
-                Some(res) => Ok(res),
-                None => Err(anyhow::anyhow!("fetch() threw an exception")),
-              }
-            };
+  match result {
+    Ok(res_global) => parse_js_response(runtime, res_global).await,
+    Err(e) => match &config.origin {
+      Some(origin) if should_pass_through(runtime, &ctx_global) => {
+        Ok((pass_through(origin, &parts, body).await?, None))
+      }
+      _ => Err(e),
+    },
+  }
+}
+
+/// Waits for every outstanding `ctx.waitUntil()` task, giving up after `grace`.
+async fn settle_wait_until(runtime: &mut JsRuntime, grace: Duration) {
+  let promise = {
+    let scope = &mut runtime.handle_scope();
+    let Ok(hook) = host_hook(scope, "settleWaitUntil") else {
+      return;
+    };
+    let recv = v8::undefined(scope).into();
+    match hook.call(scope, recv, &[]) {
+      Some(promise) => v8::Global::new(scope, promise),
+      None => return,
+    }
+  };
+
+  if tokio::time::timeout(grace, runtime.resolve(promise))
+    .await
+    .is_err()
+  {
+    eprintln!("waitUntil tasks did not finish within {grace:?}");
+  }
+}
+
+/// Runs the `init(env, ctx)` handler, if the worker exports one, before it takes
+/// any event. It fails the load when it throws or outlasts `timeout`.
+async fn handle_init(
+  runtime: &mut JsRuntime,
+  init_global: Option<&v8::Global<v8::Function>>,
+  env_global: &v8::Global<v8::Value>,
+  timeout: Duration,
+) -> Result<(), WorkerError> {
+  let Some(init_global) = init_global else {
+    return Ok(());
+  };
+  let init = run_handler(runtime, init_global, env_global, None);
+  match tokio::time::timeout(timeout, init).await {
+    Ok(result) => result.map(|_| ()),
+    Err(_) => Err(anyhow::anyhow!("init() did not finish within {timeout:?}").into()),
+  }
+}
+
+/// Runs the `shutdown(reason)` handler, if the worker exports one, giving up
+/// after `grace`.
+async fn handle_shutdown(
+  runtime: &mut JsRuntime,
+  shutdown_global: Option<&v8::Global<v8::Function>>,
+  env_global: &v8::Global<v8::Value>,
+  reason: &str,
+  grace: Duration,
+) -> Result<(), WorkerError> {
+  let Some(shutdown_global) = shutdown_global else {
+    return Ok(());
+  };
+  let reason = {
+    let scope = &mut runtime.handle_scope();
+    let reason: v8::Local<v8::Value> = v8::String::new(scope, reason).unwrap().into();
+    v8::Global::new(scope, reason)
+  };
+  let shutdown = run_handler(runtime, shutdown_global, env_global, Some(reason));
+  match tokio::time::timeout(grace, shutdown).await {
+    Ok(result) => result.map(|_| ()),
+    Err(_) => Err(anyhow::anyhow!("shutdown() did not finish within {grace:?}").into()),
+  }
+}
+
+/// Records the workers waiting on the message about to be handled, which
+/// calls made while handling it carry on.
+fn set_callers(runtime: &mut JsRuntime, callers: Vec<String>) {
+  runtime.op_state().borrow_mut().put(CallChain(callers));
+}
+
+/// Drops the sockets the worker took on while handling a message, except the
+/// one its response handed to the client, see [`HeldSockets`].
+fn drop_held_sockets(runtime: &mut JsRuntime, kept: Option<u32>) {
+  let state = runtime.op_state();
+  let held = std::mem::take(&mut state.borrow_mut().borrow_mut::<HeldSockets>().0);
+  for socket in held {
+    if Some(socket) != kept {
+      worky_common::websocket::remove(socket);
+    }
+  }
+}
 
-            match js_result {
-              Ok(r) => Ok(v8::Global::new(scope, r)),
-              Err(e) => Err(e),
+/// Starts the worker on a thread of its own. Resolves once the module is
+/// loaded and its `init()` handler finished, or with the error loading the
+/// module or `init()` failed with, in which case the worker is already gone.
+pub async fn spawn_worker(
+  addr: String,
+  module_path: impl Into<PathBuf>,
+  name: Option<String>,
+  config: WorkerConfig,
+) -> Result<WorkerHandle, WorkerError> {
+  let (tx, mut rx) = unbounded_channel::<WorkerMessage>();
+  let (ready_tx, ready_rx) = oneshot::channel();
+  // Weak, so the worker still stops once every handle is gone.
+  let broadcast = BroadcastHost::new(tx.downgrade());
+  let connection_host = tx.downgrade();
+  let access_log = config.access_log.as_ref().map(AccessLog::new).map(Arc::new);
+  let access_log_r = access_log.clone();
+  let path = module_path.into();
+  let addr_r = addr.clone();
+  let name = name.unwrap_or_default();
+  let name_r = name.clone();
+  let config_r = config.clone();
+  std::thread::spawn(move || {
+    let config = config_r;
+    let access_log = access_log_r;
+    let rt = tokio::runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()
+      .unwrap();
+    let mut runtime =
+      WorkyRuntime::with_config(Some(addr_r.clone()), Some(name_r.clone()), config.clone());
+    runtime.js_runtime.op_state().borrow_mut().put(broadcast);
+    runtime
+      .js_runtime
+      .op_state()
+      .borrow_mut()
+      .put(ConnectionHost(connection_host));
+
+    rt.block_on(async move {
+      // A module that fails to load fails the load, like a failing `init()`.
+      let exports = match runtime.run_module(Path::new(&path)).await {
+        Ok(exports) => exports,
+        Err(e) => {
+          let e = module_error(e);
+          log_error(&addr_r, &name_r, &e);
+          let _ = ready_tx.send(Err(e));
+          return;
+        }
+      };
+      let mut handler =
+        |event| lookup_handler(&mut runtime.js_runtime, &exports, event, &addr_r, &name_r);
+      let fetch_global = handler("fetch");
+      let scheduled_global = handler("scheduled");
+      let queue_global = handler("queue");
+      let tail_global = handler("tail");
+      let connect_global = handler("connect");
+      let init_global = handler("init");
+      let shutdown_global = handler("shutdown");
+      let tail_consumers = &config.tail_consumers;
+
+      let env_global = match create_env(&mut runtime.js_runtime) {
+        Ok(env) => env,
+        Err(e) => {
+          log_error(&addr_r, &name_r, &e);
+          let scope = &mut runtime.js_runtime.handle_scope();
+          let env: v8::Local<v8::Value> = v8::Object::new(scope).into();
+          v8::Global::new(scope, env)
+        }
+      };
+      if let Err(e) = set_worker_module(&mut runtime.js_runtime, &exports, &env_global) {
+        log_error(&addr_r, &name_r, &e);
+      }
+
+      let startup = Duration::from_millis(config.startup_timeout_ms);
+      let init = handle_init(
+        &mut runtime.js_runtime,
+        init_global.as_ref(),
+        &env_global,
+        startup,
+      )
+      .await;
+      if let Err(e) = init {
+        log_error(&addr_r, &name_r, &e);
+        let _ = ready_tx.send(Err(e));
+        return;
+      }
+      let _ = ready_tx.send(Ok(()));
+
+      // The event loop keeps running between requests so `waitUntil` tasks
+      // make progress after their response has been sent. Whatever `init()`
+      // left running gets to go on right away.
+      let mut idle = init_global.is_none();
+      // The socket the last response handed on.
+      let mut kept = None;
+      // Connections whose `connect()` handler is still at it, by socket.
+      let mut connections = HashMap::new();
+      loop {
+        drop_held_sockets(&mut runtime.js_runtime, kept.take());
+        // Nobody waits on what runs between messages.
+        set_callers(&mut runtime.js_runtime, Vec::new());
+        let message = tokio::select! {
+          message = rx.recv() => match message {
+            Some(message) => message,
+            None => break,
+          },
+          res = runtime.js_runtime.run_event_loop(Default::default()), if !idle => {
+            if let Err(e) = res {
+              log_error(&addr_r, &name_r, &core_error(e));
             }
-          } else {
-            Err(anyhow::anyhow!("fetch() is not defined"))
+            idle = true;
+            continue;
           }
         };
-
-        match res_global_result {
-          Ok(res_global) => {
-            // Check if it is a promise and await it
-            let res_promise_opt = {
-              let scope = &mut runtime.js_runtime.handle_scope();
-              let local = v8::Local::new(scope, res_global.clone());
-              if local.is_promise() {
-                let promise: v8::Local<v8::Promise> = local.try_into().unwrap();
-                let promise_value: v8::Local<v8::Value> = promise.into();
-                Some(v8::Global::new(scope, promise_value))
-              } else {
-                None
+        idle = false;
+
+        let req = match message {
+          WorkerMessage::Fetch(req) => req,
+          WorkerMessage::Rpc(rpc) => {
+            set_callers(&mut runtime.js_runtime, rpc.callers);
+            let result = handle_rpc(
+              &mut runtime.js_runtime,
+              &exports,
+              &env_global,
+              rpc.entrypoint.as_deref(),
+              rpc.payload,
+            )
+            .await;
+            if let Err(e) = &result {
+              log_error(&addr_r, &name_r, e);
+            }
+            let _ = rpc.resp.send(result);
+            continue;
+          }
+          WorkerMessage::Scheduled(event) => {
+            let trace = Trace::begin(&mut runtime.js_runtime, tail_consumers, || {
+              TailEventInfo::Scheduled {
+                cron: event.cron.clone(),
+                scheduled_time: millis(event.scheduled_time),
+              }
+            });
+            let result = handle_scheduled(
+              &mut runtime.js_runtime,
+              scheduled_global.as_ref(),
+              &env_global,
+              &event.cron,
+              event.scheduled_time,
+            )
+            .await;
+            if let Err(e) = &result {
+              log_error(&addr_r, &name_r, e);
+            }
+            let error = result.as_ref().err();
+            tail::finish(
+              trace,
+              &mut runtime.js_runtime,
+              &name_r,
+              tail_consumers,
+              error,
+            );
+            let _ = event.resp.send(result);
+            continue;
+          }
+          WorkerMessage::Queue(batch) => {
+            let trace = Trace::begin(&mut runtime.js_runtime, tail_consumers, || {
+              TailEventInfo::Queue {
+                queue: batch.queue.clone(),
+                batch_size: batch.messages.len(),
               }
+            });
+            let result = handle_queue(
+              &mut runtime.js_runtime,
+              queue_global.as_ref(),
+              &env_global,
+              &batch.queue,
+              batch.messages,
+            )
+            .await;
+            let (result, failure) = match result {
+              Ok((outcomes, failure)) => (Ok(outcomes), failure),
+              Err(e) => (Err(e), None),
             };
-
-            let final_res = if let Some(p) = res_promise_opt {
-              runtime.js_runtime.resolve(p).await.unwrap()
-            } else {
-              res_global
+            let error = failure.as_ref().or(result.as_ref().err());
+            if let Some(e) = error {
+              log_error(&addr_r, &name_r, e);
+            }
+            tail::finish(
+              trace,
+              &mut runtime.js_runtime,
+              &name_r,
+              tail_consumers,
+              error,
+            );
+            let _ = batch.resp.send(result);
+            continue;
+          }
+          WorkerMessage::Tail(request) => {
+            let result = handle_tail(
+              &mut runtime.js_runtime,
+              tail_global.as_ref(),
+              &env_global,
+              request.events,
+            )
+            .await;
+            if let Err(e) = &result {
+              log_error(&addr_r, &name_r, e);
+            }
+            continue;
+          }
+          WorkerMessage::Connect(connection) => {
+            let mut connection = match accept_connection(&mut runtime.js_runtime, connection) {
+              Ok(connection) => connection,
+              Err(e) => {
+                log_error(&addr_r, &name_r, &e);
+                continue;
+              }
             };
-
-            let (res, pumper) = parse_js_response(&mut runtime.js_runtime, final_res).await?;
-            Ok((res, pumper))
+            connection.trace = Trace::begin(&mut runtime.js_runtime, tail_consumers, || {
+              connection.event()
+            });
+            let result = handle_connect(
+              &mut runtime.js_runtime,
+              connect_global.as_ref(),
+              &env_global,
+              &connection,
+            );
+            match result {
+              Ok(()) => {
+                connections.insert(connection.rid, connection);
+              }
+              Err(e) => {
+                let _ = runtime
+                  .js_runtime
+                  .op_state()
+                  .borrow_mut()
+                  .resource_table
+                  .close(connection.rid);
+                end_connection(
+                  &mut runtime.js_runtime,
+                  connection,
+                  Some(e),
+                  access_log.as_deref(),
+                  &addr_r,
+                  &name_r,
+                  tail_consumers,
+                );
+              }
+            }
+            continue;
           }
-          Err(e) => Err(e),
-        }
-      };
+          WorkerMessage::ConnectionEnd(end) => {
+            if let Some(connection) = connections.remove(&end.socket) {
+              end_connection(
+                &mut runtime.js_runtime,
+                connection,
+                end.error,
+                access_log.as_deref(),
+                &addr_r,
+                &name_r,
+                tail_consumers,
+              );
+            }
+            continue;
+          }
+          WorkerMessage::Broadcast(message) => {
+            if let Err(e) = handle_broadcast(&mut runtime.js_runtime, message) {
+              log_error(&addr_r, &name_r, &e);
+            }
+            continue;
+          }
+          WorkerMessage::DurableObject(event) => {
+            set_callers(&mut runtime.js_runtime, event.callers);
+            match event.call {
+              DurableObjectCall::Fetch { request, resp } => {
+                let result =
+                  handle_object_fetch(&mut runtime.js_runtime, &event.target, request).await;
+                match result {
+                  Ok((res, pumper)) => {
+                    let socket = res.extensions().get::<AcceptedSocket>().map(|s| s.0);
+                    if resp.send(Ok(res)).is_ok() {
+                      kept = socket;
+                    }
+                    if let Some(pumper) = pumper {
+                      pumper.await;
+                    }
+                  }
+                  Err(e) => {
+                    log_error(&addr_r, &name_r, &e);
+                    let _ = resp.send(Err(e));
+                  }
+                }
+              }
+              DurableObjectCall::Rpc { payload, resp } => {
+                let result =
+                  handle_object_rpc(&mut runtime.js_runtime, &event.target, payload).await;
+                if let Err(e) = &result {
+                  log_error(&addr_r, &name_r, e);
+                }
+                let _ = resp.send(result);
+              }
+              DurableObjectCall::WebSocket {
+                socket,
+                event: socket_event,
+                resp,
+              } => {
+                let result = handle_object_socket(
+                  &mut runtime.js_runtime,
+                  &event.target,
+                  socket,
+                  socket_event,
+                )
+                .await;
+                if let Err(e) = &result {
+                  log_error(&addr_r, &name_r, e);
+                }
+                let _ = resp.send(result);
+              }
+              DurableObjectCall::Alarm { retry_count, resp } => {
+                let result =
+                  handle_object_alarm(&mut runtime.js_runtime, &event.target, retry_count).await;
+                if let Err(e) = &result {
+                  log_error(&addr_r, &name_r, e);
+                }
+                let _ = resp.send(result);
+              }
+            }
+            continue;
+          }
+        };
 
-      let result = rt.block_on(fut);
-      
-      match result {
+        let Some(req_data) = req.request_data else {
+          let _ = req
+            .resp
+            .send(Err(WorkerError::BadRequest("request has no data".into())));
+          continue;
+        };
+        set_callers(&mut runtime.js_runtime, req.callers);
+
+        let mut trace = Trace::begin(&mut runtime.js_runtime, tail_consumers, || {
+          tail::fetch_event(&req_data)
+        });
+        let result = handle_fetch(
+          &mut runtime.js_runtime,
+          fetch_global.as_ref(),
+          &env_global,
+          &config,
+          req_data,
+        )
+        .await;
+
+        match result {
           Ok((res, pumper)) => {
-              let _ = req.resp.send(Ok(res));
-              if let Some(pumper) = pumper {
-                  rt.block_on(pumper);
-              }
+            if let Some(trace) = &mut trace {
+              trace.set_response(res.status());
+            }
+            let socket = res.extensions().get::<AcceptedSocket>().map(|s| s.0);
+            if req.resp.send(Ok(res)).is_ok() {
+              kept = socket;
+            }
+            if let Some(pumper) = pumper {
+              pumper.await;
+            }
+            tail::finish(
+              trace,
+              &mut runtime.js_runtime,
+              &name_r,
+              tail_consumers,
+              None,
+            );
           }
           Err(e) => {
-              let _ = req.resp.send(Err(e));
+            log_error(&addr_r, &name_r, &e);
+            tail::finish(
+              trace,
+              &mut runtime.js_runtime,
+              &name_r,
+              tail_consumers,
+              Some(&e),
+            );
+            let _ = req.resp.send(Err(e));
           }
+        }
       }
-    }
+
+      // Every sender is gone, so the worker is being unloaded.
+      let grace = Duration::from_millis(config.shutdown_timeout_ms);
+      let shutdown = handle_shutdown(
+        &mut runtime.js_runtime,
+        shutdown_global.as_ref(),
+        &env_global,
+        "unload",
+        grace,
+      )
+      .await;
+      if let Err(e) = shutdown {
+        log_error(&addr_r, &name_r, &e);
+      }
+      settle_wait_until(
+        &mut runtime.js_runtime,
+        Duration::from_millis(config.wait_until_timeout_ms),
+      )
+      .await;
+
+      // Sockets of the worker's objects go with it, unless a worker loaded
+      // under the same name took them over.
+      let key = if name_r.is_empty() { &addr_r } else { &name_r };
+      if worky_common::workers::service(key).is_none() {
+        worky_common::websocket::close_all(key, 1001, "the worker was unloaded");
+      }
+    });
   });
 
-  WorkerHandle {
+  let rate_limiter = config.rate_limit.as_ref().map(|limit| {
+    let period = Duration::from_secs(limit.period);
+    RateLimiter::new(limit.limit, period, limit.burst.unwrap_or(limit.limit))
+  });
+
+  let connections = Arc::new(Semaphore::new(config.limits.max_connections));
+
+  let handle = WorkerHandle {
     sender: tx,
-    name: name.unwrap_or("".to_string()),
+    name,
+    config,
     addr,
+    rate_limiter,
+    access_log,
+    connections,
+  };
+  ready_rx.await.unwrap_or(Err(WorkerError::Unavailable))?;
+  Ok(handle)
+}
+
+/// The address of the peer that sent `req`, when it came in over TCP.
+fn client_ip<B>(req: &Request<B>) -> Option<IpAddr> {
+  req
+    .extensions()
+    .get::<ConnectInfo<SocketAddr>>()
+    .map(|ConnectInfo(addr)| addr.ip())
+}
+
+/// Picks what an inbound request is rate limited by. Requests without the
+/// configured header are limited by their IP instead.
+fn rate_limit_key<B>(key: &RateLimitKey, req: &Request<B>) -> String {
+  if let RateLimitKey::Route = key {
+    return format!("route:{}", req.uri().path());
+  }
+  let header = match key {
+    RateLimitKey::Header(name) => req.headers().get(name).and_then(|v| v.to_str().ok()),
+    _ => None,
+  };
+  match (header, client_ip(req)) {
+    (Some(value), _) => format!("header:{value}"),
+    (None, Some(ip)) => format!("ip:{ip}"),
+    (None, None) => "ip:unknown".to_string(),
   }
 }
 
-pub async fn listen_to_addr(addr: String, handle: std::sync::Arc<WorkerHandle>) {
-  let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-  let handle = std::sync::Arc::new(handle);
+/// Rejects requests whose target or headers are over the worker's limits, or
+/// that announce a body larger than it accepts.
+fn check_limits<B>(limits: &LimitsConfig, req: &Request<B>) -> Result<(), WorkerError> {
+  let target_len = req.uri().path_and_query().map_or(0, |p| p.as_str().len());
+  if target_len > limits.max_url_length {
+    return Err(WorkerError::UriTooLong(limits.max_url_length));
+  }
 
-  let app = Router::new().fallback(move |req: Request<axum::body::Body>| {
-    let handle = handle.clone();
-    async move {
-      let (tx, rx) = tokio::sync::oneshot::channel();
+  let headers = req.headers();
+  let header_size: usize = headers
+    .iter()
+    .map(|(name, value)| name.as_str().len() + value.len())
+    .sum();
+  if headers.len() > limits.max_header_count || header_size > limits.max_header_size {
+    return Err(WorkerError::HeadersTooLarge);
+  }
 
-      let req_bytes = req.map(|body| {
-        use http_body_util::BodyExt;
-        let body_bytes = futures::executor::block_on(body.collect())
-          .unwrap()
-          .to_bytes();
-        body_bytes.to_vec().into()
-      });
+  let content_length = headers
+    .get(hyper::header::CONTENT_LENGTH)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.parse::<u64>().ok());
+  if content_length.is_some_and(|len| len > limits.max_body_size) {
+    return Err(WorkerError::PayloadTooLarge(limits.max_body_size));
+  }
+  Ok(())
+}
 
-      let worker_req = WorkerRequest {
-        resp: tx,
-        request_data: Some(req_bytes),
-      };
+/// Checks the request against the worker's limits, rate limits it, serves
+/// matching static assets, then hands the request to the worker and waits for
+/// its response. Failures the worker reports have already been logged by it;
+/// the ones raised here are logged on the way out.
+async fn dispatch(
+  handle: &WorkerHandle,
+  req: Request<axum::body::Body>,
+) -> hyper::Response<axum::body::Body> {
+  use http_body_util::BodyExt;
+
+  let local_error = |err: WorkerError| {
+    log_error(&handle.addr, &handle.name, &err);
+    err.to_response(handle.config.dev)
+  };
 
-      handle.sender.send(worker_req).unwrap();
+  let limits = &handle.config.limits;
+  if let Err(e) = check_limits(limits, &req) {
+    return e.to_response(handle.config.dev);
+  }
 
-      let resp = rx.await.unwrap().unwrap();
-      resp
+  if let (Some(limiter), Some(limit)) = (&handle.rate_limiter, &handle.config.rate_limit) {
+    if let Err(retry_after) = limiter.check(&rate_limit_key(&limit.key, &req)) {
+      let retry_after = retry_after.as_secs_f64().ceil() as u64;
+      return WorkerError::RateLimited { retry_after }.to_response(handle.config.dev);
     }
-  });
+  }
+
+  if let Some(assets) = handle
+    .config
+    .assets
+    .as_ref()
+    .filter(|a| !a.run_worker_first)
+  {
+    let fallback = assets::is_navigation(req.headers());
+    match assets::serve(
+      assets,
+      req.method(),
+      req.uri().path(),
+      req.headers(),
+      fallback,
+    )
+    .await
+    {
+      Ok(Some(resp)) => return resp.map(axum::body::Body::from),
+      Ok(None) => {}
+      Err(e) => return local_error(WorkerError::Internal(e.into())),
+    }
+  }
 
-  axum::serve(listener, app).await.unwrap();
+  let (mut parts, body) = req.into_parts();
+  // Taken before the request goes to the worker, whose response decides
+  // whether the connection is upgraded.
+  let upgrade = WebSocketUpgrade::from_request_parts(&mut parts, &())
+    .await
+    .ok();
+  let body = Limited::new(body, limits.max_body_size.try_into().unwrap_or(usize::MAX));
+  let body = match body.collect().await {
+    Ok(body) => body.to_bytes(),
+    Err(e) if e.is::<LengthLimitError>() => {
+      return WorkerError::PayloadTooLarge(limits.max_body_size).to_response(handle.config.dev);
+    }
+    Err(e) => return local_error(WorkerError::BadRequest(e.to_string())),
+  };
+
+  let (tx, rx) = tokio::sync::oneshot::channel();
+  let worker_req = WorkerRequest {
+    resp: tx,
+    request_data: Some(Request::from_parts(parts, body)),
+    callers: Vec::new(),
+  };
+
+  if handle
+    .sender
+    .send(WorkerMessage::Fetch(worker_req))
+    .is_err()
+  {
+    return local_error(WorkerError::Unavailable);
+  }
+
+  let resp = match rx.await {
+    Ok(Ok(resp)) => resp,
+    Ok(Err(e)) => return e.to_response(handle.config.dev),
+    Err(_) => return local_error(WorkerError::Unavailable),
+  };
+
+  let Some(&AcceptedSocket(socket)) = resp.extensions().get::<AcceptedSocket>() else {
+    return resp;
+  };
+  let local = websocket::LocalWorker {
+    key: if handle.name.is_empty() {
+      handle.addr.clone()
+    } else {
+      handle.name.clone()
+    },
+    sender: handle.sender.downgrade(),
+  };
+  websocket::upgrade(upgrade, socket, resp, local).unwrap_or_else(local_error)
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
   use deno_core::v8;
+  use worky_common::tail::TailOutcome;
   use worky_runtime::WorkyRuntime;
 
+  /// Runs `code`, which defines a module's `exports`, and returns its `event`
+  /// handler along with an empty `env` to call it with.
+  fn handler(
+    runtime: &mut WorkyRuntime,
+    code: &str,
+    event: &str,
+  ) -> (v8::Global<v8::Function>, v8::Global<v8::Value>) {
+    let code =
+      format!("(() => {{ {code}; return __worky.entrypointHandler(exports, {event:?}); }})()");
+    let handler = runtime.js_runtime.execute_script("<test>", code).unwrap();
+    let scope = &mut runtime.js_runtime.handle_scope();
+    let handler = v8::Local::new(scope, handler);
+    let handler = v8::Local::<v8::Function>::try_from(handler).unwrap();
+    let env: v8::Local<v8::Value> = v8::Object::new(scope).into();
+    (v8::Global::new(scope, handler), v8::Global::new(scope, env))
+  }
+
+  /// Evaluates `expr` and returns it as a string.
+  fn read_global(runtime: &mut WorkyRuntime, expr: &'static str) -> String {
+    let value = runtime.js_runtime.execute_script("<test>", expr).unwrap();
+    let scope = &mut runtime.js_runtime.handle_scope();
+    v8::Local::new(scope, value).to_rust_string_lossy(scope)
+  }
+
   #[tokio::test]
   async fn test_parse_readable_stream() {
     let mut runtime = WorkyRuntime::new(None, None);
@@ -467,20 +1630,667 @@ mod tests {
     let (response, pumper) = parse_js_response(&mut runtime.js_runtime, res_global)
       .await
       .unwrap();
-    
+
     if let Some(pumper) = pumper {
-        // We need to drive the pumper while reading the body
-        let body = response.into_body();
-        use http_body_util::BodyExt;
-        
-        let (body_res, _) = tokio::join!(
-            body.collect(),
-            pumper
-        );
-        let body_bytes = body_res.unwrap().to_bytes();
-        assert_eq!(body_bytes.as_ref(), b"Hello World");
+      // We need to drive the pumper while reading the body
+      let body = response.into_body();
+      use http_body_util::BodyExt;
+
+      let (body_res, _) = tokio::join!(body.collect(), pumper);
+      let body_bytes = body_res.unwrap().to_bytes();
+      assert_eq!(body_bytes.as_ref(), b"Hello World");
     } else {
-        panic!("Expected stream");
+      panic!("Expected stream");
+    }
+  }
+
+  #[tokio::test]
+  async fn test_wait_until_settles() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+      globalThis.done = false;
+      const ctx = __worky.createContext();
+      ctx.waitUntil(new Promise((resolve) => setTimeout(() => {
+        globalThis.done = true;
+        resolve();
+      }, 10)));
+    "#;
+    runtime.js_runtime.execute_script("<test>", code).unwrap();
+
+    settle_wait_until(&mut runtime.js_runtime, Duration::from_secs(5)).await;
+
+    let done = runtime
+      .js_runtime
+      .execute_script("<test>", "globalThis.done")
+      .unwrap();
+    let scope = &mut runtime.js_runtime.handle_scope();
+    assert!(v8::Local::new(scope, done).is_true());
+  }
+
+  #[tokio::test]
+  async fn test_parse_plain_object_response() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let res_global = {
+      let scope = &mut runtime.js_runtime.handle_scope();
+      let code = v8::String::new(scope, "({ status: 200, body: 'hi' })").unwrap();
+      let script = v8::Script::compile(scope, code, None).unwrap();
+      let result = script.run(scope).unwrap();
+      v8::Global::new(scope, result)
+    };
+
+    let result = parse_js_response(&mut runtime.js_runtime, res_global).await;
+    assert!(matches!(result, Err(WorkerError::InvalidResponse(_))));
+  }
+
+  #[tokio::test]
+  async fn test_parse_null_body_response() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let res_global = runtime
+      .js_runtime
+      .execute_script("<test>", "new Response(null, { status: 204 })")
+      .unwrap();
+
+    let (response, pumper) = parse_js_response(&mut runtime.js_runtime, res_global)
+      .await
+      .unwrap();
+    assert!(pumper.is_none());
+    assert_eq!(response.status(), hyper::StatusCode::NO_CONTENT);
+    assert!(response.headers().get("content-length").is_none());
+  }
+
+  #[tokio::test]
+  async fn test_parse_url_search_params_body() {
+    use http_body_util::BodyExt;
+
+    let mut runtime = WorkyRuntime::new(None, None);
+    let res_global = runtime
+      .js_runtime
+      .execute_script(
+        "<test>",
+        "new Response(new URLSearchParams({ a: '1', b: '2' }))",
+      )
+      .unwrap();
+
+    let (response, pumper) = parse_js_response(&mut runtime.js_runtime, res_global)
+      .await
+      .unwrap();
+    assert!(pumper.is_none());
+    assert_eq!(
+      response.headers().get("content-type").unwrap(),
+      "application/x-www-form-urlencoded;charset=UTF-8"
+    );
+    assert_eq!(response.headers().get("content-length").unwrap(), "7");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body.as_ref(), b"a=1&b=2");
+  }
+
+  #[tokio::test]
+  async fn test_scheduled_handler() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+      const exports = {
+        default: {
+          async scheduled(controller, env, ctx) {
+            globalThis.seen = `${controller.cron} at ${controller.scheduledTime}`;
+          },
+        },
+      };
+    "#;
+    let (handler, env) = handler(&mut runtime, code, "scheduled");
+
+    let time = UNIX_EPOCH + Duration::from_secs(60);
+    handle_scheduled(
+      &mut runtime.js_runtime,
+      Some(&handler),
+      &env,
+      "* * * * *",
+      time,
+    )
+    .await
+    .unwrap();
+
+    let seen = read_global(&mut runtime, "globalThis.seen");
+    assert_eq!(seen, "* * * * * at 60000");
+  }
+
+  #[tokio::test]
+  async fn test_queue_outcomes() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+      const exports = {
+        default: {
+          async queue(batch, env, ctx) {
+            for (const message of batch.messages) {
+              if (message.body.retry) message.retry({ delaySeconds: 7 });
+              if (message.body.ack) message.ack();
+            }
+            throw new Error("the rest failed");
+          },
+        },
+      };
+    "#;
+    let (handler, env) = handler(&mut runtime, code, "queue");
+    let bodies = runtime
+      .js_runtime
+      .execute_script(
+        "<test>",
+        "[{ ack: true }, { retry: true }, {}].map((body) => Deno.core.serialize(body))",
+      )
+      .unwrap();
+    let bodies: Vec<serde_v8::JsBuffer> = {
+      let scope = &mut runtime.js_runtime.handle_scope();
+      let bodies = v8::Local::new(scope, bodies);
+      serde_v8::from_v8(scope, bodies).unwrap()
+    };
+
+    let messages = bodies
+      .into_iter()
+      .enumerate()
+      .map(|(i, body)| QueueMessage {
+        id: i as u64,
+        timestamp: SystemTime::now(),
+        available_at: SystemTime::now(),
+        attempts: 1,
+        body: body.to_vec(),
+      })
+      .collect();
+    let (outcomes, failure) = handle_queue(
+      &mut runtime.js_runtime,
+      Some(&handler),
+      &env,
+      "jobs",
+      messages,
+    )
+    .await
+    .unwrap();
+
+    assert!(failure.is_some());
+    let retry = |retry, delay_seconds| QueueOutcome {
+      retry,
+      delay_seconds,
+    };
+    assert_eq!(
+      outcomes,
+      [retry(false, None), retry(true, Some(7)), retry(true, None)]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_rpc_pipeline() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+      class Calculator extends WorkerEntrypoint {
+        add(a, b) { return { sum: a + b }; }
+        fail() { throw new RangeError("nope"); }
+      }
+      const exports = { default: Calculator };
+      const call = (steps) =>
+        __worky.handleRpc(exports, undefined, Deno.core.serialize(steps), {})
+          .then((res) => Deno.core.deserialize(res));
+
+      (async () => {
+        const ok = await call([{ get: "add" }, { call: [1, 2] }, { get: "sum" }]);
+        if (ok.ok !== 3) throw new Error("pipelined call failed");
+        const err = await call([{ get: "fail" }, { call: [] }]);
+        if (err.error.name !== "RangeError") throw new Error("error type was lost");
+        const denied = await call([{ get: "constructor" }]);
+        if (!denied.error) throw new Error("constructor was reachable");
+      })()
+    "#;
+
+    let promise = runtime.js_runtime.execute_script("<test>", code).unwrap();
+    let result = runtime.js_runtime.resolve(promise).await;
+    assert!(result.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_rpc_methods_keep_their_this() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+      class Auth extends WorkerEntrypoint {
+        verify() { return this instanceof Auth; }
+      }
+      const exports = { default: Auth };
+      const call = (steps) =>
+        __worky.handleRpc(exports, undefined, Deno.core.serialize(steps), {})
+          .then((res) => Deno.core.deserialize(res));
+
+      (async () => {
+        const ok = await call([{ get: "verify" }, { call: [] }]);
+        if (ok.ok !== true) throw new Error("verify failed");
+        for (const name of ["call", "apply", "bind"]) {
+          const forged = await call([{ get: "verify" }, { get: name }, { call: [{}] }]);
+          if (forged.error?.name !== "TypeError") throw new Error(`${name} was reachable`);
+        }
+      })()
+    "#;
+
+    let promise = runtime.js_runtime.execute_script("<test>", code).unwrap();
+    let result = runtime.js_runtime.resolve(promise).await;
+    assert!(result.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_durable_object_gates() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+      class Counter extends DurableObject {
+        async increment() {
+          const count = (await this.ctx.storage.get("count")) ?? 0;
+          await this.ctx.storage.put("count", count + 1);
+          return count + 1;
+        }
+        async discard() {
+          await this.ctx.storage.transaction(async (txn) => {
+            await txn.put("count", 100);
+            if ((await txn.get("count")) !== 100) throw new Error("write was not visible");
+            txn.rollback();
+          });
+          return this.ctx.storage.get("count");
+        }
+      }
+      __worky.setWorkerModule({ Counter }, {});
+      const id = Deno.core.ops.op_do_unique_id();
+      const call = (steps) =>
+        __worky.durableObjectRpc("Counter", id, undefined, Deno.core.serialize(steps))
+          .then((res) => Deno.core.deserialize(res));
+
+      (async () => {
+        const increment = [{ get: "increment" }, { call: [] }];
+        const counts = await Promise.all([call(increment), call(increment), call(increment)]);
+        if (counts.map((res) => res.ok).sort().join() !== "1,2,3") {
+          throw new Error(`increments interleaved: ${JSON.stringify(counts)}`);
+        }
+        const discarded = await call([{ get: "discard" }, { call: [] }]);
+        if (discarded.ok !== 3) throw new Error("rolled back write landed");
+      })()
+    "#;
+
+    let promise = runtime.js_runtime.execute_script("<test>", code).unwrap();
+    let result = runtime.js_runtime.resolve(promise).await;
+    assert!(result.is_ok(), "{:?}", result.err());
+  }
+
+  #[tokio::test]
+  async fn test_durable_object_websockets() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+      class Room extends DurableObject {
+        fetch(request) {
+          const [client, server] = Object.values(new WebSocketPair());
+          this.ctx.acceptWebSocket(server, [request.headers.get("x-user")]);
+          server.serializeAttachment({ joined: 1 });
+          return new Response(null, { status: 101, webSocket: client });
+        }
+        sockets(tag) {
+          return this.ctx.getWebSockets(tag).map((ws) => this.ctx.getTags(ws).join());
+        }
+      }
+      __worky.setWorkerModule({ Room }, {});
+      const id = Deno.core.ops.op_do_unique_id();
+      const join = (user) =>
+        __worky.durableObjectFetch("Room", id, undefined, new Request("http://room/", {
+          headers: { "x-user": user },
+        }));
+
+      (async () => {
+        const alice = await join("alice");
+        await join("bob");
+        if (alice.status !== 101 || alice.webSocket === null) throw new Error("no upgrade");
+        if (typeof __worky.responseWebSocket(alice) !== "number") throw new Error("no socket id");
+        const tags = await __worky.durableObjectRpc("Room", id, undefined,
+          Deno.core.serialize([{ get: "sockets" }, { call: ["alice"] }]));
+        if (Deno.core.deserialize(tags).ok.join() !== "alice") throw new Error("tags were lost");
+      })()
+    "#;
+
+    let promise = runtime.js_runtime.execute_script("<test>", code).unwrap();
+    let result = runtime.js_runtime.resolve(promise).await;
+    assert!(result.is_ok(), "{:?}", result.err());
+  }
+
+  #[test]
+  fn test_fetch_event_redacts_credentials() {
+    let req = Request::builder()
+      .uri("/")
+      .header(hyper::header::AUTHORIZATION, "Bearer secret")
+      .header(hyper::header::COOKIE, "session=secret")
+      .header("x-user", "alice")
+      .body(hyper::body::Bytes::new())
+      .unwrap();
+    let TailEventInfo::Fetch { request, .. } = tail::fetch_event(&req) else {
+      panic!("not a fetch event");
+    };
+    assert_eq!(request.headers["authorization"], "REDACTED");
+    assert_eq!(request.headers["cookie"], "REDACTED");
+    assert_eq!(request.headers["x-user"], "alice");
+  }
+
+  #[tokio::test]
+  async fn test_durable_object_alarm_keeps_name() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+      class Room extends DurableObject {
+        alarm() {
+          globalThis.seen = this.ctx.id.name;
+        }
+      }
+      __worky.setWorkerModule({ Room }, {});
+      const id = Deno.core.ops.op_do_unique_id();
+      __worky.durableObjectAlarm("Room", id, "lobby", 0)
+    "#;
+
+    let promise = runtime.js_runtime.execute_script("<test>", code).unwrap();
+    let result = runtime.js_runtime.resolve(promise).await;
+    assert!(result.is_ok(), "{:?}", result.err());
+    assert_eq!(read_global(&mut runtime, "globalThis.seen"), "lobby");
+  }
+
+  #[tokio::test]
+  async fn test_durable_object_socket_event_keeps_name() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+      class Room extends DurableObject {
+        webSocketMessage(ws, message) {
+          globalThis.seen = `${this.ctx.id.name} ${message}`;
+        }
+      }
+      __worky.setWorkerModule({ Room }, {});
+      const id = Deno.core.ops.op_do_unique_id();
+      __worky.durableObjectWebSocket("Room", id, "lobby", 1, { type: "message", data: "hi" })
+    "#;
+
+    let promise = runtime.js_runtime.execute_script("<test>", code).unwrap();
+    let result = runtime.js_runtime.resolve(promise).await;
+    assert!(result.is_ok(), "{:?}", result.err());
+    assert_eq!(read_global(&mut runtime, "globalThis.seen"), "lobby hi");
+  }
+
+  #[tokio::test]
+  async fn test_unclaimed_sockets_are_dropped() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+      class Room extends DurableObject {
+        fetch(request) {
+          const [client, server] = Object.values(new WebSocketPair());
+          this.ctx.acceptWebSocket(server);
+          if (request.headers.has("x-upgrade")) {
+            return new Response(null, { status: 101, webSocket: client });
+          }
+          return new Response("no socket for you");
+        }
+      }
+      __worky.setWorkerModule({ Room }, {});
+      const id = Deno.core.ops.op_do_unique_id();
+      const join = (headers) =>
+        __worky.durableObjectFetch("Room", id, undefined, new Request("http://room/", { headers }));
+
+      (async () => {
+        const res = await join({ "x-upgrade": "1" });
+        await join({});
+        return __worky.responseWebSocket(res);
+      })()
+    "#;
+
+    let promise = runtime.js_runtime.execute_script("<test>", code).unwrap();
+    let kept = runtime.js_runtime.resolve(promise).await.unwrap();
+    let kept = {
+      let scope = &mut runtime.js_runtime.handle_scope();
+      v8::Local::new(scope, kept).uint32_value(scope).unwrap()
+    };
+    let state = runtime.js_runtime.op_state();
+    let held = state.borrow().borrow::<HeldSockets>().0.clone();
+    assert_eq!(held.len(), 2);
+
+    drop_held_sockets(&mut runtime.js_runtime, Some(kept));
+    for socket in held {
+      let open = worky_common::websocket::connect(socket).is_some();
+      assert_eq!(open, socket == kept);
     }
   }
+
+  #[tokio::test]
+  async fn test_tail_events() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let consumers = [TailConsumer {
+      service: "logger".into(),
+    }];
+    let trace = Trace::begin(&mut runtime.js_runtime, &consumers, || {
+      TailEventInfo::Queue {
+        queue: "jobs".into(),
+        batch_size: 2,
+      }
+    })
+    .unwrap();
+    runtime
+      .js_runtime
+      .execute_script("<test>", "console.warn('disk is almost full')")
+      .unwrap();
+    let error = WorkerError::Exception {
+      message: "Uncaught RangeError: too many jobs".into(),
+      stack: None,
+    };
+    let event = trace.end(&mut runtime.js_runtime, "worker", Some(&error));
+
+    let code = r#"
+      const exports = {
+        default: {
+          tail(events) {
+            const [event] = events;
+            globalThis.seen = [
+              event.scriptName,
+              event.outcome,
+              event.event.queue,
+              event.logs.map((log) => `${log.level}: ${log.message[0]}`).join(),
+              event.exceptions.map((e) => `${e.name}: ${e.message}`).join(),
+            ].join(" | ");
+          },
+        },
+      };
+    "#;
+    let (handler, env) = handler(&mut runtime, code, "tail");
+    handle_tail(&mut runtime.js_runtime, Some(&handler), &env, vec![event])
+      .await
+      .unwrap();
+
+    let seen = read_global(&mut runtime, "globalThis.seen");
+    assert_eq!(
+      seen,
+      "worker | exception | jobs | warn: disk is almost full | RangeError: too many jobs"
+    );
+  }
+
+  #[tokio::test]
+  async fn test_connect_handler() {
+    use std::io::Read;
+
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+      const exports = {
+        default: {
+          async connect(socket) {
+            const { remoteAddress } = await socket.opened;
+            const writer = socket.writable.getWriter();
+            await writer.write(`hello ${remoteAddress.split(":")[0]}`);
+            await writer.close();
+          },
+        },
+      };
+    "#;
+    let (handler, env) = handler(&mut runtime, code, "connect");
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    stream.set_nonblocking(true).unwrap();
+    let connections = Arc::new(Semaphore::new(1));
+    let permit = connections.clone().try_acquire_owned().unwrap();
+
+    let connection = ConnectRequest { stream, permit };
+    let connection = accept_connection(&mut runtime.js_runtime, connection).unwrap();
+    handle_connect(&mut runtime.js_runtime, Some(&handler), &env, &connection).unwrap();
+    runtime
+      .js_runtime
+      .run_event_loop(Default::default())
+      .await
+      .unwrap();
+
+    let mut greeting = String::new();
+    client.read_to_string(&mut greeting).unwrap();
+    assert_eq!(greeting, "hello 127.0.0.1");
+    assert_eq!(connection.sent.get(), greeting.len() as u64);
+    // Closing the socket gave its connection back.
+    assert_eq!(connections.available_permits(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_connect_handler_failure_ends_the_connection() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let (tx, mut rx) = unbounded_channel();
+    runtime
+      .js_runtime
+      .op_state()
+      .borrow_mut()
+      .put(ConnectionHost(tx.downgrade()));
+    let code = r#"
+      const exports = {
+        default: {
+          async connect(socket) {
+            console.log("accepted");
+            throw new TypeError("bad greeting");
+          },
+        },
+      };
+    "#;
+    let (handler, env) = handler(&mut runtime, code, "connect");
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    stream.set_nonblocking(true).unwrap();
+    let connections = Arc::new(Semaphore::new(1));
+    let permit = connections.clone().try_acquire_owned().unwrap();
+
+    let consumers = [TailConsumer {
+      service: "logger".into(),
+    }];
+    let connection = ConnectRequest { stream, permit };
+    let mut connection = accept_connection(&mut runtime.js_runtime, connection).unwrap();
+    connection.trace = Trace::begin(&mut runtime.js_runtime, &consumers, || connection.event());
+    handle_connect(&mut runtime.js_runtime, Some(&handler), &env, &connection).unwrap();
+    runtime
+      .js_runtime
+      .run_event_loop(Default::default())
+      .await
+      .unwrap();
+
+    let Ok(WorkerMessage::ConnectionEnd(end)) = rx.try_recv() else {
+      panic!("the connection did not end");
+    };
+    assert_eq!(end.socket, connection.rid);
+    let error = end.error.unwrap();
+    assert_eq!(error.to_string(), "Uncaught TypeError: bad greeting");
+    assert_eq!(connections.available_permits(), 1);
+
+    let trace = connection.trace.unwrap();
+    let event = trace.end(&mut runtime.js_runtime, "worker", Some(&error));
+    assert_eq!(event.outcome, TailOutcome::Exception);
+    assert_eq!(event.logs[0].message, ["accepted"]);
+    assert_eq!(event.exceptions[0].name, "TypeError");
+  }
+
+  #[tokio::test]
+  async fn test_lifecycle_handlers() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+      const exports = {
+        default: {
+          async init(env, ctx) {
+            env.ready = typeof ctx.waitUntil === "function";
+          },
+          shutdown(reason, env) {
+            globalThis.seen = `${reason} ${env.ready}`;
+          },
+        },
+      };
+    "#;
+    let (init, env) = handler(&mut runtime, code, "init");
+    let (shutdown, _) = handler(&mut runtime, code, "shutdown");
+    let stuck = "const exports = { default: { init: () => new Promise(() => {}) } }";
+    let (stuck, _) = handler(&mut runtime, stuck, "init");
+
+    let timeout = Duration::from_millis(50);
+    let err = handle_init(&mut runtime.js_runtime, Some(&stuck), &env, timeout)
+      .await
+      .unwrap_err();
+    assert!(err.to_string().contains("did not finish"));
+
+    handle_init(&mut runtime.js_runtime, Some(&init), &env, timeout)
+      .await
+      .unwrap();
+    handle_shutdown(
+      &mut runtime.js_runtime,
+      Some(&shutdown),
+      &env,
+      "unload",
+      timeout,
+    )
+    .await
+    .unwrap();
+
+    let seen = read_global(&mut runtime, "globalThis.seen");
+    assert_eq!(seen, "unload true");
+  }
+
+  #[tokio::test]
+  async fn test_spawn_worker_fails_on_a_broken_module() {
+    let path = std::env::temp_dir().join(format!("worky-broken-{}.js", std::process::id()));
+    std::fs::write(&path, "export default {").unwrap();
+    let result = spawn_worker(
+      "127.0.0.1:0".into(),
+      path.clone(),
+      None,
+      WorkerConfig::default(),
+    )
+    .await;
+    std::fs::remove_file(&path).unwrap();
+    assert!(result.is_err());
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn test_bind_unix_keeps_live_sockets() {
+    let path = std::env::temp_dir().join(format!("worky-bind-{}.sock", std::process::id()));
+    let addr = format!("unix:{}", path.display());
+    let _ = std::fs::remove_file(&path);
+
+    let live = listen::bind(&addr).await.unwrap();
+    let taken = listen::bind(&addr).await.err().unwrap();
+    assert_eq!(taken.kind(), std::io::ErrorKind::AddrInUse);
+
+    // The file a stopped listener left behind is taken over.
+    drop(live);
+    assert!(path.exists());
+    listen::bind(&addr).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_request_url() {
+    let mut headers = hyper::HeaderMap::new();
+    let uri: hyper::Uri = "/path?q=1".parse().unwrap();
+    assert_eq!(js::request_url(&uri, &headers), "http://localhost/path?q=1");
+
+    headers.insert(hyper::header::HOST, "example.com:8080".parse().unwrap());
+    assert_eq!(
+      js::request_url(&uri, &headers),
+      "http://example.com:8080/path?q=1"
+    );
+
+    headers.insert(hyper::header::HOST, "evil/../".parse().unwrap());
+    assert_eq!(js::request_url(&uri, &headers), "http://localhost/path?q=1");
+
+    let uri: hyper::Uri = "http://h2.example/".parse().unwrap();
+    assert_eq!(js::request_url(&uri, &headers), "http://h2.example/");
+  }
 }
@@ -0,0 +1,163 @@
+use axum::Router;
+use hyper::Request;
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use worky_common::config::Protocol;
+use worky_common::workers::{ConnectRequest, WorkerHandle, WorkerMessage};
+
+use crate::{access, compression, dispatch};
+
+/// A bound listen address, ready to be served.
+pub enum WorkerListener {
+  Tcp(tokio::net::TcpListener),
+  #[cfg(unix)]
+  Unix(tokio::net::UnixListener),
+}
+
+/// Binds a listen address: `host:port` (`[::1]:8080` for IPv6), or
+/// `unix:/path/to.sock` for a Unix domain socket.
+pub async fn bind(addr: &str) -> io::Result<WorkerListener> {
+  if let Some(path) = addr.strip_prefix("unix:") {
+    return bind_unix(path);
+  }
+
+  let addr = tokio::net::lookup_host(addr)
+    .await?
+    .next()
+    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "address did not resolve"))?;
+
+  let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+  // IPv6 sockets would otherwise also claim the IPv4 port, so `0.0.0.0:80`
+  // and `[::]:80` could not be listed side by side.
+  if addr.is_ipv6() {
+    socket.set_only_v6(true)?;
+  }
+  socket.set_reuse_address(true)?;
+  socket.set_nonblocking(true)?;
+  socket.bind(&addr.into())?;
+  socket.listen(1024)?;
+  let listener = tokio::net::TcpListener::from_std(socket.into())?;
+  Ok(WorkerListener::Tcp(listener))
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str) -> io::Result<WorkerListener> {
+  use std::os::unix::fs::FileTypeExt;
+
+  // A socket file left behind by an earlier run would make the bind fail.
+  // One a listener still answers on is not ours to take over.
+  if std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+    match std::os::unix::net::UnixStream::connect(path) {
+      Ok(_) => {
+        return Err(io::Error::new(
+          io::ErrorKind::AddrInUse,
+          format!("{path} is in use by a running listener"),
+        ))
+      }
+      Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path)?,
+      // Left to the bind to report.
+      Err(_) => {}
+    }
+  }
+  Ok(WorkerListener::Unix(tokio::net::UnixListener::bind(path)?))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_: &str) -> io::Result<WorkerListener> {
+  Err(io::Error::new(
+    io::ErrorKind::Unsupported,
+    "Unix domain sockets are not supported on this platform",
+  ))
+}
+
+fn router(handle: Arc<WorkerHandle>) -> Router {
+  let compression = compression::layer(&handle.config.compression);
+  let access_log = axum::middleware::from_fn_with_state(handle.clone(), access::access_log);
+  let mut app = Router::new().fallback(move |req: Request<axum::body::Body>| {
+    let handle = handle.clone();
+    async move { dispatch(&handle, req).await }
+  });
+  if let Some(compression) = compression {
+    app = app.layer(compression);
+  }
+  // Outermost, so it sees rate-limited requests and the compressed size.
+  app.layer(access_log)
+}
+
+/// Serves the worker on `listener` until the task is aborted.
+pub async fn serve(listener: WorkerListener, handle: Arc<WorkerHandle>) {
+  if handle.config.protocol == Protocol::Tcp {
+    return serve_tcp(listener, handle).await;
+  }
+
+  let app = router(handle.clone());
+  let result = match listener {
+    WorkerListener::Tcp(listener) => {
+      let app = app.into_make_service_with_connect_info::<SocketAddr>();
+      axum::serve(listener, app).await
+    }
+    #[cfg(unix)]
+    WorkerListener::Unix(listener) => axum::serve(listener, app).await,
+  };
+
+  if let Err(e) = result {
+    eprintln!("Error: listener for {} stopped: {e}", handle.addr);
+  }
+}
+
+/// Accepts connections for a `tcp` worker and hands each to its thread.
+/// Connections over the rate limit or `limits.max_connections` are closed
+/// right away.
+async fn serve_tcp(listener: WorkerListener, handle: Arc<WorkerHandle>) {
+  let listener = match listener {
+    WorkerListener::Tcp(listener) => listener,
+    #[cfg(unix)]
+    WorkerListener::Unix(_) => {
+      eprintln!(
+        "Error: {} speaks raw TCP and cannot listen on a Unix socket",
+        handle.addr
+      );
+      return;
+    }
+  };
+
+  loop {
+    let (stream, peer) = match listener.accept().await {
+      Ok(accepted) => accepted,
+      Err(e) => {
+        eprintln!("Error: listener for {} could not accept: {e}", handle.addr);
+        // Usually out of file descriptors; give connections time to close.
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        continue;
+      }
+    };
+    if let Some(limiter) = &handle.rate_limiter {
+      if limiter.check(&format!("ip:{}", peer.ip())).is_err() {
+        continue;
+      }
+    }
+    let Ok(permit) = handle.connections.clone().try_acquire_owned() else {
+      continue;
+    };
+    let stream = match stream.into_std() {
+      Ok(stream) => stream,
+      Err(e) => {
+        eprintln!("Error: connection from {peer} to {}: {e}", handle.addr);
+        continue;
+      }
+    };
+    let request = ConnectRequest { stream, permit };
+    if handle.sender.send(WorkerMessage::Connect(request)).is_err() {
+      return;
+    }
+  }
+}
+
+pub async fn listen_to_addr(addr: String, handle: Arc<WorkerHandle>) {
+  match bind(&addr).await {
+    Ok(listener) => serve(listener, handle).await,
+    Err(e) => eprintln!("Error: could not listen on {addr}: {e}"),
+  }
+}
@@ -0,0 +1,135 @@
+use deno_core::JsRuntime;
+use hyper::header::{HeaderName, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION};
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime};
+use worky_common::config::TailConsumer;
+use worky_common::error::WorkerError;
+use worky_common::tail::{
+  TailEvent, TailEventInfo, TailException, TailOutcome, TracedRequest, TracedResponse,
+};
+use worky_common::workers::{self, TailRequest, WorkerMessage};
+use worky_ops::ext::console::TailCapture;
+
+use crate::js::request_url;
+use crate::millis;
+
+/// An event being handled by a worker that has tail consumers. Its console
+/// output is collected until [`finish`] hands everything to them.
+pub(crate) struct Trace {
+  /// Its buffer in the [`TailCapture`].
+  id: u64,
+  started: SystemTime,
+  clock: Instant,
+  event: TailEventInfo,
+}
+
+impl Trace {
+  /// Starts tracing the event `event` describes, unless nobody tails the
+  /// worker.
+  pub(crate) fn begin(
+    runtime: &mut JsRuntime,
+    consumers: &[TailConsumer],
+    event: impl FnOnce() -> TailEventInfo,
+  ) -> Option<Self> {
+    if consumers.is_empty() {
+      return None;
+    }
+    let state = runtime.op_state();
+    let mut state = state.borrow_mut();
+    if !state.has::<TailCapture>() {
+      state.put(TailCapture::default());
+    }
+    Some(Self {
+      id: state.borrow_mut::<TailCapture>().begin(),
+      started: SystemTime::now(),
+      clock: Instant::now(),
+      event: event(),
+    })
+  }
+
+  /// Stops collecting console output and puts the event together.
+  pub(crate) fn end(
+    self,
+    runtime: &mut JsRuntime,
+    name: &str,
+    error: Option<&WorkerError>,
+  ) -> TailEvent {
+    let logs = runtime
+      .op_state()
+      .borrow_mut()
+      .try_borrow_mut::<TailCapture>()
+      .map(|capture| capture.end(self.id))
+      .unwrap_or_default();
+
+    TailEvent {
+      script_name: (!name.is_empty()).then(|| name.to_string()),
+      outcome: match error {
+        Some(_) => TailOutcome::Exception,
+        None => TailOutcome::Ok,
+      },
+      event_timestamp: millis(self.started),
+      wall_time: self.clock.elapsed().as_secs_f64() * 1000.0,
+      event: self.event,
+      logs,
+      exceptions: error.map(TailException::new).into_iter().collect(),
+    }
+  }
+
+  pub(crate) fn set_response(&mut self, status: hyper::StatusCode) {
+    if let TailEventInfo::Fetch { response, .. } = &mut self.event {
+      *response = Some(TracedResponse {
+        status: status.as_u16(),
+      });
+    }
+  }
+}
+
+/// Headers whose values tail consumers do not get to see, as they carry the
+/// client's credentials.
+const REDACTED_HEADERS: [HeaderName; 3] = [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION];
+
+/// Describes a request for the trace of its `fetch()` event.
+pub(crate) fn fetch_event(req: &hyper::Request<hyper::body::Bytes>) -> TailEventInfo {
+  let headers: HashMap<String, String> = req
+    .headers()
+    .iter()
+    .map(|(name, value)| {
+      let value = if REDACTED_HEADERS.contains(name) {
+        "REDACTED".to_string()
+      } else {
+        String::from_utf8_lossy(value.as_bytes()).into_owned()
+      };
+      (name.as_str().to_string(), value)
+    })
+    .collect();
+  TailEventInfo::Fetch {
+    request: TracedRequest {
+      url: request_url(req.uri(), req.headers()),
+      method: req.method().to_string(),
+      headers,
+    },
+    response: None,
+  }
+}
+
+/// Ends `trace`, if the event was traced, and sends it to every tail consumer
+/// that is loaded. `error` is what ended the event, if it failed.
+pub(crate) fn finish(
+  trace: Option<Trace>,
+  runtime: &mut JsRuntime,
+  name: &str,
+  consumers: &[TailConsumer],
+  error: Option<&WorkerError>,
+) {
+  let Some(trace) = trace else {
+    return;
+  };
+  let event = trace.end(runtime, name, error);
+  for consumer in consumers {
+    if let Some(handle) = workers::service(&consumer.service) {
+      let _ = handle.sender.send(WorkerMessage::Tail(TailRequest {
+        events: vec![event.clone()],
+      }));
+    }
+  }
+}
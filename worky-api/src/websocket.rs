@@ -0,0 +1,146 @@
+use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use futures::{SinkExt, StreamExt};
+use tokio::sync::mpsc::WeakUnboundedSender;
+use tokio::sync::oneshot;
+use worky_common::error::WorkerError;
+use worky_common::websocket::{self, SocketCommand, SocketEvent, SocketMessage, SocketOwner};
+use worky_common::workers::{self, DurableObjectCall, DurableObjectRequest, WorkerMessage};
+
+/// Where events of sockets owned by the worker behind a listener go when that
+/// worker has no name to be looked up by.
+#[derive(Clone)]
+pub(crate) struct LocalWorker {
+  pub key: String,
+  /// Weak, so open sockets do not keep the worker running once it is
+  /// unloaded.
+  pub sender: WeakUnboundedSender<WorkerMessage>,
+}
+
+/// Completes the upgrade for a response that handed the client end of
+/// `socket` to the client. Headers the worker set, like
+/// `Sec-WebSocket-Protocol`, are kept.
+pub(crate) fn upgrade(
+  upgrade: Option<WebSocketUpgrade>,
+  socket: u32,
+  response: hyper::Response<axum::body::Body>,
+  local: LocalWorker,
+) -> Result<hyper::Response<axum::body::Body>, WorkerError> {
+  let Some(upgrade) = upgrade else {
+    websocket::remove(socket);
+    return Err(WorkerError::InvalidResponse(
+      "a WebSocket was returned for a request that is not a WebSocket upgrade".into(),
+    ));
+  };
+
+  let mut upgraded = upgrade.on_upgrade(move |ws| run(socket, ws, local));
+  for (name, value) in response.headers() {
+    if !upgraded.headers().contains_key(name) {
+      upgraded.headers_mut().insert(name, value.clone());
+    }
+  }
+  Ok(upgraded)
+}
+
+/// Carries frames between the client and the object that accepted `socket`
+/// until either end closes it.
+async fn run(socket: u32, ws: WebSocket, local: LocalWorker) {
+  let Some((owner, mut commands)) = websocket::connect(socket) else {
+    return;
+  };
+  let (mut sink, mut stream) = ws.split();
+
+  let ended = loop {
+    tokio::select! {
+      command = commands.recv() => {
+        let message = match command {
+          Some(SocketCommand::Send(SocketMessage::Text(text))) => Message::Text(text.into()),
+          Some(SocketCommand::Send(SocketMessage::Binary(data))) => Message::Binary(data.into()),
+          Some(SocketCommand::Close { code, reason }) => {
+            let frame = CloseFrame { code, reason: reason.into() };
+            let _ = sink.send(Message::Close(Some(frame))).await;
+            break None;
+          }
+          None => break None,
+        };
+        if let Err(e) = sink.send(message).await {
+          break Some(SocketEvent::Error(e.to_string()));
+        }
+      }
+      frame = stream.next() => {
+        let message = match frame {
+          Some(Ok(Message::Text(text))) => SocketMessage::Text(text.as_str().to_owned()),
+          Some(Ok(Message::Binary(data))) => SocketMessage::Binary(data.to_vec()),
+          Some(Ok(Message::Close(frame))) => {
+            let (code, reason) = match frame {
+              Some(frame) => (frame.code, frame.reason.as_str().to_owned()),
+              None => (1005, String::new()),
+            };
+            break Some(SocketEvent::Close { code, reason, was_clean: true });
+          }
+          // Pings are answered by the connection itself.
+          Some(Ok(_)) => continue,
+          Some(Err(e)) => break Some(SocketEvent::Error(e.to_string())),
+          None => break Some(abnormal_close()),
+        };
+        deliver(&owner, &local, socket, SocketEvent::Message(message)).await;
+      }
+    }
+  };
+
+  // Gone before the object hears of it, so it can no longer send.
+  websocket::remove(socket);
+  if let Some(event) = ended {
+    let failed = matches!(event, SocketEvent::Error(_));
+    deliver(&owner, &local, socket, event).await;
+    if failed {
+      deliver(&owner, &local, socket, abnormal_close()).await;
+    }
+  }
+}
+
+/// The close event of a connection that dropped without a close frame.
+fn abnormal_close() -> SocketEvent {
+  SocketEvent::Close {
+    code: 1006,
+    reason: String::new(),
+    was_clean: false,
+  }
+}
+
+/// Runs the object's handler for `event` and waits for it, so the object
+/// sees a socket's events in order.
+async fn deliver(owner: &SocketOwner, local: &LocalWorker, socket: u32, event: SocketEvent) {
+  let sender = match workers::service(&owner.worker) {
+    Some(handle) => Some(handle.sender.clone()),
+    None if owner.worker == local.key => local.sender.upgrade(),
+    None => None,
+  };
+  let Some(sender) = sender else {
+    eprintln!(
+      "Error: WebSocket {socket} belongs to {}, which is not loaded",
+      owner.worker
+    );
+    return;
+  };
+
+  let (tx, rx) = oneshot::channel();
+  let sent = sender.send(WorkerMessage::DurableObject(DurableObjectRequest {
+    target: owner.object.clone(),
+    call: DurableObjectCall::WebSocket {
+      socket,
+      event,
+      resp: tx,
+    },
+    callers: Vec::new(),
+  }));
+  let result = match sent {
+    Ok(()) => rx.await.unwrap_or(Err(WorkerError::Unavailable)),
+    Err(_) => Err(WorkerError::Unavailable),
+  };
+  if let Err(e) = result {
+    eprintln!(
+      "Error: WebSocket {socket} of {} {}: {e}",
+      owner.object.class, owner.object.id
+    );
+  }
+}
@@ -1,6 +1,7 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use clap::Parser;
+use worky_common::config::WorkerConfig;
 use worky_socket::{keepalive, protocol::Request as SocRequest, send_request};
 
 use tracing::{error, info, Level};
@@ -23,11 +24,29 @@ enum Commands {
     path: PathBuf,
     #[arg(short, long)]
     name: Option<String>,
+    /// Path to the worker's `worky.toml`
+    #[arg(short, long)]
+    config: Option<PathBuf>,
   },
   Unload {
     #[arg(short, long)]
     address: String,
   },
+  /// Show the loaded workers, or only the one at `address`
+  Status {
+    #[arg(short, long)]
+    address: Option<String>,
+  },
+  /// Invoke a worker's handler by hand, e.g. `worky trigger app scheduled`
+  Trigger {
+    /// Name or address of the worker
+    worker: String,
+    /// Event to send: `scheduled`
+    event: String,
+    /// Cron expression the handler sees, the worker's first by default
+    #[arg(long)]
+    cron: Option<String>,
+  },
   Log {
     #[arg()]
     query: String,
@@ -60,6 +79,7 @@ async fn main() -> Result<(), anyhow::Error> {
           .join("worky-api/test/hello.js"),
         refresh: None,
         name: Some("worker1".to_string()),
+        config: Default::default(),
       });
       keepalive().await;
     }
@@ -67,17 +87,42 @@ async fn main() -> Result<(), anyhow::Error> {
       address,
       path,
       name,
+      config,
     }) => {
+      let config = match config {
+        Some(path) => {
+          let mut config: WorkerConfig = toml::from_str(&std::fs::read_to_string(&path)?)?;
+          let base = std::path::absolute(&path)?;
+          config.resolve_paths(base.parent().unwrap_or(Path::new("/")));
+          config
+        }
+        None => Default::default(),
+      };
       send_request(SocRequest::Load {
         address,
         path,
         refresh: None,
         name,
+        config,
       });
     }
     Some(Commands::Unload { address }) => {
       send_request(SocRequest::Unload { address });
     }
+    Some(Commands::Status { address }) => {
+      send_request(SocRequest::Status { address });
+    }
+    Some(Commands::Trigger {
+      worker,
+      event,
+      cron,
+    }) => {
+      send_request(SocRequest::Trigger {
+        worker,
+        event,
+        cron,
+      });
+    }
     Some(Commands::Log { query }) => {
       let logs = worky_ops::ext::console::get_logs(query);
 
@@ -88,11 +133,13 @@ async fn main() -> Result<(), anyhow::Error> {
             addr = %addr,
             "{logs}"
           ),
-          worky_ops::ext::console::LogType::Info => info!(
-            worker = name,
-            addr = %addr,
-            "{logs}"
-          ),
+          worky_ops::ext::console::LogType::Info | worky_ops::ext::console::LogType::Access => {
+            info!(
+              worker = name,
+              addr = %addr,
+              "{logs}"
+            )
+          }
         }
       }
     }
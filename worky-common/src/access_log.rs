@@ -0,0 +1,253 @@
+use crate::config::{AccessLogConfig, AccessLogFormat};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// One served request, recorded once its response body has been sent.
+#[derive(Serialize, Debug, Clone)]
+pub struct AccessEntry {
+  #[serde(serialize_with = "serialize_time")]
+  pub time: SystemTime,
+  pub request_id: String,
+  pub client_ip: Option<IpAddr>,
+  /// The worker that made the request through a service binding.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub caller: Option<String>,
+  pub method: String,
+  pub path: String,
+  pub version: String,
+  pub status: u16,
+  pub bytes: u64,
+  #[serde(rename = "duration_ms", serialize_with = "serialize_millis")]
+  pub duration: Duration,
+  pub referer: Option<String>,
+  pub user_agent: Option<String>,
+}
+
+impl AccessEntry {
+  pub fn format(&self, format: AccessLogFormat) -> String {
+    match format {
+      AccessLogFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+      // Combined log format, followed by the duration and the request id. The
+      // calling worker, if any, takes the place of the remote user.
+      AccessLogFormat::Combined => format!(
+        "{ip} - {caller} [{time}] \"{method} {path} {version}\" {status} {bytes} \"{referer}\" \"{agent}\" {ms:.3} {id}",
+        ip = self.client_ip.map(|ip| ip.to_string()).unwrap_or("-".into()),
+        caller = self.caller.as_deref().unwrap_or("-"),
+        time = clf_time(self.time),
+        method = self.method,
+        path = self.path,
+        version = self.version,
+        status = self.status,
+        bytes = self.bytes,
+        referer = self.referer.as_deref().unwrap_or("-"),
+        agent = self.user_agent.as_deref().unwrap_or("-"),
+        ms = self.duration.as_secs_f64() * 1000.0,
+        id = self.request_id,
+      ),
+    }
+  }
+}
+
+/// Unique across the daemon's lifetime and, through its start time, across
+/// restarts.
+pub fn new_request_id() -> String {
+  static START: Lazy<u64> = Lazy::new(|| {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs()
+  });
+  static NEXT: AtomicU64 = AtomicU64::new(0);
+  format!("{:x}-{:x}", *START, NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// A worker's access log: the format its lines take and the file, if any,
+/// they are appended to on top of the log store.
+pub struct AccessLog {
+  pub format: AccessLogFormat,
+  file: Option<RotatingFile>,
+}
+
+impl AccessLog {
+  pub fn new(config: &AccessLogConfig) -> Self {
+    Self {
+      format: config.format,
+      file: config
+        .file
+        .clone()
+        .map(|path| RotatingFile::new(path, config.max_size, config.max_files)),
+    }
+  }
+
+  /// Formats `entry` and appends it to the file. The line is returned for
+  /// the caller to put in the log store.
+  pub fn record(&self, entry: &AccessEntry) -> String {
+    let line = entry.format(self.format);
+    if let Some(file) = &self.file {
+      if let Err(e) = file.write_line(&line) {
+        eprintln!(
+          "Error: could not write access log {}: {e}",
+          file.path.display()
+        );
+      }
+    }
+    line
+  }
+}
+
+/// Appends lines to `path`, moving it to `path.1`, `path.1` to `path.2` and
+/// so on once it would grow past `max_size` bytes. The oldest beyond
+/// `max_files` is dropped.
+struct RotatingFile {
+  path: PathBuf,
+  max_size: u64,
+  max_files: u32,
+  file: Mutex<Option<(File, u64)>>,
+}
+
+impl RotatingFile {
+  fn new(path: PathBuf, max_size: u64, max_files: u32) -> Self {
+    Self {
+      path,
+      max_size,
+      max_files,
+      file: Mutex::new(None),
+    }
+  }
+
+  fn write_line(&self, line: &str) -> io::Result<()> {
+    let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+    let len = line.len() as u64 + 1;
+
+    let full = matches!(&*file, Some((_, size)) if *size > 0 && size + len > self.max_size);
+    if full {
+      *file = None;
+      self.rotate()?;
+    }
+
+    if file.is_none() {
+      if let Some(dir) = self.path.parent() {
+        fs::create_dir_all(dir)?;
+      }
+      let opened = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&self.path)?;
+      let size = opened.metadata()?.len();
+      *file = Some((opened, size));
+    }
+
+    let (opened, size) = file.as_mut().expect("the file was just opened");
+    writeln!(opened, "{line}")?;
+    *size += len;
+    Ok(())
+  }
+
+  fn rotate(&self) -> io::Result<()> {
+    let numbered = |n: u32| {
+      let mut path = self.path.clone().into_os_string();
+      path.push(format!(".{n}"));
+      PathBuf::from(path)
+    };
+
+    if self.max_files == 0 {
+      return remove_if_exists(&self.path);
+    }
+    remove_if_exists(&numbered(self.max_files))?;
+    for n in (1..self.max_files).rev() {
+      let from = numbered(n);
+      if from.exists() {
+        fs::rename(from, numbered(n + 1))?;
+      }
+    }
+    fs::rename(&self.path, numbered(1))
+  }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+  match fs::remove_file(path) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+    _ => Ok(()),
+  }
+}
+
+/// Splits a UNIX timestamp into UTC year, month, day, hour, minute, second.
+pub(crate) fn civil(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+  let secs = time
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_secs();
+  let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+
+  // Howard Hinnant's days-to-civil algorithm.
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let doe = z.rem_euclid(146_097);
+  let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+  let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+  let year = yoe + era * 400 + i64::from(month <= 2);
+
+  (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// `10/Oct/2000:13:55:36 +0000`
+fn clf_time(time: SystemTime) -> String {
+  const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+  ];
+  let (year, month, day, hour, min, sec) = civil(time);
+  let month = MONTHS[month as usize - 1];
+  format!("{day:02}/{month}/{year}:{hour:02}:{min:02}:{sec:02} +0000")
+}
+
+/// RFC 3339, e.g. `2000-10-10T13:55:36Z`.
+fn serialize_time<S: serde::Serializer>(time: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+  let (year, month, day, hour, min, sec) = civil(*time);
+  s.collect_str(&format_args!(
+    "{year:04}-{month:02}-{day:02}T{hour:02}:{min:02}:{sec:02}Z"
+  ))
+}
+
+fn serialize_millis<S: serde::Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+  s.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_combined_format() {
+    let entry = AccessEntry {
+      time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+      request_id: "abc".into(),
+      client_ip: Some([127, 0, 0, 1].into()),
+      caller: None,
+      method: "GET".into(),
+      path: "/index.html".into(),
+      version: "HTTP/1.1".into(),
+      status: 200,
+      bytes: 2326,
+      duration: Duration::from_millis(12),
+      referer: None,
+      user_agent: Some("curl/8.0".into()),
+    };
+    assert_eq!(
+      entry.format(AccessLogFormat::Combined),
+      "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /index.html HTTP/1.1\" 200 2326 \"-\" \"curl/8.0\" 12.000 abc"
+    );
+    assert!(entry
+      .format(AccessLogFormat::Json)
+      .contains("\"time\":\"2000-10-10T13:55:36Z\""));
+  }
+}
@@ -0,0 +1,338 @@
+use crate::config::{AssetsConfig, NotFoundHandling};
+use hyper::header::{self, HeaderMap};
+use hyper::{Method, Response, StatusCode};
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, Take};
+use tokio_util::io::ReaderStream;
+
+/// Precompressed variants looked for next to a file, in order of preference.
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// The body of an asset response: nothing, or the served part of the file,
+/// read as it is sent rather than up front.
+pub enum AssetBody {
+  Empty,
+  File(Take<File>),
+}
+
+impl From<AssetBody> for axum::body::Body {
+  fn from(body: AssetBody) -> Self {
+    match body {
+      AssetBody::Empty => axum::body::Body::empty(),
+      AssetBody::File(file) => axum::body::Body::from_stream(ReaderStream::new(file)),
+    }
+  }
+}
+
+/// Serves `path` out of the assets directory. `Ok(None)` means no file
+/// matched and the request belongs to the worker. With `fallback` set, a miss
+/// gets the app's `index.html` when the assets are a single-page app.
+pub async fn serve(
+  config: &AssetsConfig,
+  method: &Method,
+  path: &str,
+  headers: &HeaderMap,
+  fallback: bool,
+) -> io::Result<Option<Response<AssetBody>>> {
+  if method != Method::GET && method != Method::HEAD {
+    return Ok(None);
+  }
+
+  let spa = fallback && config.not_found_handling == NotFoundHandling::SinglePageApplication;
+  let file = match resolve(&config.directory, path).await {
+    Some(file) => file,
+    None if spa => match resolve(&config.directory, "/index.html").await {
+      Some(file) => file,
+      None => return Ok(None),
+    },
+    None => return Ok(None),
+  };
+
+  respond(&file, method, headers).await.map(Some)
+}
+
+/// Whether the request comes from a browser navigating to a page, which is
+/// what the single-page-app fallback applies to.
+pub fn is_navigation(headers: &HeaderMap) -> bool {
+  let header = |name| {
+    headers
+      .get(name)
+      .and_then(|v| v.to_str().ok())
+      .unwrap_or_default()
+  };
+  header("sec-fetch-mode") == "navigate" || header("accept").contains("text/html")
+}
+
+/// Maps a request path to a file under `root`, trying `index.html` for
+/// directories and a `.html` extension for bare names.
+async fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+  let decoded = percent_encoding::percent_decode_str(path)
+    .decode_utf8()
+    .ok()?;
+
+  let mut relative = PathBuf::new();
+  for part in decoded.split('/') {
+    match part {
+      "" | "." => continue,
+      ".." => return None,
+      part if part.contains('\\') || part.contains('\0') => return None,
+      part => relative.push(part),
+    }
+  }
+
+  let base = root.join(&relative);
+  let mut candidates = vec![base.join("index.html")];
+  if !decoded.ends_with('/') && relative.components().next().is_some() {
+    let mut html = base.clone().into_os_string();
+    html.push(".html");
+    candidates.insert(0, base);
+    candidates.push(html.into());
+  }
+
+  // Symlinks may point anywhere, so check where a file really lives.
+  let root = tokio::fs::canonicalize(root).await.ok()?;
+  for candidate in candidates {
+    let Ok(real) = tokio::fs::canonicalize(&candidate).await else {
+      continue;
+    };
+    let is_file = tokio::fs::metadata(&real)
+      .await
+      .is_ok_and(|meta| meta.is_file());
+    if is_file && real.starts_with(&root) {
+      return Some(real);
+    }
+  }
+  None
+}
+
+async fn respond(
+  file: &Path,
+  method: &Method,
+  headers: &HeaderMap,
+) -> io::Result<Response<AssetBody>> {
+  let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+
+  // Ranges address the plain file, so they are never served compressed.
+  let mut variant = None;
+  if range.is_none() {
+    for (encoding, extension) in PRECOMPRESSED {
+      if !accepts_encoding(headers, encoding) {
+        continue;
+      }
+      let mut path = file.as_os_str().to_owned();
+      path.push(".");
+      path.push(extension);
+      let path = PathBuf::from(path);
+      if let Ok(meta) = tokio::fs::metadata(&path).await {
+        if meta.is_file() {
+          variant = Some((encoding, path, meta));
+          break;
+        }
+      }
+    }
+  }
+
+  let (encoding, path, meta) = match variant {
+    Some((encoding, path, meta)) => (Some(encoding), path, meta),
+    None => (None, file.to_path_buf(), tokio::fs::metadata(file).await?),
+  };
+  let len = meta.len();
+  let etag = etag(&meta, encoding);
+
+  let content_type = mime_guess::from_path(file).first_or_octet_stream();
+  let content_type = match content_type.type_() {
+    mime_guess::mime::TEXT => format!("{content_type}; charset=utf-8"),
+    _ => content_type.to_string(),
+  };
+
+  let mut builder = Response::builder()
+    .header(header::CONTENT_TYPE, content_type)
+    .header(header::ETAG, &etag)
+    .header(header::CACHE_CONTROL, "public, max-age=0, must-revalidate")
+    .header(header::ACCEPT_RANGES, "bytes")
+    .header(header::VARY, "Accept-Encoding");
+  if let Some(encoding) = encoding {
+    builder = builder.header(header::CONTENT_ENCODING, encoding);
+  }
+
+  if etag_matches(headers, &etag) {
+    return builder
+      .status(StatusCode::NOT_MODIFIED)
+      .body(AssetBody::Empty)
+      .map_err(io::Error::other);
+  }
+
+  // `If-Range` only lets the range through while the file is unchanged.
+  let if_range = headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok());
+  let range = match (range, if_range) {
+    (Some(range), Some(if_range)) if if_range.trim() != etag => None,
+    (range, _) => range.and_then(|range| parse_range(range, len)),
+  };
+
+  let (status, start, end) = match range {
+    None => (StatusCode::OK, 0, len),
+    Some(Ok((start, end))) => {
+      builder = builder.header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"));
+      (StatusCode::PARTIAL_CONTENT, start, end + 1)
+    }
+    Some(Err(())) => {
+      return builder
+        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+        .body(AssetBody::Empty)
+        .map_err(io::Error::other);
+    }
+  };
+
+  let body = if method == Method::HEAD {
+    AssetBody::Empty
+  } else {
+    let mut file = File::open(&path).await?;
+    file.seek(io::SeekFrom::Start(start)).await?;
+    AssetBody::File(file.take(end - start))
+  };
+
+  builder
+    .status(status)
+    .header(header::CONTENT_LENGTH, end - start)
+    .body(body)
+    .map_err(io::Error::other)
+}
+
+/// Derives a validator from the file's size and modification time, so it
+/// changes whenever the file is replaced.
+fn etag(meta: &Metadata, encoding: Option<&str>) -> String {
+  let modified = meta
+    .modified()
+    .ok()
+    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+    .map(|time| time.as_nanos())
+    .unwrap_or_default();
+  match encoding {
+    Some(encoding) => format!("\"{:x}-{modified:x}-{encoding}\"", meta.len()),
+    None => format!("\"{:x}-{modified:x}\"", meta.len()),
+  }
+}
+
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+  headers
+    .get_all(header::IF_NONE_MATCH)
+    .iter()
+    .filter_map(|v| v.to_str().ok())
+    .flat_map(|v| v.split(','))
+    .map(|tag| tag.trim())
+    .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Whether `Accept-Encoding` allows `encoding`, i.e. lists it without `q=0`.
+pub fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+  headers
+    .get_all(header::ACCEPT_ENCODING)
+    .iter()
+    .filter_map(|v| v.to_str().ok())
+    .flat_map(|v| v.split(','))
+    .any(|item| {
+      let mut parts = item.split(';');
+      let name = parts.next().unwrap_or_default().trim();
+      let refused = parts.any(|param| {
+        param
+          .trim()
+          .strip_prefix("q=")
+          .and_then(|q| q.trim().parse::<f32>().ok())
+          .is_some_and(|q| q == 0.0)
+      });
+      name.eq_ignore_ascii_case(encoding) && !refused
+    })
+}
+
+/// Parses a single `bytes=` range into inclusive bounds. `None` means the
+/// header should be ignored and the whole file served, `Some(Err)` that the
+/// range cannot be satisfied. Multiple ranges are not supported.
+fn parse_range(value: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+  let spec = value.trim().strip_prefix("bytes=")?;
+  if spec.contains(',') {
+    return None;
+  }
+  let (start, end) = spec.split_once('-')?;
+  let (start, end) = (start.trim(), end.trim());
+
+  if start.is_empty() {
+    let suffix: u64 = end.parse().ok()?;
+    if suffix == 0 || len == 0 {
+      return Some(Err(()));
+    }
+    return Some(Ok((len.saturating_sub(suffix), len - 1)));
+  }
+
+  let start: u64 = start.parse().ok()?;
+  let end = match end {
+    "" => u64::MAX,
+    end => end.parse().ok()?,
+  };
+  if end < start {
+    return None;
+  }
+  if start >= len {
+    return Some(Err(()));
+  }
+  Some(Ok((start, end.min(len - 1))))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_range() {
+    assert_eq!(parse_range("bytes=0-4", 10), Some(Ok((0, 4))));
+    assert_eq!(parse_range("bytes=5-", 10), Some(Ok((5, 9))));
+    assert_eq!(parse_range("bytes=-3", 10), Some(Ok((7, 9))));
+    assert_eq!(parse_range("bytes=8-100", 10), Some(Ok((8, 9))));
+    assert_eq!(parse_range("bytes=10-", 10), Some(Err(())));
+    assert_eq!(parse_range("bytes=0-1,4-5", 10), None);
+    assert_eq!(parse_range("items=0-4", 10), None);
+  }
+
+  #[test]
+  fn test_accepts_encoding() {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+      header::ACCEPT_ENCODING,
+      "gzip, deflate, br;q=0".parse().unwrap(),
+    );
+    assert!(accepts_encoding(&headers, "gzip"));
+    assert!(!accepts_encoding(&headers, "br"));
+    assert!(!accepts_encoding(&headers, "zstd"));
+  }
+
+  #[tokio::test]
+  async fn test_serve_streams_range() {
+    let directory = std::env::temp_dir().join(format!("worky-assets-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("hello.txt"), "hello, world").unwrap();
+    let config = AssetsConfig {
+      directory: directory.clone(),
+      binding: None,
+      not_found_handling: NotFoundHandling::None,
+      run_worker_first: false,
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(header::RANGE, "bytes=7-11".parse().unwrap());
+
+    let response = serve(&config, &Method::GET, "/hello.txt", &headers, false)
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()[header::CONTENT_LENGTH], "5");
+    let body = axum::body::Body::from(response.into_body());
+    let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+    assert_eq!(&body[..], b"world");
+
+    std::fs::remove_dir_all(directory).unwrap();
+  }
+}
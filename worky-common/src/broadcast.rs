@@ -0,0 +1,137 @@
+use crate::workers::WorkerMessage;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc::WeakUnboundedSender;
+
+/// Isolates listening on each channel, by scope and channel name.
+static CHANNELS: Lazy<Mutex<HashMap<(String, String), Vec<Subscriber>>>> =
+  Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_ISOLATE: AtomicU64 = AtomicU64::new(1);
+
+/// A message posted on a `BroadcastChannel` in another isolate, for the
+/// channels of the same name in this one.
+#[derive(Debug, Clone)]
+pub struct BroadcastMessage {
+  pub channel: String,
+  /// The message, encoded with the V8 serializer.
+  pub data: Vec<u8>,
+}
+
+/// An isolate with open channels of some name. The sender is weak so an
+/// open channel does not keep an unloaded worker's thread alive.
+struct Subscriber {
+  isolate: u64,
+  sender: WeakUnboundedSender<WorkerMessage>,
+}
+
+fn channels() -> std::sync::MutexGuard<'static, HashMap<(String, String), Vec<Subscriber>>> {
+  CHANNELS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Identifies an isolate to the channels it subscribes to.
+pub fn isolate_id() -> u64 {
+  NEXT_ISOLATE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The scope channels are shared in: the broadcast group, when the worker
+/// joined one, or else the worker itself, by name or else address.
+pub fn scope(group: Option<&str>, worker: &str) -> String {
+  match group {
+    Some(group) => format!("group:{group}"),
+    None => format!("worker:{worker}"),
+  }
+}
+
+/// Has messages posted on `channel` in `scope` sent to `isolate`.
+pub fn subscribe(
+  scope: &str,
+  channel: &str,
+  isolate: u64,
+  sender: WeakUnboundedSender<WorkerMessage>,
+) {
+  let mut channels = channels();
+  let subscribers = channels
+    .entry((scope.to_string(), channel.to_string()))
+    .or_default();
+  if !subscribers.iter().any(|s| s.isolate == isolate) {
+    subscribers.push(Subscriber { isolate, sender });
+  }
+}
+
+pub fn unsubscribe(scope: &str, channel: &str, isolate: u64) {
+  let mut channels = channels();
+  let key = (scope.to_string(), channel.to_string());
+  if let Some(subscribers) = channels.get_mut(&key) {
+    subscribers.retain(|s| s.isolate != isolate);
+    if subscribers.is_empty() {
+      channels.remove(&key);
+    }
+  }
+}
+
+/// Sends a message posted in isolate `from` to every other isolate listening
+/// on `channel` in `scope`, returning how many it reached. Isolates whose
+/// worker is gone are dropped along the way.
+pub fn post(scope: &str, channel: &str, from: u64, data: &[u8]) -> usize {
+  let mut channels = channels();
+  let key = (scope.to_string(), channel.to_string());
+  let Some(subscribers) = channels.get_mut(&key) else {
+    return 0;
+  };
+
+  let mut reached = 0;
+  subscribers.retain(|subscriber| {
+    if subscriber.isolate == from {
+      return true;
+    }
+    let Some(sender) = subscriber.sender.upgrade() else {
+      return false;
+    };
+    let message = BroadcastMessage {
+      channel: channel.to_string(),
+      data: data.to_vec(),
+    };
+    let sent = sender.send(WorkerMessage::Broadcast(message)).is_ok();
+    reached += usize::from(sent);
+    sent
+  });
+  if subscribers.is_empty() {
+    channels.remove(&key);
+  }
+  reached
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::sync::mpsc::unbounded_channel;
+
+  #[test]
+  fn test_post_skips_sender_and_unloaded_workers() {
+    let scope = scope(None, "cache");
+    let (tx, mut rx) = unbounded_channel();
+    let (gone, _) = unbounded_channel();
+    let (me, other, unloaded) = (isolate_id(), isolate_id(), isolate_id());
+    subscribe(&scope, "invalidate", me, tx.downgrade());
+    subscribe(&scope, "invalidate", other, tx.downgrade());
+    subscribe(&scope, "invalidate", unloaded, gone.downgrade());
+    drop(gone);
+
+    assert_eq!(post(&scope, "invalidate", me, b"key"), 1);
+    match rx.try_recv() {
+      Ok(WorkerMessage::Broadcast(message)) => {
+        assert_eq!(message.channel, "invalidate");
+        assert_eq!(message.data, b"key");
+      }
+      _ => panic!("expected a broadcast"),
+    }
+    assert!(rx.try_recv().is_err());
+
+    unsubscribe(&scope, "invalidate", other);
+    assert_eq!(post(&scope, "invalidate", me, b"key"), 0);
+    unsubscribe(&scope, "invalidate", me);
+    assert!(!channels().contains_key(&(scope, "invalidate".to_string())));
+  }
+}
@@ -0,0 +1,414 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Per-worker settings, read from the worker's `worky.toml` and sent to the
+/// daemon along with the `load` request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WorkerConfig {
+  /// Development mode: error responses carry the JS exception and stack.
+  pub dev: bool,
+
+  /// What the worker's listeners speak.
+  pub protocol: Protocol,
+
+  /// Addresses the worker listens on besides the one it was loaded at, e.g.
+  /// `[::]:8080` or `unix:/run/worky/app.sock`.
+  pub listen: Vec<String>,
+
+  /// Origin that requests fall back to when the handler throws after calling
+  /// `ctx.passThroughOnException()`, e.g. `https://legacy.example.com`.
+  pub origin: Option<String>,
+
+  /// How long pending `ctx.waitUntil()` tasks may keep running once the
+  /// worker is unloaded, in milliseconds.
+  pub wait_until_timeout_ms: u64,
+
+  /// How long the `init(env, ctx)` handler may take before the load fails, in
+  /// milliseconds.
+  pub startup_timeout_ms: u64,
+
+  /// How long the `shutdown(reason)` handler may take once the worker is
+  /// unloaded, in milliseconds.
+  pub shutdown_timeout_ms: u64,
+
+  /// How long a call to another worker, through a service binding or to one
+  /// of its durable objects, may wait for the reply, in milliseconds.
+  pub subrequest_timeout_ms: u64,
+
+  /// Plain-text variables exposed on `env`.
+  pub vars: HashMap<String, String>,
+
+  /// Names of the secrets exposed on `env`, looked up in the daemon's secrets.
+  pub secrets: Vec<String>,
+
+  /// KV namespaces exposed on `env`.
+  pub kv_namespaces: Vec<KvNamespaceBinding>,
+
+  /// Other workers exposed on `env`, called in-process.
+  pub services: Vec<ServiceBinding>,
+
+  /// Durable object namespaces exposed on `env`.
+  pub durable_objects: Vec<DurableObjectBinding>,
+
+  /// Static assets served in front of the worker.
+  pub assets: Option<AssetsConfig>,
+
+  /// Compression of responses negotiated with `Accept-Encoding`.
+  pub compression: CompressionConfig,
+
+  /// Limit on inbound requests, applied before the worker runs.
+  pub rate_limit: Option<RateLimitConfig>,
+
+  /// Rate limiters exposed on `env` for keys of the worker's choosing.
+  pub rate_limiters: Vec<RateLimiterBinding>,
+
+  /// Size limits checked before a request reaches the worker.
+  pub limits: LimitsConfig,
+
+  /// Records every request the worker serves.
+  pub access_log: Option<AccessLogConfig>,
+
+  /// Events that invoke the worker without a request.
+  pub triggers: TriggersConfig,
+
+  /// Queues the worker sends to or consumes.
+  pub queues: QueuesConfig,
+
+  /// Workers whose `tail()` handler hears about every event this worker
+  /// handles, with its outcome and console output.
+  pub tail_consumers: Vec<TailConsumer>,
+
+  /// Workers naming the same group share `BroadcastChannel`s. Without one,
+  /// channels reach only the worker's own isolates.
+  pub broadcast_group: Option<String>,
+}
+
+impl Default for WorkerConfig {
+  fn default() -> Self {
+    Self {
+      dev: false,
+      protocol: Protocol::default(),
+      listen: Vec::new(),
+      origin: None,
+      wait_until_timeout_ms: 30_000,
+      startup_timeout_ms: 10_000,
+      shutdown_timeout_ms: 5_000,
+      subrequest_timeout_ms: 30_000,
+      vars: HashMap::new(),
+      secrets: Vec::new(),
+      kv_namespaces: Vec::new(),
+      services: Vec::new(),
+      durable_objects: Vec::new(),
+      assets: None,
+      compression: CompressionConfig::default(),
+      rate_limit: None,
+      rate_limiters: Vec::new(),
+      limits: LimitsConfig::default(),
+      access_log: None,
+      triggers: TriggersConfig::default(),
+      queues: QueuesConfig::default(),
+      tail_consumers: Vec::new(),
+      broadcast_group: None,
+    }
+  }
+}
+
+impl WorkerConfig {
+  /// Makes relative paths in the config relative to `base`, the directory of
+  /// the `worky.toml` they were read from, since the daemon runs elsewhere.
+  pub fn resolve_paths(&mut self, base: &Path) {
+    if let Some(assets) = &mut self.assets {
+      assets.directory = base.join(&assets.directory);
+    }
+    if let Some(file) = self.access_log.as_mut().and_then(|log| log.file.as_mut()) {
+      *file = base.join(&*file);
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+  /// Requests go to the `fetch()` handler.
+  #[default]
+  Http,
+  /// Each accepted connection goes to the `connect(socket, env, ctx)`
+  /// handler as a duplex stream.
+  Tcp,
+}
+
+/// Binds a KV namespace to a name on `env`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KvNamespaceBinding {
+  /// Name of the property on `env`, e.g. `SESSIONS`.
+  pub binding: String,
+  /// Namespace the data lives in. Workers sharing an id share the data.
+  pub id: String,
+}
+
+/// Binds another worker to a name on `env`, e.g. `env.AUTH.fetch(request)`.
+/// Requests go straight into that worker's queue instead of over the network.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceBinding {
+  pub binding: String,
+  /// Name the other worker was loaded with.
+  pub service: String,
+  /// Export whose `WorkerEntrypoint` class answers RPC calls, the default
+  /// export if unset.
+  #[serde(default)]
+  pub entrypoint: Option<String>,
+}
+
+/// Binds the objects of an exported class to a name on `env`, e.g.
+/// `env.ROOMS.get(env.ROOMS.idFromName("lobby"))`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DurableObjectBinding {
+  pub binding: String,
+  /// Exported class the objects are instances of.
+  pub class_name: String,
+  /// Name of the worker exporting the class, this worker if unset. The
+  /// objects always live in that worker.
+  #[serde(default)]
+  pub service: Option<String>,
+}
+
+/// Sends the worker's events to the `tail()` handler of another worker.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TailConsumer {
+  /// Name the consuming worker was loaded with.
+  pub service: String,
+}
+
+/// Serves a directory of static files, e.g. a frontend build.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AssetsConfig {
+  /// Directory the files are served from.
+  pub directory: PathBuf,
+  /// Name of the property on `env` that serves assets, e.g. `ASSETS`.
+  #[serde(default)]
+  pub binding: Option<String>,
+  /// What a request that matches no file gets.
+  #[serde(default)]
+  pub not_found_handling: NotFoundHandling,
+  /// Hand every request to the worker, which serves assets through its
+  /// binding, instead of serving matching files before the worker runs.
+  #[serde(default)]
+  pub run_worker_first: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotFoundHandling {
+  /// Misses go to the worker, or get a 404 through the binding.
+  #[default]
+  None,
+  /// Navigation misses get the app's `/index.html`.
+  SinglePageApplication,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CompressionConfig {
+  pub enabled: bool,
+  /// Encodings offered to clients, e.g. `["br", "gzip"]`.
+  pub algorithms: Vec<CompressionAlgorithm>,
+  /// Responses with a known length below this many bytes are sent as-is.
+  pub min_size: u16,
+}
+
+impl Default for CompressionConfig {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      algorithms: vec![
+        CompressionAlgorithm::Br,
+        CompressionAlgorithm::Zstd,
+        CompressionAlgorithm::Gzip,
+      ],
+      min_size: 1024,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+  Gzip,
+  Br,
+  Zstd,
+}
+
+/// Lets each client make `limit` requests per `period` seconds, bursting up
+/// to `burst` at once.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateLimitConfig {
+  pub limit: u32,
+  #[serde(default = "default_period")]
+  pub period: u64,
+  /// Defaults to `limit`.
+  #[serde(default)]
+  pub burst: Option<u32>,
+  /// What tells clients apart.
+  #[serde(default)]
+  pub key: RateLimitKey,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitKey {
+  /// The client's IP address.
+  #[default]
+  Ip,
+  /// The value of a request header, e.g. `{ header = "x-api-key" }`.
+  Header(String),
+  /// The request path, limiting each route as a whole.
+  Route,
+}
+
+/// Binds a rate limiter to a name on `env`, e.g. `RATE_LIMITER`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateLimiterBinding {
+  pub binding: String,
+  pub limit: u32,
+  #[serde(default = "default_period")]
+  pub period: u64,
+}
+
+fn default_period() -> u64 {
+  60
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AccessLogConfig {
+  pub format: AccessLogFormat,
+  /// File the log is also appended to, besides the log store.
+  pub file: Option<PathBuf>,
+  /// Size in bytes past which the file is rotated.
+  pub max_size: u64,
+  /// How many rotated files are kept.
+  pub max_files: u32,
+}
+
+impl Default for AccessLogConfig {
+  fn default() -> Self {
+    Self {
+      format: AccessLogFormat::Combined,
+      file: None,
+      max_size: 10 * 1024 * 1024,
+      max_files: 5,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+  /// Apache's combined log format.
+  #[default]
+  Combined,
+  /// One JSON object per line.
+  Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LimitsConfig {
+  /// Largest request body in bytes, answered with 413 beyond it.
+  pub max_body_size: u64,
+  /// Most request headers, answered with 431 beyond it.
+  pub max_header_count: usize,
+  /// Largest total size of the request headers in bytes, answered with 431.
+  pub max_header_size: usize,
+  /// Longest request target (path and query) in bytes, answered with 414.
+  pub max_url_length: usize,
+  /// Most connections a `tcp` worker holds open at once. Further ones are
+  /// closed right after they are accepted.
+  pub max_connections: usize,
+}
+
+impl Default for LimitsConfig {
+  fn default() -> Self {
+    Self {
+      max_body_size: 100 * 1024 * 1024,
+      max_header_count: 100,
+      max_header_size: 32 * 1024,
+      max_url_length: 16 * 1024,
+      max_connections: 1024,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TriggersConfig {
+  /// Cron expressions, in UTC, that invoke the `scheduled()` handler, e.g.
+  /// `"*/5 * * * *"`.
+  pub crons: Vec<String>,
+  /// What becomes of runs that came due while the daemon was down.
+  pub missed: MissedRunPolicy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MissedRunPolicy {
+  /// Missed runs are dropped.
+  Skip,
+  /// Missed runs are made up for with a single run.
+  #[default]
+  Once,
+  /// Every missed run is made up for, oldest first.
+  All,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct QueuesConfig {
+  pub producers: Vec<QueueProducerBinding>,
+  pub consumers: Vec<QueueConsumerConfig>,
+}
+
+/// Binds a queue to a name on `env`, e.g. `env.JOBS.send(message)`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueProducerBinding {
+  pub binding: String,
+  pub queue: String,
+}
+
+/// Hands the messages of `queue` to the worker's `queue()` handler. Each
+/// queue has a single consumer, the first loaded worker that declares one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueConsumerConfig {
+  pub queue: String,
+  /// Most messages delivered in one batch.
+  #[serde(default = "default_max_batch_size")]
+  pub max_batch_size: usize,
+  /// Seconds a message may wait for a batch to fill up before a smaller
+  /// batch is delivered.
+  #[serde(default = "default_max_batch_timeout")]
+  pub max_batch_timeout: u64,
+  /// How often a message is retried before it goes to the dead-letter queue,
+  /// or is dropped without one.
+  #[serde(default = "default_max_retries")]
+  pub max_retries: u32,
+  #[serde(default)]
+  pub dead_letter_queue: Option<String>,
+  /// Seconds before a retried message is delivered again, unless the retry
+  /// asks for another delay.
+  #[serde(default)]
+  pub retry_delay: u64,
+}
+
+fn default_max_batch_size() -> usize {
+  10
+}
+
+fn default_max_batch_timeout() -> u64 {
+  5
+}
+
+fn default_max_retries() -> u32 {
+  3
+}
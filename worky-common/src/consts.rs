@@ -1,3 +1,5 @@
 pub mod paths {
   pub const SOCKET_PATH: &'static str = "worky-ipc.sock";
+  /// Default location of the daemon's database, see `db::db`.
+  pub const DB_PATH: &'static str = "worky_kv.db";
 }
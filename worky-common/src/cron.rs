@@ -0,0 +1,206 @@
+use crate::access_log::civil;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+  "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// How far ahead `next_after` looks before deciding a schedule never fires,
+/// e.g. `0 0 31 2 *`.
+const SEARCH_DAYS: u64 = 5 * 366;
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid cron expression {expression:?}: {reason}")]
+pub struct CronError {
+  expression: String,
+  reason: String,
+}
+
+/// A standard five-field cron expression (`minute hour day month weekday`),
+/// evaluated in UTC. Fields take `*`, numbers, ranges, lists and `/step`s;
+/// months and weekdays also take three-letter names. `@hourly`, `@daily`,
+/// `@weekly`, `@monthly` and `@yearly` are shorthands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+  minutes: u64,
+  hours: u64,
+  days: u64,
+  months: u64,
+  weekdays: u64,
+  // As in cron, a restricted day and weekday match when either one does.
+  any_day: bool,
+  any_weekday: bool,
+}
+
+impl Schedule {
+  pub fn parse(expression: &str) -> Result<Self, CronError> {
+    let error = |reason: String| CronError {
+      expression: expression.to_string(),
+      reason,
+    };
+
+    let expanded = match expression.trim() {
+      "@yearly" | "@annually" => "0 0 1 1 *",
+      "@monthly" => "0 0 1 * *",
+      "@weekly" => "0 0 * * 0",
+      "@daily" | "@midnight" => "0 0 * * *",
+      "@hourly" => "0 * * * *",
+      other => other,
+    };
+    let fields: Vec<&str> = expanded.split_whitespace().collect();
+    let [minute, hour, day, month, weekday] = fields[..] else {
+      return Err(error(format!("expected 5 fields, got {}", fields.len())));
+    };
+
+    let weekdays = parse_field(weekday, 0, 7, &WEEKDAYS, 0).map_err(error)?;
+    Ok(Self {
+      minutes: parse_field(minute, 0, 59, &[], 0).map_err(error)?,
+      hours: parse_field(hour, 0, 23, &[], 0).map_err(error)?,
+      days: parse_field(day, 1, 31, &[], 1).map_err(error)?,
+      months: parse_field(month, 1, 12, &MONTHS, 1).map_err(error)?,
+      // 7 is another name for Sunday.
+      weekdays: (weekdays | weekdays >> 7) & 0x7f,
+      any_day: day.starts_with('*'),
+      any_weekday: weekday.starts_with('*'),
+    })
+  }
+
+  /// The first minute strictly after `time` that the schedule fires at.
+  pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+    let secs = time.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let mut secs = (secs / 60 + 1) * 60;
+    let limit = secs + SEARCH_DAYS * 86_400;
+
+    while secs < limit {
+      let now = UNIX_EPOCH + Duration::from_secs(secs);
+      let (_, month, day, hour, minute, _) = civil(now);
+      let days = secs / 86_400;
+      // 1970-01-01 was a Thursday.
+      let weekday = (days + 4) % 7;
+
+      if !self.matches_day(month, day, weekday) {
+        secs = (days + 1) * 86_400;
+      } else if !bit(self.hours, hour) {
+        secs = (secs / 3600 + 1) * 3600;
+      } else if !bit(self.minutes, minute) {
+        secs += 60;
+      } else {
+        return Some(now);
+      }
+    }
+    None
+  }
+
+  fn matches_day(&self, month: u32, day: u32, weekday: u64) -> bool {
+    if !bit(self.months, month.into()) {
+      return false;
+    }
+    let day = bit(self.days, day.into());
+    let weekday = bit(self.weekdays, weekday);
+    match (self.any_day, self.any_weekday) {
+      (false, false) => day || weekday,
+      _ => day && weekday,
+    }
+  }
+}
+
+fn bit(set: u64, n: u64) -> bool {
+  set & (1 << n) != 0
+}
+
+/// Parses one field into a bit set of the values it allows. `names` are
+/// alternative spellings of the values from `first_name` up.
+fn parse_field(
+  field: &str,
+  min: u64,
+  max: u64,
+  names: &[&str],
+  first_name: u64,
+) -> Result<u64, String> {
+  let value = |s: &str| -> Result<u64, String> {
+    let lower = s.to_ascii_lowercase();
+    let n = match names.iter().position(|name| *name == lower) {
+      Some(i) => i as u64 + first_name,
+      None => s.parse().map_err(|_| format!("{s:?} is not a number"))?,
+    };
+    if n < min || n > max {
+      return Err(format!("{n} is outside {min}-{max}"));
+    }
+    Ok(n)
+  };
+
+  let mut set = 0;
+  for item in field.split(',') {
+    let (range, step) = match item.split_once('/') {
+      Some((range, step)) => {
+        let step: u64 = step
+          .parse()
+          .ok()
+          .filter(|step| *step > 0)
+          .ok_or_else(|| format!("invalid step in {item:?}"))?;
+        (range, step)
+      }
+      None => (item, 1),
+    };
+
+    let (start, end) = match range {
+      "*" => (min, max),
+      range => match range.split_once('-') {
+        Some((start, end)) => (value(start)?, value(end)?),
+        // `5/15` runs from 5 to the end of the range.
+        None if step > 1 => (value(range)?, max),
+        None => {
+          let n = value(range)?;
+          (n, n)
+        }
+      },
+    };
+    if start > end {
+      return Err(format!("range {range:?} is backwards"));
+    }
+    for n in (start..=end).step_by(step as usize) {
+      set |= 1 << n;
+    }
+  }
+  Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn at(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+  }
+
+  #[test]
+  fn test_next_after() {
+    // 2000-10-10T13:55:36Z, a Tuesday.
+    let now = at(971_186_136);
+
+    let every_five = Schedule::parse("*/5 * * * *").unwrap();
+    assert_eq!(every_five.next_after(now), Some(at(971_186_400)));
+
+    let daily = Schedule::parse("@daily").unwrap();
+    assert_eq!(daily.next_after(now), Some(at(971_222_400)));
+
+    // Next Monday at 09:30.
+    let weekly = Schedule::parse("30 9 * * MON").unwrap();
+    assert_eq!(weekly.next_after(now), Some(at(971_688_600)));
+
+    assert_eq!(Schedule::parse("0 0 31 2 *").unwrap().next_after(now), None);
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    assert!(Schedule::parse("* * * *").is_err());
+    assert!(Schedule::parse("60 * * * *").is_err());
+    assert!(Schedule::parse("*/0 * * * *").is_err());
+    assert!(Schedule::parse("5-1 * * * *").is_err());
+    assert_eq!(
+      Schedule::parse("0 0 * * 7").unwrap(),
+      Schedule::parse("0 0 * * sun").unwrap()
+    );
+  }
+}
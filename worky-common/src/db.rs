@@ -0,0 +1,34 @@
+use once_cell::sync::Lazy;
+
+/// Trees whose names start with this hold the daemon's own state, so no KV
+/// namespace may use it.
+pub const INTERNAL_PREFIX: &str = "__worky_";
+
+static DB: Lazy<Option<sled::Db>> = Lazy::new(|| {
+  let path =
+    std::env::var("WORKY_DB_PATH").unwrap_or_else(|_| crate::consts::paths::DB_PATH.into());
+  match sled::open(&path) {
+    Ok(db) => Some(db),
+    Err(e) => {
+      eprintln!("Error: could not open the database at {path}: {e}");
+      None
+    }
+  }
+});
+
+/// The daemon's database, shared by every worker and the daemon itself.
+/// sled locks its files, so it can only be opened once per process. The
+/// path comes from `WORKY_DB_PATH`.
+pub fn db() -> Option<sled::Db> {
+  DB.clone()
+}
+
+/// Opens one of the daemon's own trees, e.g. `internal_tree("scheduler")`.
+pub fn internal_tree(name: &str) -> sled::Result<sled::Tree> {
+  match db() {
+    Some(db) => db.open_tree(format!("{INTERNAL_PREFIX}{name}")),
+    None => Err(sled::Error::Unsupported(
+      "the database is not available".into(),
+    )),
+  }
+}
@@ -0,0 +1,441 @@
+use crate::db;
+use crate::queue::{from_millis, millis};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::ops::Bound;
+use std::time::SystemTime;
+use tokio::sync::Notify;
+
+/// Wakes the daemon's alarm task when an alarm is set.
+static ALARM_NOTIFY: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// The id of the object `name` refers to, the same every time.
+pub fn id_from_name(class: &str, name: &str) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(class.as_bytes());
+  hasher.update([0]);
+  hasher.update(name.as_bytes());
+  hex(&hasher.finalize())
+}
+
+/// A fresh random id.
+pub fn unique_id() -> String {
+  hex(&rand::random::<[u8; 32]>())
+}
+
+/// Ids are 64 lowercase hex digits.
+pub fn is_valid_id(id: &str) -> bool {
+  id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// What `storage.list()` takes. Bounds and prefixes compare keys by their
+/// UTF-8 bytes.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ListOptions {
+  /// First key, inclusive.
+  pub start: Option<String>,
+  /// First key, exclusive. Takes precedence over `start`.
+  pub start_after: Option<String>,
+  /// Last key, exclusive.
+  pub end: Option<String>,
+  pub prefix: Option<String>,
+  pub reverse: bool,
+  pub limit: Option<usize>,
+}
+
+/// The storage of one durable object. Objects of a class share a tree, with
+/// every key prefixed by the object's id; ids all have the same length, so
+/// no object can see another's keys.
+pub struct ObjectStorage {
+  tree: sled::Tree,
+  prefix: Vec<u8>,
+}
+
+impl ObjectStorage {
+  /// The storage of object `id` of `class`, as exported by `worker`.
+  pub fn open(worker: &str, class: &str, id: &str) -> sled::Result<Self> {
+    match db::db() {
+      Some(db) => Self::open_in(&db, worker, class, id),
+      None => Err(sled::Error::Unsupported(
+        "the database is not available".into(),
+      )),
+    }
+  }
+
+  fn open_in(db: &sled::Db, worker: &str, class: &str, id: &str) -> sled::Result<Self> {
+    if !is_valid_id(id) {
+      return Err(sled::Error::Unsupported(format!(
+        "invalid durable object id {id:?}"
+      )));
+    }
+    let tree = db.open_tree(format!("{}do:{worker}:{class}", db::INTERNAL_PREFIX))?;
+    Ok(Self {
+      tree,
+      prefix: id.as_bytes().to_vec(),
+    })
+  }
+
+  fn key(&self, key: &str) -> Vec<u8> {
+    [self.prefix.as_slice(), key.as_bytes()].concat()
+  }
+
+  /// The values of whichever of `keys` are set.
+  pub fn get(&self, keys: &[String]) -> sled::Result<Vec<(String, Vec<u8>)>> {
+    let mut entries = Vec::new();
+    for key in keys {
+      if let Some(value) = self.tree.get(self.key(key))? {
+        entries.push((key.clone(), value.to_vec()));
+      }
+    }
+    Ok(entries)
+  }
+
+  /// Sets keys to `Some` value and deletes those set to `None`, all or
+  /// nothing.
+  pub fn apply(&self, writes: Vec<(String, Option<Vec<u8>>)>) -> sled::Result<()> {
+    let mut batch = sled::Batch::default();
+    for (key, value) in writes {
+      match value {
+        Some(value) => batch.insert(self.key(&key), value),
+        None => batch.remove(self.key(&key)),
+      }
+    }
+    self.tree.apply_batch(batch)
+  }
+
+  /// Deletes `keys`, returning how many of them were set.
+  pub fn delete(&self, keys: &[String]) -> sled::Result<usize> {
+    let mut deleted = 0;
+    let mut batch = sled::Batch::default();
+    for key in keys {
+      let key = self.key(key);
+      if self.tree.contains_key(&key)? {
+        deleted += 1;
+      }
+      batch.remove(key);
+    }
+    self.tree.apply_batch(batch)?;
+    Ok(deleted)
+  }
+
+  pub fn delete_all(&self) -> sled::Result<()> {
+    let mut batch = sled::Batch::default();
+    for entry in self.tree.scan_prefix(&self.prefix) {
+      let (key, _) = entry?;
+      batch.remove(key);
+    }
+    self.tree.apply_batch(batch)
+  }
+
+  pub fn list(&self, options: &ListOptions) -> sled::Result<Vec<(String, Vec<u8>)>> {
+    let (start, inclusive) = match (&options.start_after, &options.start, &options.prefix) {
+      (Some(after), _, _) => (self.key(after), false),
+      (None, Some(start), _) => (self.key(start), true),
+      (None, None, prefix) => (self.key(prefix.as_deref().unwrap_or("")), true),
+    };
+    let end = match &options.end {
+      Some(end) => self.key(end),
+      // The first key past every key of this object.
+      None => {
+        let mut end = self.prefix.clone();
+        *end.last_mut().expect("ids are not empty") += 1;
+        end
+      }
+    };
+    if start >= end {
+      return Ok(Vec::new());
+    }
+
+    let start = if inclusive {
+      Bound::Included(start)
+    } else {
+      Bound::Excluded(start)
+    };
+    let range = self.tree.range((start, Bound::Excluded(end)));
+    let range: Box<dyn Iterator<Item = _>> = if options.reverse {
+      Box::new(range.rev())
+    } else {
+      Box::new(range)
+    };
+    let limit = options.limit.unwrap_or(usize::MAX);
+    let mut entries = Vec::new();
+    for entry in range {
+      if entries.len() >= limit {
+        break;
+      }
+      let (key, value) = entry?;
+      let key = String::from_utf8_lossy(&key[self.prefix.len()..]).into_owned();
+      if options.prefix.as_ref().is_some_and(|p| !key.starts_with(p)) {
+        continue;
+      }
+      entries.push((key, value.to_vec()));
+    }
+    Ok(entries)
+  }
+
+  /// Waits until every write so far is on disk.
+  pub async fn sync(&self) -> sled::Result<()> {
+    self.tree.flush_async().await?;
+    Ok(())
+  }
+}
+
+/// Signalled whenever an alarm is set.
+pub fn alarm_notifier() -> &'static Notify {
+  &ALARM_NOTIFY
+}
+
+/// The alarm of one durable object, due at `time`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alarm {
+  /// The worker the object lives in, by name or else address.
+  pub worker: String,
+  pub class: String,
+  pub id: String,
+  /// The name the object's id was derived from, if any, so an object the
+  /// alarm brings back keeps it.
+  pub name: Option<String>,
+  pub time: SystemTime,
+  /// How often the object's `alarm()` handler has failed for this alarm.
+  pub retries: u32,
+}
+
+impl Alarm {
+  fn key(worker: &str, class: &str, id: &str) -> Vec<u8> {
+    [worker, class, id].join("\0").into_bytes()
+  }
+
+  fn encode(&self) -> Vec<u8> {
+    let mut bytes = millis(self.time).to_be_bytes().to_vec();
+    bytes.extend_from_slice(&self.retries.to_be_bytes());
+    // Marked, as an empty name is a name too.
+    if let Some(name) = &self.name {
+      bytes.push(1);
+      bytes.extend_from_slice(name.as_bytes());
+    }
+    bytes
+  }
+
+  fn decode(key: &[u8], bytes: &[u8]) -> Option<Self> {
+    let key = std::str::from_utf8(key).ok()?;
+    let mut parts = key.splitn(3, '\0');
+    let (worker, class, id) = (parts.next()?, parts.next()?, parts.next()?);
+    let (time, rest) = bytes.split_first_chunk::<8>()?;
+    let (retries, name) = rest.split_first_chunk::<4>()?;
+    let name = match name.split_first() {
+      Some((1, name)) => Some(String::from_utf8(name.to_vec()).ok()?),
+      Some(_) => return None,
+      None => None,
+    };
+    Some(Self {
+      worker: worker.to_string(),
+      class: class.to_string(),
+      id: id.to_string(),
+      name,
+      time: from_millis(u64::from_be_bytes(*time)),
+      retries: u32::from_be_bytes(*retries),
+    })
+  }
+}
+
+/// The alarms of all durable objects, at most one per object. An alarm stays
+/// stored until its handler succeeds, so it survives restarts and fires at
+/// least once.
+pub struct Alarms {
+  tree: sled::Tree,
+}
+
+impl Alarms {
+  pub fn open() -> sled::Result<Self> {
+    Ok(Self {
+      tree: db::internal_tree("alarms")?,
+    })
+  }
+
+  pub fn get(&self, worker: &str, class: &str, id: &str) -> sled::Result<Option<SystemTime>> {
+    let key = Alarm::key(worker, class, id);
+    Ok(
+      self
+        .tree
+        .get(&key)?
+        .and_then(|bytes| Alarm::decode(&key, &bytes))
+        .map(|alarm| alarm.time),
+    )
+  }
+
+  /// Sets the object's alarm, replacing the one it had. `name` is the name
+  /// the object's id was derived from, if any.
+  pub fn set(
+    &self,
+    worker: &str,
+    class: &str,
+    id: &str,
+    name: Option<&str>,
+    time: SystemTime,
+  ) -> sled::Result<()> {
+    let alarm = Alarm {
+      worker: worker.to_string(),
+      class: class.to_string(),
+      id: id.to_string(),
+      name: name.map(str::to_string),
+      time,
+      retries: 0,
+    };
+    self
+      .tree
+      .insert(Alarm::key(worker, class, id), alarm.encode())?;
+    alarm_notifier().notify_one();
+    Ok(())
+  }
+
+  pub fn delete(&self, worker: &str, class: &str, id: &str) -> sled::Result<()> {
+    self.tree.remove(Alarm::key(worker, class, id))?;
+    Ok(())
+  }
+
+  /// The alarms due at `now`, along with when the next of the others is.
+  pub fn due(&self, now: SystemTime) -> sled::Result<(Vec<Alarm>, Option<SystemTime>)> {
+    let mut due = Vec::new();
+    let mut next: Option<SystemTime> = None;
+    for entry in self.tree.iter() {
+      let (key, value) = entry?;
+      let Some(alarm) = Alarm::decode(&key, &value) else {
+        continue;
+      };
+      if alarm.time <= now {
+        due.push(alarm);
+      } else if next.is_none_or(|next| alarm.time < next) {
+        next = Some(alarm.time);
+      }
+    }
+    Ok((due, next))
+  }
+
+  /// Puts off `alarm` until `time` after its handler failed, unless the
+  /// object set or deleted its alarm in the meantime.
+  pub fn retry(&self, alarm: &Alarm, time: SystemTime) -> sled::Result<()> {
+    let retried = Alarm {
+      time,
+      retries: alarm.retries + 1,
+      ..alarm.clone()
+    };
+    self.swap(alarm, Some(retried.encode()))
+  }
+
+  /// Drops `alarm` once it is done with, unless the object set another one
+  /// in the meantime, e.g. from its `alarm()` handler.
+  pub fn complete(&self, alarm: &Alarm) -> sled::Result<()> {
+    self.swap(alarm, None)
+  }
+
+  fn swap(&self, alarm: &Alarm, new: Option<Vec<u8>>) -> sled::Result<()> {
+    let key = Alarm::key(&alarm.worker, &alarm.class, &alarm.id);
+    // Losing the race means the object's own change stands.
+    let _ = self.tree.compare_and_swap(key, Some(alarm.encode()), new)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_ids() {
+    let id = id_from_name("Room", "lobby");
+    assert!(is_valid_id(&id));
+    assert_eq!(id, id_from_name("Room", "lobby"));
+    assert_ne!(id, id_from_name("Other", "lobby"));
+    assert_ne!(unique_id(), unique_id());
+    assert!(!is_valid_id("lobby"));
+  }
+
+  #[test]
+  fn test_storage_list() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let (a, b) = (unique_id(), unique_id());
+    let storage = ObjectStorage::open_in(&db, "app", "Room", &a).unwrap();
+    let other = ObjectStorage::open_in(&db, "app", "Room", &b).unwrap();
+
+    let put = |key: &str| (key.to_string(), Some(key.as_bytes().to_vec()));
+    storage
+      .apply(vec![put("msg:1"), put("msg:2"), put("msg:3"), put("name")])
+      .unwrap();
+    other.apply(vec![put("msg:9")]).unwrap();
+
+    let keys = |options: ListOptions| -> Vec<String> {
+      let entries = storage.list(&options).unwrap();
+      entries.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(
+      keys(Default::default()),
+      ["msg:1", "msg:2", "msg:3", "name"]
+    );
+    let prefix = Some("msg:".to_string());
+    assert_eq!(
+      keys(ListOptions {
+        prefix: prefix.clone(),
+        reverse: true,
+        limit: Some(2),
+        ..Default::default()
+      }),
+      ["msg:3", "msg:2"]
+    );
+    assert_eq!(
+      keys(ListOptions {
+        start_after: Some("msg:1".into()),
+        end: Some("msg:3".into()),
+        ..Default::default()
+      }),
+      ["msg:2"]
+    );
+
+    assert_eq!(storage.delete(&["name".into(), "nope".into()]).unwrap(), 1);
+    storage.delete_all().unwrap();
+    assert!(keys(Default::default()).is_empty());
+    assert_eq!(other.get(&["msg:9".into()]).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn test_alarms() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let alarms = Alarms {
+      tree: db.open_tree("alarms").unwrap(),
+    };
+    let at = |secs| from_millis(secs * 1000);
+    let (a, b) = (unique_id(), unique_id());
+
+    alarms
+      .set("app", "Room", &a, Some("lobby"), at(10))
+      .unwrap();
+    alarms.set("app", "Room", &b, None, at(20)).unwrap();
+    let (due, next) = alarms.due(at(15)).unwrap();
+    assert_eq!(due.len(), 1);
+    assert_eq!(next, Some(at(20)));
+
+    let alarm = &due[0];
+    assert_eq!(alarm.id, a);
+    assert_eq!(alarm.name.as_deref(), Some("lobby"));
+    alarms.retry(alarm, at(30)).unwrap();
+    let (due, _) = alarms.due(at(30)).unwrap();
+    assert_eq!(due.iter().find(|d| d.id == a).unwrap().retries, 1);
+    assert_eq!(due.iter().find(|d| d.id == b).unwrap().name, None);
+
+    // An alarm set while the old one fired survives its completion.
+    let fired = due.into_iter().find(|d| d.id == a).unwrap();
+    alarms
+      .set("app", "Room", &a, Some("lobby"), at(40))
+      .unwrap();
+    alarms.complete(&fired).unwrap();
+    assert_eq!(alarms.get("app", "Room", &a).unwrap(), Some(at(40)));
+
+    alarms.delete("app", "Room", &b).unwrap();
+    assert_eq!(alarms.get("app", "Room", &b).unwrap(), None);
+  }
+}
@@ -12,3 +12,126 @@ macro_rules! deno_error {
     deno_core::error::CoreErrorKind::Io(::std::io::Error::new($kind, $msg)).into()
   };
 }
+
+/// Everything that can go wrong between the listener receiving a request and
+/// the worker producing a response.
+#[derive(Debug, thiserror::Error)]
+pub enum WorkerError {
+  /// The request could not be read from the client.
+  #[error("bad request: {0}")]
+  BadRequest(String),
+
+  /// The module does not export the handler for this event.
+  #[error("the worker does not export a {0}() handler")]
+  NoHandler(&'static str),
+
+  /// The handler threw, or the promise it returned rejected.
+  #[error("{message}")]
+  Exception {
+    message: String,
+    stack: Option<String>,
+  },
+
+  /// The handler returned something that could not be turned into a response.
+  #[error("invalid response: {0}")]
+  InvalidResponse(String),
+
+  /// The worker thread is gone or dropped the request.
+  #[error("the worker is not running")]
+  Unavailable,
+
+  /// Forwarding the request to the pass-through origin failed.
+  #[error("upstream request failed: {0}")]
+  Upstream(String),
+
+  /// The worker called through a binding did not answer in time.
+  #[error("no reply within {0:?}")]
+  Timeout(std::time::Duration),
+
+  /// The request body is larger than the worker accepts.
+  #[error("request body exceeds {0} bytes")]
+  PayloadTooLarge(u64),
+
+  /// The request has too many headers, or too large ones.
+  #[error("request headers exceed the limit")]
+  HeadersTooLarge,
+
+  /// The request target is longer than the worker accepts.
+  #[error("request URL exceeds {0} bytes")]
+  UriTooLong(usize),
+
+  /// The client ran out of requests; it may retry after this many seconds.
+  #[error("rate limited, retry after {retry_after}s")]
+  RateLimited { retry_after: u64 },
+
+  #[error(transparent)]
+  Internal(#[from] anyhow::Error),
+}
+
+impl WorkerError {
+  pub fn status(&self) -> hyper::StatusCode {
+    match self {
+      WorkerError::BadRequest(_) => hyper::StatusCode::BAD_REQUEST,
+      WorkerError::Unavailable | WorkerError::Upstream(_) => hyper::StatusCode::BAD_GATEWAY,
+      WorkerError::Timeout(_) => hyper::StatusCode::GATEWAY_TIMEOUT,
+      WorkerError::PayloadTooLarge(_) => hyper::StatusCode::PAYLOAD_TOO_LARGE,
+      WorkerError::HeadersTooLarge => hyper::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+      WorkerError::UriTooLong(_) => hyper::StatusCode::URI_TOO_LONG,
+      WorkerError::RateLimited { .. } => hyper::StatusCode::TOO_MANY_REQUESTS,
+      _ => hyper::StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+
+  /// The JS stack trace, when the error came out of the isolate.
+  pub fn stack(&self) -> Option<&str> {
+    match self {
+      WorkerError::Exception { stack, .. } => stack.as_deref(),
+      _ => None,
+    }
+  }
+
+  /// Renders the error for the client. Outside of dev mode only the status
+  /// line is sent so no worker internals leak.
+  pub fn to_response(&self, dev: bool) -> hyper::Response<axum::body::Body> {
+    let status = self.status();
+    let code = status.as_u16();
+    let reason = status.canonical_reason().unwrap_or("Error");
+
+    let mut builder = hyper::Response::builder().status(status);
+    if let WorkerError::RateLimited { retry_after } = self {
+      builder = builder.header(hyper::header::RETRY_AFTER, *retry_after);
+    }
+    let response = if dev {
+      let page = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{code} {reason}</title></head>\n<body>\n\
+         <h1>{code} {reason}</h1>\n<pre>{message}</pre>\n<pre>{stack}</pre>\n</body>\n</html>\n",
+        message = escape_html(&self.to_string()),
+        stack = escape_html(self.stack().unwrap_or_default()),
+      );
+      builder
+        .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(axum::body::Body::from(page))
+    } else {
+      builder
+        .header(hyper::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(axum::body::Body::from(format!("{code} {reason}")))
+    };
+
+    response.expect("error responses are always valid")
+  }
+}
+
+fn escape_html(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&#39;"),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
@@ -0,0 +1,471 @@
+use crate::db::INTERNAL_PREFIX;
+use crate::queue::{from_millis, millis};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{TransactionError, TransactionResult, Transactional};
+use std::convert::Infallible;
+use std::ops::Bound;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Most keys one `list()` call returns, and how many it returns by default.
+pub const MAX_LIST_LIMIT: usize = 1000;
+/// Largest metadata a key may carry, serialized as JSON.
+pub const MAX_METADATA_SIZE: usize = 1024;
+
+/// When each key with an expiration expires, by namespace and key.
+const EXPIRATIONS: &str = "kv_expirations";
+/// The same, ordered by time for the sweeper.
+const SCHEDULE: &str = "kv_schedule";
+/// The metadata of each key that has any, by namespace and key.
+const METADATA: &str = "kv_metadata";
+
+/// What `KV.list()` takes.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct KvListOptions {
+  pub prefix: Option<String>,
+  pub limit: Option<usize>,
+  /// Where the previous page left off, as it returned it.
+  pub cursor: Option<String>,
+}
+
+/// A page of keys, as `KV.list()` resolves to it.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct KvListPage {
+  pub keys: Vec<KvKey>,
+  pub list_complete: bool,
+  /// Set when more keys follow, to be passed back for the next page.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub cursor: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct KvKey {
+  pub name: String,
+  /// When the key expires, in seconds since the epoch.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub expiration: Option<u64>,
+  /// As JSON.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub metadata: Option<String>,
+}
+
+/// A stored value, with the metadata put along with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvEntry {
+  pub value: sled::IVec,
+  /// As JSON.
+  pub metadata: Option<String>,
+}
+
+/// What `KV.put()` takes. Expirations are in seconds.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct KvPutOptions {
+  /// When the key expires, since the epoch.
+  pub expiration: Option<f64>,
+  /// How long from now the key expires. Takes precedence over `expiration`.
+  pub expiration_ttl: Option<f64>,
+  /// Serialized as JSON.
+  pub metadata: Option<String>,
+}
+
+impl KvPutOptions {
+  /// The metadata to store, if it is not too large.
+  pub fn metadata(&self) -> anyhow::Result<Option<&str>> {
+    match self.metadata.as_deref() {
+      Some(metadata) if metadata.len() > MAX_METADATA_SIZE => {
+        anyhow::bail!("metadata may be at most {MAX_METADATA_SIZE} bytes as JSON")
+      }
+      metadata => Ok(metadata),
+    }
+  }
+
+  /// When a key put at `now` expires, if ever.
+  pub fn expires_at(&self, now: SystemTime) -> anyhow::Result<Option<SystemTime>> {
+    let after = |from: SystemTime, secs: f64| {
+      let secs = Duration::try_from_secs_f64(secs).ok()?;
+      from.checked_add(secs)
+    };
+    match (self.expiration_ttl, self.expiration) {
+      (Some(ttl), _) => match after(now, ttl).filter(|time| *time > now) {
+        Some(time) => Ok(Some(time)),
+        None => anyhow::bail!("expirationTtl must be a positive number of seconds"),
+      },
+      (None, Some(expiration)) => match after(UNIX_EPOCH, expiration).filter(|time| *time > now) {
+        Some(time) => Ok(Some(time)),
+        None => anyhow::bail!("expiration must be in the future, in seconds since the epoch"),
+      },
+      (None, None) => Ok(None),
+    }
+  }
+}
+
+/// A KV namespace. Keys that expire are also tracked in two of the daemon's
+/// trees, one by key and one by time, so they vanish as soon as they are due
+/// and the sweeper finds them after a restart. Metadata is kept in a third,
+/// so values are stored as they were put.
+pub struct KvNamespace {
+  name: String,
+  tree: sled::Tree,
+  expirations: sled::Tree,
+  schedule: sled::Tree,
+  metadata: sled::Tree,
+}
+
+impl KvNamespace {
+  /// Opens the namespace `name`, the empty name being the default namespace
+  /// behind the `KV` global.
+  pub fn open(db: &sled::Db, name: &str) -> anyhow::Result<Self> {
+    if name.starts_with(INTERNAL_PREFIX) {
+      anyhow::bail!("KV namespace {name} is reserved");
+    }
+    Ok(Self {
+      name: name.to_string(),
+      tree: namespace_tree(db, name)?,
+      expirations: internal(db, EXPIRATIONS)?,
+      schedule: internal(db, SCHEDULE)?,
+      metadata: internal(db, METADATA)?,
+    })
+  }
+
+  /// Where the key's expiration is stored: the namespace, a NUL, the key.
+  fn entry(&self, key: &[u8]) -> Vec<u8> {
+    let mut entry = self.name.as_bytes().to_vec();
+    entry.push(0);
+    entry.extend_from_slice(key);
+    entry
+  }
+
+  fn expiration(&self, key: &[u8]) -> sled::Result<Option<SystemTime>> {
+    let expiration = self.expirations.get(self.entry(key))?;
+    Ok(expiration.and_then(|bytes| decode_time(&bytes)))
+  }
+
+  fn metadata(&self, key: &[u8]) -> sled::Result<Option<String>> {
+    let metadata = self.metadata.get(self.entry(key))?;
+    Ok(metadata.map(|json| String::from_utf8_lossy(&json).into_owned()))
+  }
+
+  pub fn get(&self, key: &str) -> sled::Result<Option<KvEntry>> {
+    let Some(value) = self.tree.get(key)? else {
+      return Ok(None);
+    };
+    if let Some(expiration) = self.expiration(key.as_bytes())? {
+      if expiration <= SystemTime::now() {
+        return Ok(None);
+      }
+    }
+    Ok(Some(KvEntry {
+      value,
+      metadata: self.metadata(key.as_bytes())?,
+    }))
+  }
+
+  /// Stores `value`, replacing the key's value, expiration and metadata.
+  pub fn put(
+    &self,
+    key: &str,
+    value: &[u8],
+    expiration: Option<SystemTime>,
+    metadata: Option<&str>,
+  ) -> sled::Result<()> {
+    let entry = self.entry(key.as_bytes());
+    let trees = (
+      &self.tree,
+      &self.expirations,
+      &self.schedule,
+      &self.metadata,
+    );
+    let result: TransactionResult<(), Infallible> =
+      trees.transaction(|(tree, expirations, schedule, metadata_tree)| {
+        if let Some(old) = expirations.remove(entry.as_slice())? {
+          schedule.remove(schedule_key(&old, &entry))?;
+        }
+        if let Some(expiration) = expiration {
+          let time = millis(expiration).to_be_bytes();
+          expirations.insert(entry.as_slice(), &time[..])?;
+          schedule.insert(schedule_key(&time, &entry), sled::IVec::default())?;
+        }
+        match metadata {
+          Some(metadata) => metadata_tree.insert(entry.as_slice(), metadata)?,
+          None => metadata_tree.remove(entry.as_slice())?,
+        };
+        tree.insert(key, value)?;
+        Ok(())
+      });
+    committed(result)
+  }
+
+  pub fn delete(&self, key: &str) -> sled::Result<()> {
+    let entry = self.entry(key.as_bytes());
+    let trees = (
+      &self.tree,
+      &self.expirations,
+      &self.schedule,
+      &self.metadata,
+    );
+    let result: TransactionResult<(), Infallible> =
+      trees.transaction(|(tree, expirations, schedule, metadata)| {
+        if let Some(old) = expirations.remove(entry.as_slice())? {
+          schedule.remove(schedule_key(&old, &entry))?;
+        }
+        metadata.remove(entry.as_slice())?;
+        tree.remove(key)?;
+        Ok(())
+      });
+    committed(result)
+  }
+
+  /// Lists the keys that have not expired in byte order, a page at a time.
+  pub fn list(&self, options: &KvListOptions) -> anyhow::Result<KvListPage> {
+    let limit = options.limit.unwrap_or(MAX_LIST_LIMIT);
+    if limit == 0 || limit > MAX_LIST_LIMIT {
+      anyhow::bail!("limit must be between 1 and {MAX_LIST_LIMIT}");
+    }
+    let prefix = options.prefix.as_deref().unwrap_or("").as_bytes();
+    let after = options.cursor.as_deref().map(decode_cursor).transpose()?;
+
+    // A cursor before the prefix, as from a list with another prefix, would
+    // scan every key in between.
+    let keys = match after {
+      Some(after) if after.as_slice() >= prefix => self
+        .tree
+        .range::<&[u8], _>((Bound::Excluded(after.as_slice()), Bound::Unbounded)),
+      _ => self.tree.scan_prefix(prefix),
+    };
+
+    let now = SystemTime::now();
+    let mut page = Vec::new();
+    let mut last = None;
+    for key in keys.keys() {
+      let key = key?;
+      if !key.starts_with(prefix) {
+        break;
+      }
+      let expiration = self.expiration(&key)?;
+      if expiration.is_some_and(|expiration| expiration <= now) {
+        continue;
+      }
+      if page.len() == limit {
+        return Ok(KvListPage {
+          keys: page,
+          list_complete: false,
+          cursor: last.as_deref().map(encode_cursor),
+        });
+      }
+      page.push(KvKey {
+        name: String::from_utf8_lossy(&key).into_owned(),
+        expiration: expiration.map(|expiration| millis(expiration) / 1000),
+        metadata: self.metadata(&key)?,
+      });
+      last = Some(key);
+    }
+    Ok(KvListPage {
+      keys: page,
+      list_complete: true,
+      cursor: None,
+    })
+  }
+}
+
+/// Removes every key that expired by `now`, returning how many it removed.
+/// Keys put again since they were scheduled keep their new value.
+pub fn sweep(db: &sled::Db, now: SystemTime) -> anyhow::Result<usize> {
+  let expirations = internal(db, EXPIRATIONS)?;
+  let schedule = internal(db, SCHEDULE)?;
+  let metadata = internal(db, METADATA)?;
+  let end = (millis(now) + 1).to_be_bytes();
+
+  let mut removed = 0;
+  for scheduled in schedule.range(..end) {
+    let (scheduled, _) = scheduled?;
+    let Some((time, entry)) = scheduled.split_first_chunk::<8>() else {
+      schedule.remove(&scheduled)?;
+      continue;
+    };
+    let split = entry.iter().position(|b| *b == 0);
+    let namespace = split.and_then(|split| std::str::from_utf8(&entry[..split]).ok());
+    let (Some(split), Some(namespace)) = (split, namespace) else {
+      schedule.remove(&scheduled)?;
+      continue;
+    };
+    let tree = namespace_tree(db, namespace)?;
+    let key = &entry[split + 1..];
+
+    let trees = (&tree, &expirations, &schedule, &metadata);
+    let result: TransactionResult<bool, Infallible> =
+      trees.transaction(|(tree, expirations, schedule, metadata)| {
+        schedule.remove(scheduled.clone())?;
+        if expirations.get(entry)?.as_deref() != Some(time.as_slice()) {
+          return Ok(false);
+        }
+        expirations.remove(entry)?;
+        metadata.remove(entry)?;
+        tree.remove(key)?;
+        Ok(true)
+      });
+    removed += usize::from(committed(result)?);
+  }
+  Ok(removed)
+}
+
+fn namespace_tree(db: &sled::Db, name: &str) -> sled::Result<sled::Tree> {
+  if name.is_empty() {
+    Ok((**db).clone())
+  } else {
+    db.open_tree(name)
+  }
+}
+
+fn internal(db: &sled::Db, name: &str) -> sled::Result<sled::Tree> {
+  db.open_tree(format!("{INTERNAL_PREFIX}{name}"))
+}
+
+fn schedule_key(time: &[u8], entry: &[u8]) -> Vec<u8> {
+  [time, entry].concat()
+}
+
+fn decode_time(bytes: &[u8]) -> Option<SystemTime> {
+  let millis = u64::from_be_bytes(bytes.try_into().ok()?);
+  Some(from_millis(millis))
+}
+
+/// The transactions here never abort, so they only fail on storage errors.
+pub(crate) fn committed<T>(result: TransactionResult<T, Infallible>) -> sled::Result<T> {
+  result.map_err(|err| match err {
+    TransactionError::Abort(never) => match never {},
+    TransactionError::Storage(err) => err,
+  })
+}
+
+/// Cursors are the last key listed, in hex, so any key round-trips.
+fn encode_cursor(key: &[u8]) -> String {
+  key.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn decode_cursor(cursor: &str) -> anyhow::Result<Vec<u8>> {
+  let invalid = || anyhow::anyhow!("invalid cursor");
+  if cursor.len() % 2 != 0 {
+    return Err(invalid());
+  }
+  (0..cursor.len())
+    .step_by(2)
+    .map(|i| {
+      let digits = cursor.get(i..i + 2).ok_or_else(invalid)?;
+      u8::from_str_radix(digits, 16).map_err(|_| invalid())
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_list_pages() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let kv = KvNamespace::open(&db, "").unwrap();
+    for key in ["session:a", "session:b", "session:c", "user:a"] {
+      kv.put(key, b"1", None, None).unwrap();
+    }
+
+    let names =
+      |page: &KvListPage| -> Vec<String> { page.keys.iter().map(|key| key.name.clone()).collect() };
+    let mut options = KvListOptions {
+      prefix: Some("session:".into()),
+      limit: Some(2),
+      cursor: None,
+    };
+    let first = kv.list(&options).unwrap();
+    assert_eq!(names(&first), ["session:a", "session:b"]);
+    assert!(!first.list_complete);
+
+    options.cursor = first.cursor;
+    let second = kv.list(&options).unwrap();
+    assert_eq!(names(&second), ["session:c"]);
+    assert!(second.list_complete);
+    assert_eq!(second.cursor, None);
+
+    let all = kv.list(&KvListOptions::default()).unwrap();
+    assert_eq!(all.keys.len(), 4);
+
+    options.cursor = Some("zz".into());
+    assert!(kv.list(&options).is_err());
+    options.limit = Some(0);
+    assert!(kv.list(&options).is_err());
+  }
+
+  #[test]
+  fn test_expiration() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let kv = KvNamespace::open(&db, "sessions").unwrap();
+    let now = SystemTime::now();
+    kv.put("gone", b"1", Some(now - Duration::from_secs(1)), None)
+      .unwrap();
+    kv.put("later", b"2", Some(now + Duration::from_secs(3600)), None)
+      .unwrap();
+    kv.put("renewed", b"3", Some(now - Duration::from_secs(1)), None)
+      .unwrap();
+    kv.put("renewed", b"4", None, None).unwrap();
+
+    assert_eq!(kv.get("gone").unwrap(), None);
+    let renewed = kv.get("renewed").unwrap().unwrap();
+    assert_eq!(renewed.value, b"4");
+    let keys = kv.list(&KvListOptions::default()).unwrap().keys;
+    let names: Vec<_> = keys.iter().map(|key| key.name.as_str()).collect();
+    assert_eq!(names, ["later", "renewed"]);
+    assert!(keys[0].expiration.is_some());
+
+    // Only the expired key is still stored, until the sweeper comes by.
+    assert_eq!(sweep(&db, now).unwrap(), 1);
+    assert!(!kv.tree.contains_key("gone").unwrap());
+    assert!(kv.tree.contains_key("later").unwrap());
+    assert_eq!(sweep(&db, now + Duration::from_secs(7200)).unwrap(), 1);
+    assert!(!kv.tree.contains_key("later").unwrap());
+    assert!(kv.tree.contains_key("renewed").unwrap());
+  }
+
+  #[test]
+  fn test_metadata() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let kv = KvNamespace::open(&db, "").unwrap();
+    let value = [0xff, 0x00, 0xfe];
+    kv.put("blob", &value, None, Some(r#"{"type":"png"}"#))
+      .unwrap();
+
+    let entry = kv.get("blob").unwrap().unwrap();
+    assert_eq!(entry.value, &value[..]);
+    assert_eq!(entry.metadata.as_deref(), Some(r#"{"type":"png"}"#));
+    let keys = kv.list(&KvListOptions::default()).unwrap().keys;
+    assert_eq!(keys[0].metadata, entry.metadata);
+
+    // Putting the key again without metadata drops it.
+    kv.put("blob", &value, None, None).unwrap();
+    assert_eq!(kv.get("blob").unwrap().unwrap().metadata, None);
+
+    let large = KvPutOptions {
+      metadata: Some("x".repeat(MAX_METADATA_SIZE + 1)),
+      ..Default::default()
+    };
+    assert!(large.metadata().is_err());
+  }
+
+  #[test]
+  fn test_put_options() {
+    let now = UNIX_EPOCH + Duration::from_secs(1000);
+    let ttl = KvPutOptions {
+      expiration_ttl: Some(60.0),
+      ..Default::default()
+    };
+    assert_eq!(
+      ttl.expires_at(now).unwrap(),
+      Some(now + Duration::from_secs(60))
+    );
+    let past = KvPutOptions {
+      expiration: Some(900.0),
+      ..Default::default()
+    };
+    assert!(past.expires_at(now).is_err());
+    assert_eq!(KvPutOptions::default().expires_at(now).unwrap(), None);
+  }
+}
@@ -1,5 +1,17 @@
+pub mod access_log;
+pub mod assets;
+pub mod broadcast;
+pub mod config;
 pub mod consts;
+pub mod cron;
+pub mod db;
+pub mod durable;
 pub mod error;
+pub mod kv;
+pub mod queue;
+pub mod ratelimit;
+pub mod tail;
+pub mod websocket;
 pub mod workers;
 
 pub type ResultBytes<T = Vec<u8>> = T;
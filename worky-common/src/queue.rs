@@ -0,0 +1,305 @@
+use crate::db;
+use crate::kv::committed;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use sled::transaction::{TransactionResult, Transactional};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+/// Wakes a queue's consumer when messages are sent, by queue name.
+static NOTIFIERS: Lazy<Mutex<HashMap<String, Arc<Notify>>>> =
+  Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Signalled whenever messages are sent to `queue`.
+pub fn notifier(queue: &str) -> Arc<Notify> {
+  let mut notifiers = NOTIFIERS.lock().unwrap();
+  notifiers.entry(queue.to_string()).or_default().clone()
+}
+
+/// A message waiting in a queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueMessage {
+  /// Ids grow with every message sent, so they also order the queue.
+  pub id: u64,
+  /// When the message was sent.
+  pub timestamp: SystemTime,
+  /// When the message may next be delivered, later than `timestamp` for
+  /// delayed sends and retries.
+  pub available_at: SystemTime,
+  /// How often the message has been delivered.
+  pub attempts: u32,
+  /// The message, encoded with the V8 serializer.
+  pub body: Vec<u8>,
+}
+
+impl QueueMessage {
+  fn encode(&self) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(20 + self.body.len());
+    bytes.extend_from_slice(&millis(self.timestamp).to_be_bytes());
+    bytes.extend_from_slice(&millis(self.available_at).to_be_bytes());
+    bytes.extend_from_slice(&self.attempts.to_be_bytes());
+    bytes.extend_from_slice(&self.body);
+    bytes
+  }
+
+  /// Where the message is in the schedule: when it is available, then its id.
+  fn schedule_key(&self) -> [u8; 16] {
+    schedule_key(&millis(self.available_at).to_be_bytes(), self.id)
+  }
+
+  fn decode(key: &[u8], bytes: &[u8]) -> Option<Self> {
+    let id = u64::from_be_bytes(key.try_into().ok()?);
+    let (timestamp, rest) = bytes.split_first_chunk::<8>()?;
+    let (available_at, rest) = rest.split_first_chunk::<8>()?;
+    let (attempts, body) = rest.split_first_chunk::<4>()?;
+    Some(Self {
+      id,
+      timestamp: from_millis(u64::from_be_bytes(*timestamp)),
+      available_at: from_millis(u64::from_be_bytes(*available_at)),
+      attempts: u32::from_be_bytes(*attempts),
+      body: body.to_vec(),
+    })
+  }
+}
+
+/// What the consumer made of a delivered message.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueOutcome {
+  pub retry: bool,
+  /// Overrides the consumer's `retry_delay`.
+  #[serde(default)]
+  pub delay_seconds: Option<u64>,
+}
+
+/// A queue, stored in its own tree of the daemon's database. Messages stay
+/// in it until their consumer acknowledges them, so they survive restarts
+/// and are delivered at least once. A second tree schedules them by when
+/// they become available, so finding those that are ready does not read
+/// the ones that are not.
+pub struct Queue {
+  name: String,
+  db: sled::Db,
+  tree: sled::Tree,
+  schedule: sled::Tree,
+}
+
+impl Queue {
+  pub fn open(name: &str) -> sled::Result<Self> {
+    match db::db() {
+      Some(db) => Self::open_in(&db, name),
+      None => Err(sled::Error::Unsupported(
+        "the database is not available".into(),
+      )),
+    }
+  }
+
+  fn open_in(db: &sled::Db, name: &str) -> sled::Result<Self> {
+    let queue = Self {
+      name: name.to_string(),
+      db: db.clone(),
+      tree: db.open_tree(format!("{}queue:{name}", db::INTERNAL_PREFIX))?,
+      schedule: db.open_tree(format!("{}queue_schedule:{name}", db::INTERNAL_PREFIX))?,
+    };
+    // Queues stored before there was a schedule get theirs built once.
+    if queue.schedule.is_empty() {
+      for entry in queue.tree.iter() {
+        let (key, value) = entry?;
+        if let Some(message) = QueueMessage::decode(&key, &value) {
+          queue
+            .schedule
+            .insert(message.schedule_key(), sled::IVec::default())?;
+        }
+      }
+    }
+    Ok(queue)
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Appends `(body, delay)` messages in one go and wakes the consumer.
+  pub fn send(&self, messages: impl IntoIterator<Item = (Vec<u8>, Duration)>) -> sled::Result<()> {
+    let now = SystemTime::now();
+    let messages = messages
+      .into_iter()
+      .map(|(body, delay)| {
+        Ok(QueueMessage {
+          id: self.db.generate_id()?,
+          timestamp: now,
+          available_at: now + delay,
+          attempts: 0,
+          body,
+        })
+      })
+      .collect::<sled::Result<Vec<_>>>()?;
+    let result: TransactionResult<(), Infallible> =
+      (&self.tree, &self.schedule).transaction(|(tree, schedule)| {
+        for message in &messages {
+          tree.insert(message.id.to_be_bytes().as_slice(), message.encode())?;
+          schedule.insert(message.schedule_key().as_slice(), sled::IVec::default())?;
+        }
+        Ok(())
+      });
+    committed(result)?;
+    notifier(&self.name).notify_one();
+    Ok(())
+  }
+
+  /// Up to `limit` of the messages available at `now`, in the order they
+  /// became available, along with when the next of the others does.
+  pub fn ready(
+    &self,
+    now: SystemTime,
+    limit: usize,
+  ) -> sled::Result<(Vec<QueueMessage>, Option<SystemTime>)> {
+    let end = (millis(now) + 1).to_be_bytes();
+    let mut ready = Vec::new();
+    for scheduled in self.schedule.range(..end) {
+      if ready.len() == limit {
+        break;
+      }
+      let (scheduled, _) = scheduled?;
+      let id = scheduled.get(8..).unwrap_or_default();
+      let message = match self.tree.get(id)? {
+        Some(value) => QueueMessage::decode(id, &value),
+        None => None,
+      };
+      match message {
+        Some(message) if message.schedule_key() == *scheduled => ready.push(message),
+        // Left behind by a message that is gone or was rescheduled.
+        _ => {
+          self.schedule.remove(&scheduled)?;
+        }
+      }
+    }
+    let next = match self.schedule.range(end..).next() {
+      Some(scheduled) => {
+        let (scheduled, _) = scheduled?;
+        scheduled
+          .first_chunk::<8>()
+          .map(|time| from_millis(u64::from_be_bytes(*time)))
+      }
+      None => None,
+    };
+    Ok((ready, next))
+  }
+
+  /// Puts a delivered message back to be delivered again after `delay`.
+  pub fn retry(&self, message: &QueueMessage, delay: Duration) -> sled::Result<()> {
+    let message = QueueMessage {
+      available_at: SystemTime::now() + delay,
+      ..message.clone()
+    };
+    let result: TransactionResult<(), Infallible> =
+      (&self.tree, &self.schedule).transaction(|(tree, schedule)| {
+        let id = message.id.to_be_bytes();
+        let old = tree.insert(id.as_slice(), message.encode())?;
+        if let Some(old) = old.and_then(|old| QueueMessage::decode(&id, &old)) {
+          schedule.remove(old.schedule_key().as_slice())?;
+        }
+        schedule.insert(message.schedule_key().as_slice(), sled::IVec::default())?;
+        Ok(())
+      });
+    committed(result)
+  }
+
+  pub fn remove(&self, id: u64) -> sled::Result<()> {
+    let result: TransactionResult<(), Infallible> =
+      (&self.tree, &self.schedule).transaction(|(tree, schedule)| {
+        let id = id.to_be_bytes();
+        let old = tree.remove(id.as_slice())?;
+        if let Some(old) = old.and_then(|old| QueueMessage::decode(&id, &old)) {
+          schedule.remove(old.schedule_key().as_slice())?;
+        }
+        Ok(())
+      });
+    committed(result)
+  }
+
+  pub fn len(&self) -> usize {
+    self.tree.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.tree.is_empty()
+  }
+}
+
+fn schedule_key(time: &[u8; 8], id: u64) -> [u8; 16] {
+  let mut key = [0; 16];
+  key[..8].copy_from_slice(time);
+  key[8..].copy_from_slice(&id.to_be_bytes());
+  key
+}
+
+pub(crate) fn millis(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_millis() as u64
+}
+
+pub(crate) fn from_millis(millis: u64) -> SystemTime {
+  UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_send_ready_retry() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let queue = Queue::open_in(&db, "jobs").unwrap();
+    queue
+      .send([
+        (b"first".to_vec(), Duration::ZERO),
+        (b"later".to_vec(), Duration::from_secs(60)),
+        (b"second".to_vec(), Duration::ZERO),
+      ])
+      .unwrap();
+
+    let now = SystemTime::now();
+    let (ready, next) = queue.ready(now, 10).unwrap();
+    let bodies: Vec<_> = ready.iter().map(|m| m.body.as_slice()).collect();
+    assert_eq!(bodies, [b"first".as_slice(), b"second"]);
+    assert!(next.is_some_and(|next| next > now));
+
+    let mut first = ready[0].clone();
+    first.attempts += 1;
+    queue.retry(&first, Duration::from_secs(30)).unwrap();
+    queue.remove(ready[1].id).unwrap();
+
+    let (ready, _) = queue.ready(SystemTime::now(), 10).unwrap();
+    assert!(ready.is_empty());
+    let (ready, _) = queue.ready(now + Duration::from_secs(45), 10).unwrap();
+    assert_eq!(ready.len(), 1);
+    assert_eq!(ready[0].attempts, 1);
+    assert_eq!(queue.len(), 2);
+  }
+
+  #[test]
+  fn test_schedule_is_rebuilt() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let queue = Queue::open_in(&db, "jobs").unwrap();
+    queue
+      .send([
+        (b"later".to_vec(), Duration::from_secs(60)),
+        (b"now".to_vec(), Duration::ZERO),
+      ])
+      .unwrap();
+    queue.schedule.clear().unwrap();
+
+    let queue = Queue::open_in(&db, "jobs").unwrap();
+    let now = SystemTime::now();
+    let (ready, next) = queue.ready(now, 10).unwrap();
+    assert_eq!(ready.len(), 1);
+    assert_eq!(ready[0].body, b"now");
+    assert!(next.is_some_and(|next| next > now));
+  }
+}
@@ -0,0 +1,111 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Most keys a limiter tracks. Past it, the least recently seen key's bucket
+/// is dropped to make room.
+const MAX_KEYS: usize = 10_000;
+
+/// Token-bucket limiter shared by every request to a worker: each key may
+/// burst up to `capacity` requests and earns them back at `rate` per second.
+pub struct RateLimiter {
+  capacity: f64,
+  rate: f64,
+  buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+  by_key: HashMap<String, Bucket>,
+  /// Keys by when they were last seen, oldest first.
+  by_use: BTreeMap<u64, String>,
+  uses: u64,
+}
+
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+  last_use: u64,
+}
+
+impl RateLimiter {
+  /// Allows `limit` requests per `period`, bursting up to `burst`.
+  pub fn new(limit: u32, period: Duration, burst: u32) -> Self {
+    let period = period.as_secs_f64().max(f64::EPSILON);
+    Self {
+      capacity: f64::from(burst.max(1)),
+      rate: f64::from(limit.max(1)) / period,
+      buckets: Mutex::new(Buckets::default()),
+    }
+  }
+
+  /// Takes a token for `key`. When none is left, returns how long until the
+  /// next one is earned.
+  pub fn check(&self, key: &str) -> Result<(), Duration> {
+    let now = Instant::now();
+    let mut buckets = self.buckets.lock().unwrap();
+    let buckets = &mut *buckets;
+    buckets.uses += 1;
+    let last_use = buckets.uses;
+
+    if buckets.by_key.len() >= MAX_KEYS && !buckets.by_key.contains_key(key) {
+      if let Some((_, oldest)) = buckets.by_use.pop_first() {
+        buckets.by_key.remove(&oldest);
+      }
+    }
+
+    let bucket = buckets.by_key.entry(key.to_string()).or_insert(Bucket {
+      tokens: self.capacity,
+      updated: now,
+      last_use,
+    });
+    buckets.by_use.remove(&bucket.last_use);
+    buckets.by_use.insert(last_use, key.to_string());
+    bucket.last_use = last_use;
+    bucket.tokens = self.refilled(bucket, now);
+    bucket.updated = now;
+
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      Ok(())
+    } else {
+      Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+    }
+  }
+
+  fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    (bucket.tokens + elapsed * self.rate).min(self.capacity)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_bucket_runs_dry() {
+    let limiter = RateLimiter::new(1, Duration::from_secs(60), 2);
+    assert!(limiter.check("a").is_ok());
+    assert!(limiter.check("a").is_ok());
+    let retry_after = limiter.check("a").unwrap_err();
+    assert!(retry_after > Duration::from_secs(50));
+    assert!(limiter.check("b").is_ok());
+  }
+
+  #[test]
+  fn test_evicts_least_recently_seen() {
+    let limiter = RateLimiter::new(1, Duration::from_secs(60), 1);
+    assert!(limiter.check("kept").is_ok());
+    for i in 0..MAX_KEYS * 2 {
+      let _ = limiter.check(&format!("spray-{i}"));
+      if i % 100 == 0 {
+        assert!(limiter.check("kept").is_err());
+      }
+    }
+    let buckets = limiter.buckets.lock().unwrap();
+    assert_eq!(buckets.by_key.len(), MAX_KEYS);
+    assert_eq!(buckets.by_use.len(), MAX_KEYS);
+    assert!(buckets.by_key.contains_key("kept"));
+  }
+}
@@ -0,0 +1,157 @@
+use crate::error::WorkerError;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a worker's tail consumers hear about one event it handled, as the
+/// consumer's `tail(events)` handler receives it.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TailEvent {
+  /// Name of the worker that handled the event, `null` when it has none.
+  pub script_name: Option<String>,
+  pub outcome: TailOutcome,
+  /// When the event started, in milliseconds since the epoch.
+  pub event_timestamp: f64,
+  /// How long the event took, in milliseconds.
+  pub wall_time: f64,
+  pub event: TailEventInfo,
+  pub logs: Vec<TailLog>,
+  pub exceptions: Vec<TailException>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TailOutcome {
+  Ok,
+  /// The handler threw, or the worker could not run it.
+  Exception,
+}
+
+/// The event that was handled, shaped after what started it.
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum TailEventInfo {
+  Fetch {
+    request: TracedRequest,
+    /// `null` when no response was produced.
+    response: Option<TracedResponse>,
+  },
+  Scheduled {
+    cron: String,
+    #[serde(rename = "scheduledTime")]
+    scheduled_time: f64,
+  },
+  Queue {
+    queue: String,
+    #[serde(rename = "batchSize")]
+    batch_size: usize,
+  },
+  Connect {
+    #[serde(rename = "remoteAddress")]
+    remote_address: String,
+    #[serde(rename = "localAddress")]
+    local_address: String,
+  },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TracedRequest {
+  pub url: String,
+  pub method: String,
+  pub headers: HashMap<String, String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TracedResponse {
+  pub status: u16,
+}
+
+/// A line the worker wrote to the console.
+#[derive(Serialize, Debug, Clone)]
+pub struct TailLog {
+  pub timestamp: f64,
+  /// `debug`, `log`, `warn` or `error`.
+  pub level: String,
+  pub message: Vec<String>,
+}
+
+impl TailLog {
+  /// A line written at deno_console's print `level`, 0 for `console.debug()`
+  /// up to 3 for `console.error()`.
+  pub fn new(level: u32, message: &str) -> Self {
+    let level = match level {
+      0 => "debug",
+      1 => "log",
+      2 => "warn",
+      _ => "error",
+    };
+    Self {
+      timestamp: now(),
+      level: level.to_string(),
+      message: vec![message.trim_end().to_string()],
+    }
+  }
+}
+
+/// An exception that ended the event.
+#[derive(Serialize, Debug, Clone)]
+pub struct TailException {
+  pub timestamp: f64,
+  pub name: String,
+  pub message: String,
+}
+
+impl TailException {
+  /// Splits a JS exception like `Uncaught TypeError: boom` into its name and
+  /// message. Failures outside of JS are reported as `Error`.
+  pub fn new(err: &WorkerError) -> Self {
+    let (name, message) = match err {
+      WorkerError::Exception { message, .. } => {
+        let message = message.strip_prefix("Uncaught ").unwrap_or(message);
+        match message.split_once(": ") {
+          Some((name, rest)) if is_error_name(name) => (name.to_string(), rest.to_string()),
+          _ => ("Error".to_string(), message.to_string()),
+        }
+      }
+      err => ("Error".to_string(), err.to_string()),
+    };
+    Self {
+      timestamp: now(),
+      name,
+      message,
+    }
+  }
+}
+
+fn is_error_name(name: &str) -> bool {
+  !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Milliseconds since the epoch, as JS dates count them.
+fn now() -> f64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_millis() as f64
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_exception_names() {
+    let thrown = WorkerError::Exception {
+      message: "Uncaught TypeError: x is not a function".into(),
+      stack: None,
+    };
+    let exception = TailException::new(&thrown);
+    assert_eq!(exception.name, "TypeError");
+    assert_eq!(exception.message, "x is not a function");
+
+    let exception = TailException::new(&WorkerError::Unavailable);
+    assert_eq!(exception.name, "Error");
+    assert_eq!(exception.message, "the worker is not running");
+  }
+}
@@ -0,0 +1,256 @@
+use crate::workers::DurableObjectTarget;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// Sockets accepted by durable objects, by id.
+static SOCKETS: Lazy<Mutex<HashMap<u32, Socket>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Marks a response that hands the client end of an accepted socket to the
+/// client, by the socket's id. The listener upgrades the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcceptedSocket(pub u32);
+
+/// The sockets a worker took on while handling a message, accepted by its
+/// objects or handed over by another worker, kept in its `OpState`. Those the
+/// message's response does not pass on are dropped, as no client could ever
+/// connect to them.
+#[derive(Debug, Default)]
+pub struct HeldSockets(pub Vec<u32>);
+
+/// A data frame to or from a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocketMessage {
+  Text(String),
+  Binary(Vec<u8>),
+}
+
+/// What the object wants done with its socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocketCommand {
+  Send(SocketMessage),
+  Close { code: u16, reason: String },
+}
+
+/// What happened on a socket, for the object's handlers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocketEvent {
+  Message(SocketMessage),
+  Close {
+    code: u16,
+    reason: String,
+    was_clean: bool,
+  },
+  Error(String),
+}
+
+/// The object a socket belongs to and the worker it lives in, by name or
+/// else address.
+#[derive(Debug, Clone)]
+pub struct SocketOwner {
+  pub worker: String,
+  pub object: DurableObjectTarget,
+}
+
+/// A hibernatable socket. The host holds the connection, so the object can
+/// be evicted from its isolate while the socket stays open.
+struct Socket {
+  owner: SocketOwner,
+  tags: Vec<String>,
+  /// Set with `ws.serializeAttachment()`, encoded with the V8 serializer.
+  attachment: Option<Vec<u8>>,
+  commands: UnboundedSender<SocketCommand>,
+  /// Until the connection is upgraded, commands queue up here.
+  pending: Option<UnboundedReceiver<SocketCommand>>,
+}
+
+fn sockets() -> std::sync::MutexGuard<'static, HashMap<u32, Socket>> {
+  SOCKETS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Registers a socket accepted by `owner`, returning its id.
+pub fn accept(owner: SocketOwner, tags: Vec<String>) -> u32 {
+  let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+  let (commands, pending) = unbounded_channel();
+  let socket = Socket {
+    owner,
+    tags,
+    attachment: None,
+    commands,
+    pending: Some(pending),
+  };
+  sockets().insert(id, socket);
+  id
+}
+
+/// Hands the connection of socket `id` its commands, along with the owner
+/// its events go to. `None` if the socket is gone or already connected.
+pub fn connect(id: u32) -> Option<(SocketOwner, UnboundedReceiver<SocketCommand>)> {
+  let mut sockets = sockets();
+  let socket = sockets.get_mut(&id)?;
+  let pending = socket.pending.take()?;
+  Some((socket.owner.clone(), pending))
+}
+
+/// Socket `id` if it belongs to `worker`. To any other worker it looks
+/// closed.
+fn owned<'a>(
+  sockets: &'a mut HashMap<u32, Socket>,
+  id: u32,
+  worker: &str,
+) -> Option<&'a mut Socket> {
+  sockets
+    .get_mut(&id)
+    .filter(|socket| socket.owner.worker == worker)
+}
+
+/// Queues `command` for the client. `false` if the socket is closed.
+pub fn send(id: u32, worker: &str, command: SocketCommand) -> bool {
+  owned(&mut sockets(), id, worker).is_some_and(|socket| socket.commands.send(command).is_ok())
+}
+
+pub fn is_open(id: u32, worker: &str) -> bool {
+  owned(&mut sockets(), id, worker).is_some()
+}
+
+pub fn remove(id: u32) {
+  sockets().remove(&id);
+}
+
+/// Closes every socket of `worker`'s objects, e.g. once it is unloaded.
+pub fn close_all(worker: &str, code: u16, reason: &str) {
+  sockets().retain(|_, socket| {
+    if socket.owner.worker != worker {
+      return true;
+    }
+    // Queued ahead of the end of the channel, so the client still gets it.
+    let _ = socket.commands.send(SocketCommand::Close {
+      code,
+      reason: reason.to_owned(),
+    });
+    false
+  });
+}
+
+/// The open sockets of an object, only those tagged `tag` if given.
+pub fn of_object(worker: &str, class: &str, object_id: &str, tag: Option<&str>) -> Vec<u32> {
+  let mut ids: Vec<u32> = sockets()
+    .iter()
+    .filter(|(_, socket)| {
+      let owner = &socket.owner;
+      owner.worker == worker && owner.object.class == class && owner.object.id == object_id
+    })
+    .filter(|(_, socket)| tag.is_none_or(|tag| socket.tags.iter().any(|t| t == tag)))
+    .map(|(id, _)| *id)
+    .collect();
+  ids.sort_unstable();
+  ids
+}
+
+pub fn tags(id: u32, worker: &str) -> Vec<String> {
+  owned(&mut sockets(), id, worker)
+    .map(|socket| socket.tags.clone())
+    .unwrap_or_default()
+}
+
+pub fn attachment(id: u32, worker: &str) -> Option<Vec<u8>> {
+  owned(&mut sockets(), id, worker)?.attachment.clone()
+}
+
+/// `false` if the socket is closed.
+pub fn set_attachment(id: u32, worker: &str, attachment: Option<Vec<u8>>) -> bool {
+  match owned(&mut sockets(), id, worker) {
+    Some(socket) => {
+      socket.attachment = attachment;
+      true
+    }
+    None => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_socket_lifecycle() {
+    let owner = |id: &str| SocketOwner {
+      worker: "chat".into(),
+      object: DurableObjectTarget {
+        class: "Room".into(),
+        id: id.into(),
+        name: None,
+      },
+    };
+    let a = accept(owner("lobby"), vec!["alice".into()]);
+    let b = accept(owner("lobby"), vec![]);
+    accept(owner("other"), vec!["alice".into()]);
+
+    assert_eq!(of_object("chat", "Room", "lobby", None), [a, b]);
+    assert_eq!(of_object("chat", "Room", "lobby", Some("alice")), [a]);
+
+    // Commands sent before the upgrade wait for the connection.
+    let hello = SocketCommand::Send(SocketMessage::Text("hello".into()));
+    assert!(send(a, "chat", hello.clone()));
+    let (_, mut commands) = connect(a).unwrap();
+    assert!(connect(a).is_none());
+    assert_eq!(commands.try_recv().unwrap(), hello);
+
+    assert!(set_attachment(a, "chat", Some(vec![1])));
+    assert_eq!(attachment(a, "chat"), Some(vec![1]));
+    remove(a);
+    assert!(!is_open(a, "chat"));
+    assert!(!send(a, "chat", hello));
+  }
+
+  #[test]
+  fn test_other_workers_cannot_use_a_socket() {
+    let owner = SocketOwner {
+      worker: "chat".into(),
+      object: DurableObjectTarget {
+        class: "Room".into(),
+        id: "lobby".into(),
+        name: None,
+      },
+    };
+    let id = accept(owner, vec!["alice".into()]);
+    let hello = SocketCommand::Send(SocketMessage::Text("hello".into()));
+
+    assert!(!is_open(id, "intruder"));
+    assert!(!send(id, "intruder", hello));
+    assert!(!set_attachment(id, "intruder", Some(vec![1])));
+    assert!(tags(id, "intruder").is_empty());
+    assert_eq!(attachment(id, "chat"), None);
+    assert_eq!(tags(id, "chat"), ["alice"]);
+    remove(id);
+  }
+
+  #[test]
+  fn test_close_all() {
+    let owner = |worker: &str| SocketOwner {
+      worker: worker.into(),
+      object: DurableObjectTarget {
+        class: "Room".into(),
+        id: "lobby".into(),
+        name: None,
+      },
+    };
+    let gone = accept(owner("unloaded"), vec![]);
+    let kept = accept(owner("loaded"), vec![]);
+    let (_, mut commands) = connect(gone).unwrap();
+
+    close_all("unloaded", 1001, "bye");
+    assert!(!is_open(gone, "unloaded"));
+    assert!(is_open(kept, "loaded"));
+    let close = SocketCommand::Close {
+      code: 1001,
+      reason: "bye".into(),
+    };
+    assert_eq!(commands.try_recv().unwrap(), close);
+    assert!(commands.try_recv().is_err());
+    remove(kept);
+  }
+}
@@ -1,13 +1,227 @@
+use crate::access_log::AccessLog;
+use crate::broadcast::BroadcastMessage;
+use crate::config::{LimitsConfig, WorkerConfig};
+use crate::error::WorkerError;
+use crate::queue::{QueueMessage, QueueOutcome};
+use crate::ratelimit::RateLimiter;
+use crate::tail::TailEvent;
+use crate::websocket::SocketEvent;
 use hyper::{Request, Response};
-use std::sync::mpsc::Sender;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Loaded workers by name, for service bindings to reach them.
+static SERVICES: Lazy<Mutex<HashMap<String, Arc<WorkerHandle>>>> =
+  Lazy::new(|| Mutex::new(HashMap::new()));
 
 pub struct WorkerRequest {
-  pub resp: tokio::sync::oneshot::Sender<anyhow::Result<Response<axum::body::Body>>>,
+  pub resp: tokio::sync::oneshot::Sender<Result<Response<axum::body::Body>, WorkerError>>,
   pub request_data: Option<Request<hyper::body::Bytes>>,
+  /// Workers waiting on the request, the one that sent it last. Empty when it
+  /// came from outside.
+  pub callers: Vec<String>,
+}
+
+/// The workers waiting on the message a worker is handling, kept in its
+/// `OpState`. Calls it makes to other workers carry them on, so a call back
+/// into one of them, which would wait on itself, fails instead.
+#[derive(Debug, Clone, Default)]
+pub struct CallChain(pub Vec<String>);
+
+impl CallChain {
+  /// The callers of a call `worker` makes to `target`, or `None` when
+  /// `target` is already waiting on it.
+  pub fn extend(self, worker: &str, target: &str) -> Option<Vec<String>> {
+    let mut callers = self.0;
+    callers.push(worker.to_owned());
+    (!callers.iter().any(|c| c == target)).then_some(callers)
+  }
+}
+
+/// A call to a method of one of the worker's `WorkerEntrypoint` classes.
+pub struct RpcRequest {
+  pub resp: tokio::sync::oneshot::Sender<Result<Vec<u8>, WorkerError>>,
+  /// Export holding the class, the default export when `None`.
+  pub entrypoint: Option<String>,
+  /// The structured-clone encoded pipeline of property reads and calls.
+  pub payload: Vec<u8>,
+  /// Workers waiting on the call, as for [`WorkerRequest`].
+  pub callers: Vec<String>,
+}
+
+/// A cron trigger firing, or a manual `worky trigger`.
+pub struct ScheduledRequest {
+  pub resp: tokio::sync::oneshot::Sender<Result<(), WorkerError>>,
+  /// The expression that fired, empty when triggered by hand without one.
+  pub cron: String,
+  pub scheduled_time: SystemTime,
+}
+
+/// Messages for the worker's `queue()` handler.
+pub struct QueueRequest {
+  /// What to do with each message, in the order they were handed over.
+  pub resp: tokio::sync::oneshot::Sender<Result<Vec<QueueOutcome>, WorkerError>>,
+  pub queue: String,
+  pub messages: Vec<QueueMessage>,
+}
+
+/// One of the worker's durable objects, created on first use.
+#[derive(Debug, Clone)]
+pub struct DurableObjectTarget {
+  /// Exported class of the object.
+  pub class: String,
+  pub id: String,
+  /// The name the id was derived from, if any.
+  pub name: Option<String>,
+}
+
+pub enum DurableObjectCall {
+  Fetch {
+    request: Request<hyper::body::Bytes>,
+    resp: tokio::sync::oneshot::Sender<Result<Response<axum::body::Body>, WorkerError>>,
+  },
+  /// An RPC pipeline, encoded as for [`RpcRequest`].
+  Rpc {
+    payload: Vec<u8>,
+    resp: tokio::sync::oneshot::Sender<Result<Vec<u8>, WorkerError>>,
+  },
+  /// Something happened on one of the object's hibernatable sockets.
+  WebSocket {
+    socket: u32,
+    event: SocketEvent,
+    resp: tokio::sync::oneshot::Sender<Result<(), WorkerError>>,
+  },
+  /// The object's alarm came due, sent by the daemon.
+  Alarm {
+    /// How often the handler failed for this alarm before.
+    retry_count: u32,
+    resp: tokio::sync::oneshot::Sender<Result<(), WorkerError>>,
+  },
+}
+
+/// An event for a durable object, sent by another worker or the host.
+pub struct DurableObjectRequest {
+  pub target: DurableObjectTarget,
+  pub call: DurableObjectCall,
+  /// Workers waiting on the call, as for [`WorkerRequest`].
+  pub callers: Vec<String>,
+}
+
+/// Events of other workers for the worker's `tail()` handler. Nothing waits
+/// for it to run.
+pub struct TailRequest {
+  pub events: Vec<TailEvent>,
+}
+
+/// A connection accepted by a `tcp` worker's listener, for its `connect()`
+/// handler.
+pub struct ConnectRequest {
+  /// Taken off the listener's runtime so the worker thread can adopt it.
+  pub stream: std::net::TcpStream,
+  /// Held until the connection closes, to keep `limits.max_connections`.
+  pub permit: OwnedSemaphorePermit,
+}
+
+/// Sent by a `tcp` worker to itself once its `connect()` handler settled and
+/// the connection's socket closed, to log the connection and finish its trace.
+pub struct ConnectionEnd {
+  /// The socket's resource id, as the connection was handed to the handler.
+  pub socket: u32,
+  /// What the handler threw, if it failed.
+  pub error: Option<WorkerError>,
+}
+
+/// Everything a worker thread is asked to do.
+pub enum WorkerMessage {
+  Fetch(WorkerRequest),
+  Rpc(RpcRequest),
+  Scheduled(ScheduledRequest),
+  Queue(QueueRequest),
+  DurableObject(DurableObjectRequest),
+  Tail(TailRequest),
+  Connect(ConnectRequest),
+  ConnectionEnd(ConnectionEnd),
+  Broadcast(BroadcastMessage),
 }
 
 pub struct WorkerHandle {
   pub addr: String,
   pub name: String,
-  pub sender: Sender<WorkerRequest>,
+  pub config: WorkerConfig,
+  pub sender: UnboundedSender<WorkerMessage>,
+  /// Shared by all of the worker's listeners, from `config.rate_limit`.
+  pub rate_limiter: Option<RateLimiter>,
+  /// From `config.access_log`, shared with the worker thread for the
+  /// connections of a `tcp` worker.
+  pub access_log: Option<Arc<AccessLog>>,
+  /// Open connections of a `tcp` worker, up to `limits.max_connections`.
+  pub connections: Arc<Semaphore>,
+}
+
+/// What `worky status` reports about a loaded worker.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkerStatus {
+  pub address: String,
+  pub name: String,
+  /// Every address the worker listens on, its load address first.
+  pub listen: Vec<String>,
+  pub limits: LimitsConfig,
+}
+
+impl WorkerHandle {
+  pub fn status(&self) -> WorkerStatus {
+    WorkerStatus {
+      address: self.addr.clone(),
+      name: self.name.clone(),
+      listen: std::iter::once(&self.addr)
+        .chain(&self.config.listen)
+        .cloned()
+        .collect(),
+      limits: self.config.limits.clone(),
+    }
+  }
+}
+
+/// Makes the worker reachable by its name. A worker loaded later under the
+/// same name takes its place.
+pub fn register_service(handle: Arc<WorkerHandle>) {
+  if !handle.name.is_empty() {
+    let mut services = SERVICES.lock().unwrap();
+    services.insert(handle.name.clone(), handle);
+  }
+}
+
+/// Forgets the worker loaded at `addr`, unless its name was taken over since.
+pub fn unregister_service(addr: &str) {
+  let mut services = SERVICES.lock().unwrap();
+  services.retain(|_, handle| handle.addr != addr);
+}
+
+pub fn service(name: &str) -> Option<Arc<WorkerHandle>> {
+  SERVICES.lock().unwrap().get(name).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_call_chain_rejects_waiting_workers() {
+    let outside = CallChain::default();
+    assert_eq!(outside.clone().extend("a", "b"), Some(vec!["a".to_owned()]));
+    assert_eq!(outside.extend("a", "a"), None);
+
+    // a called b, which now calls c; c calling a or b would deadlock.
+    let chain = CallChain(vec!["a".to_owned()]);
+    assert_eq!(
+      chain.clone().extend("b", "c"),
+      Some(vec!["a".to_owned(), "b".to_owned()])
+    );
+    assert_eq!(chain.extend("b", "a"), None);
+  }
 }
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use worky_common::durable::{self, Alarm, Alarms};
+use worky_common::error::WorkerError;
+use worky_common::workers::{
+  DurableObjectCall, DurableObjectRequest, DurableObjectTarget, WorkerHandle, WorkerMessage,
+};
+
+/// Longest the task sleeps before looking at the alarms again, which is also
+/// how soon an alarm of a worker that was not loaded gets another chance.
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+/// Failures after which an alarm is given up on.
+const MAX_RETRIES: u32 = 6;
+/// Wait before the first retry, doubled for every retry after it.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Starts firing durable object alarms. Each due alarm goes to the worker
+/// its object lives in, one at a time per object, and stays stored until the
+/// object's `alarm()` handler succeeds or has failed `MAX_RETRIES` times.
+pub fn spawn() -> JoinHandle<()> {
+  tokio::spawn(async {
+    let alarms = match Alarms::open() {
+      Ok(alarms) => Arc::new(alarms),
+      Err(e) => {
+        eprintln!("Error: could not open durable object alarms: {e}");
+        return;
+      }
+    };
+
+    let mut firing: HashMap<(String, String, String), JoinHandle<()>> = HashMap::new();
+    loop {
+      firing.retain(|_, task| !task.is_finished());
+      let now = SystemTime::now();
+      let next = match alarms.due(now) {
+        Ok((due, next)) => {
+          for alarm in due {
+            let object = (alarm.worker.clone(), alarm.class.clone(), alarm.id.clone());
+            if firing.contains_key(&object) {
+              continue;
+            }
+            // Alarms of unloaded workers wait for them to come back.
+            let Some(handle) = worky_store::find_worker(&alarm.worker) else {
+              continue;
+            };
+            firing.insert(object, tokio::spawn(fire(alarms.clone(), handle, alarm)));
+          }
+          next
+        }
+        Err(e) => {
+          eprintln!("Error: could not read durable object alarms: {e}");
+          None
+        }
+      };
+
+      let wait = next
+        .map_or(IDLE_WAIT, |next| {
+          next.duration_since(now).unwrap_or_default()
+        })
+        .min(IDLE_WAIT);
+      tokio::select! {
+        _ = durable::alarm_notifier().notified() => {}
+        _ = tokio::time::sleep(wait) => {}
+      }
+    }
+  })
+}
+
+/// How long an alarm waits after its handler failed `retries` times.
+fn retry_delay(retries: u32) -> Duration {
+  FIRST_RETRY_DELAY * 2u32.pow(retries.saturating_sub(1))
+}
+
+async fn fire(alarms: Arc<Alarms>, handle: Arc<WorkerHandle>, alarm: Alarm) {
+  let (tx, rx) = oneshot::channel();
+  let sent = handle
+    .sender
+    .send(WorkerMessage::DurableObject(DurableObjectRequest {
+      target: DurableObjectTarget {
+        class: alarm.class.clone(),
+        id: alarm.id.clone(),
+        name: alarm.name.clone(),
+      },
+      call: DurableObjectCall::Alarm {
+        retry_count: alarm.retries,
+        resp: tx,
+      },
+      callers: Vec::new(),
+    }));
+  let result = match sent {
+    Ok(()) => rx.await.unwrap_or(Err(WorkerError::Unavailable)),
+    Err(_) => Err(WorkerError::Unavailable),
+  };
+
+  let object = format!("{} {} of {}", alarm.class, alarm.id, alarm.worker);
+  let stored = match result {
+    Ok(()) => alarms.complete(&alarm),
+    Err(e) if alarm.retries < MAX_RETRIES => {
+      let delay = retry_delay(alarm.retries + 1);
+      eprintln!("Error: alarm of {object} failed, retrying in {delay:?}: {e}");
+      alarms.retry(&alarm, SystemTime::now() + delay)
+    }
+    Err(e) => {
+      eprintln!(
+        "Error: alarm of {object} failed {} times, giving up: {e}",
+        alarm.retries + 1
+      );
+      alarms.complete(&alarm)
+    }
+  };
+  if let Err(e) = stored {
+    eprintln!("Error: could not update the alarm of {object}: {e}");
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_retry_delay() {
+    let delays: Vec<u64> = (1..=4).map(|n| retry_delay(n).as_secs()).collect();
+    assert_eq!(delays, [2, 4, 8, 16]);
+  }
+}
@@ -0,0 +1,27 @@
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+use worky_common::{db, kv};
+
+/// How often expired KV keys are removed. They read as missing before that.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Starts removing expired KV keys from the database, including those that
+/// expired while the daemon was down.
+pub fn spawn() -> JoinHandle<()> {
+  tokio::spawn(async {
+    let Some(db) = db::db() else {
+      return;
+    };
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+      interval.tick().await;
+      let db = db.clone();
+      let swept = tokio::task::spawn_blocking(move || kv::sweep(&db, SystemTime::now())).await;
+      match swept {
+        Ok(Err(e)) => eprintln!("Error: could not remove expired KV keys: {e}"),
+        Err(e) => eprintln!("Error: KV sweeper failed: {e}"),
+        Ok(Ok(_)) => {}
+      }
+    }
+  })
+}
@@ -1,3 +1,8 @@
+pub mod alarms;
+pub mod kv;
+pub mod queues;
+pub mod scheduler;
+
 pub fn add(left: u64, right: u64) -> u64 {
   left + right
 }
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use worky_common::config::QueueConsumerConfig;
+use worky_common::error::WorkerError;
+use worky_common::queue::{self, Queue, QueueMessage, QueueOutcome};
+use worky_common::workers::{QueueRequest, WorkerHandle, WorkerMessage};
+
+/// Longest a consumer sleeps before checking its worker is still loaded.
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+/// Starts delivering queue messages. A task per consumed queue hands batches
+/// to the worker consuming it, from when the worker is loaded until it is
+/// unloaded; messages sent in between wait in the queue.
+pub fn spawn() -> JoinHandle<()> {
+  tokio::spawn(async {
+    let mut consumers: HashMap<String, JoinHandle<()>> = HashMap::new();
+    loop {
+      consumers.retain(|_, task| !task.is_finished());
+      for queue in consumed_queues() {
+        consumers
+          .entry(queue.clone())
+          .or_insert_with(|| tokio::spawn(consume(queue)));
+      }
+      tokio::time::sleep(IDLE_WAIT).await;
+    }
+  })
+}
+
+fn consumed_queues() -> Vec<String> {
+  let workers = worky_store::WORKERS.lock().unwrap();
+  workers
+    .values()
+    .flat_map(|handle| &handle.config.queues.consumers)
+    .map(|consumer| consumer.queue.clone())
+    .collect()
+}
+
+/// The loaded worker consuming `queue`, along with its consumer settings.
+fn consumer(queue: &str) -> Option<(Arc<WorkerHandle>, QueueConsumerConfig)> {
+  let workers = worky_store::WORKERS.lock().unwrap();
+  workers.values().find_map(|handle| {
+    let consumers = &handle.config.queues.consumers;
+    let consumer = consumers.iter().find(|c| c.queue == queue)?;
+    Some((handle.clone(), consumer.clone()))
+  })
+}
+
+async fn consume(name: String) {
+  let queue = match Queue::open(&name) {
+    Ok(queue) => queue,
+    Err(e) => {
+      eprintln!("Error: could not open queue {name}: {e}");
+      return;
+    }
+  };
+  let notify = queue::notifier(&name);
+
+  while let Some((handle, config)) = consumer(&name) {
+    let now = SystemTime::now();
+    let batch_size = config.max_batch_size.max(1);
+    let (ready, next) = match queue.ready(now, batch_size) {
+      Ok(ready) => ready,
+      Err(e) => {
+        eprintln!("Error: could not read queue {name}: {e}");
+        tokio::time::sleep(IDLE_WAIT).await;
+        continue;
+      }
+    };
+
+    // A batch goes out once it is full or its oldest message has waited for
+    // the batch timeout.
+    let timeout = Duration::from_secs(config.max_batch_timeout);
+    let deadline = ready.first().map(|message| message.available_at + timeout);
+    if ready.len() >= batch_size || deadline.is_some_and(|deadline| deadline <= now) {
+      deliver(&queue, &handle, &config, ready).await;
+      continue;
+    }
+
+    let wait = [deadline, next]
+      .into_iter()
+      .flatten()
+      .min()
+      .map_or(IDLE_WAIT, |wake| {
+        wake.duration_since(now).unwrap_or_default()
+      })
+      .min(IDLE_WAIT);
+    tokio::select! {
+      _ = notify.notified() => {}
+      _ = tokio::time::sleep(wait) => {}
+    }
+  }
+}
+
+/// Hands a batch to the consumer and acknowledges or retries each message as
+/// it decided. A batch that could not be handled at all is retried whole.
+async fn deliver(
+  queue: &Queue,
+  handle: &WorkerHandle,
+  config: &QueueConsumerConfig,
+  mut messages: Vec<QueueMessage>,
+) {
+  for message in &mut messages {
+    message.attempts += 1;
+  }
+
+  let (tx, rx) = oneshot::channel();
+  let sent = handle.sender.send(WorkerMessage::Queue(QueueRequest {
+    resp: tx,
+    queue: queue.name().to_string(),
+    messages: messages.clone(),
+  }));
+  let outcomes = match sent {
+    Ok(()) => rx.await.unwrap_or(Err(WorkerError::Unavailable)),
+    Err(_) => Err(WorkerError::Unavailable),
+  };
+
+  let retry = QueueOutcome {
+    retry: true,
+    delay_seconds: None,
+  };
+  let outcomes = outcomes.unwrap_or_else(|e| {
+    eprintln!(
+      "Error: could not deliver a batch of queue {} to {}: {e}",
+      queue.name(),
+      handle.addr
+    );
+    vec![retry; messages.len()]
+  });
+
+  for (i, message) in messages.iter().enumerate() {
+    let outcome = outcomes.get(i).copied().unwrap_or(retry);
+    if let Err(e) = settle(queue, config, message, outcome) {
+      eprintln!(
+        "Error: could not update message {:x} of queue {}: {e}",
+        message.id,
+        queue.name()
+      );
+    }
+  }
+}
+
+/// Removes an acknowledged message, or puts it back for another attempt. A
+/// message out of retries moves to the dead-letter queue, if there is one.
+fn settle(
+  queue: &Queue,
+  config: &QueueConsumerConfig,
+  message: &QueueMessage,
+  outcome: QueueOutcome,
+) -> sled::Result<()> {
+  if !outcome.retry {
+    return queue.remove(message.id);
+  }
+  if message.attempts <= config.max_retries {
+    let delay = outcome.delay_seconds.unwrap_or(config.retry_delay);
+    return queue.retry(message, Duration::from_secs(delay));
+  }
+
+  match &config.dead_letter_queue {
+    Some(dead_letter_queue) => {
+      Queue::open(dead_letter_queue)?.send([(message.body.clone(), Duration::ZERO)])?;
+    }
+    None => eprintln!(
+      "Dropping message {:x} of queue {} after {} attempts",
+      message.id,
+      queue.name(),
+      message.attempts
+    ),
+  }
+  queue.remove(message.id)
+}
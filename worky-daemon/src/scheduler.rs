@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use worky_common::config::MissedRunPolicy;
+use worky_common::cron::Schedule;
+use worky_common::db;
+use worky_common::error::WorkerError;
+use worky_common::workers::{ScheduledRequest, WorkerHandle, WorkerMessage};
+
+/// Most runs made up for at once under [`MissedRunPolicy::All`]; older ones
+/// are dropped.
+const MAX_MISSED_RUNS: usize = 100;
+
+/// Starts the scheduler, which wakes up at the start of every minute and
+/// runs the cron triggers of loaded workers that came due. When each trigger
+/// last ran is kept in the database, so runs missed while the daemon was down
+/// are handled by the worker's [`MissedRunPolicy`] once it is loaded again.
+pub fn spawn() -> JoinHandle<()> {
+  tokio::spawn(async {
+    let last_runs = match db::internal_tree("scheduler") {
+      Ok(tree) => tree,
+      Err(e) => {
+        eprintln!("Error: cron triggers are disabled: {e}");
+        return;
+      }
+    };
+
+    loop {
+      let now = SystemTime::now();
+      let into_minute = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % 60;
+      tokio::time::sleep(Duration::from_secs(60 - into_minute)).await;
+      tick(&last_runs, SystemTime::now());
+    }
+  })
+}
+
+fn tick(last_runs: &sled::Tree, now: SystemTime) {
+  let workers: Vec<Arc<WorkerHandle>> = worky_store::WORKERS
+    .lock()
+    .unwrap()
+    .values()
+    .cloned()
+    .collect();
+
+  for handle in workers {
+    let triggers = &handle.config.triggers;
+    for cron in &triggers.crons {
+      // Validated when the worker was loaded.
+      let Ok(schedule) = Schedule::parse(cron) else {
+        continue;
+      };
+
+      // Keyed by name so the record outlives the worker's address.
+      let worker = match handle.name.as_str() {
+        "" => handle.addr.as_str(),
+        name => name,
+      };
+      let key = format!("{worker}\n{cron}");
+      // A trigger seen for the first time only runs if it is due right now.
+      let last_run = last_runs
+        .get(&key)
+        .ok()
+        .flatten()
+        .and_then(|v| <[u8; 8]>::try_from(v.as_ref()).ok())
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(secs)))
+        .unwrap_or_else(|| now - Duration::from_secs(60));
+
+      for scheduled_time in due_runs(&schedule, last_run, now, triggers.missed) {
+        let handle = handle.clone();
+        let cron = cron.clone();
+        tokio::spawn(async move {
+          if let Err(e) = trigger(&handle, cron.clone(), scheduled_time).await {
+            eprintln!(
+              "Error: cron trigger {cron:?} of {} failed: {e}",
+              handle.addr
+            );
+          }
+        });
+      }
+
+      let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+      if let Err(e) = last_runs.insert(key, secs.to_be_bytes().to_vec()) {
+        eprintln!("Error: could not record the run of cron trigger {cron:?}: {e}");
+      }
+    }
+  }
+}
+
+/// The times in `(last_run, now]` that `schedule` fired at and that should
+/// run under `policy`. Only a time within the last minute is on schedule; the
+/// ones before it were missed.
+fn due_runs(
+  schedule: &Schedule,
+  last_run: SystemTime,
+  now: SystemTime,
+  policy: MissedRunPolicy,
+) -> Vec<SystemTime> {
+  let mut due = VecDeque::new();
+  let mut time = last_run;
+  while let Some(next) = schedule.next_after(time).filter(|next| *next <= now) {
+    if due.len() == MAX_MISSED_RUNS {
+      due.pop_front();
+    }
+    due.push_back(next);
+    time = next;
+  }
+
+  let latest = due.back().copied();
+  let on_schedule = latest.filter(|time| {
+    now
+      .duration_since(*time)
+      .is_ok_and(|late| late < Duration::from_secs(60))
+  });
+  match policy {
+    MissedRunPolicy::Skip => on_schedule.into_iter().collect(),
+    MissedRunPolicy::Once => latest.into_iter().collect(),
+    MissedRunPolicy::All => due.into(),
+  }
+}
+
+/// Runs the worker's `scheduled()` handler and waits for it to finish.
+pub async fn trigger(
+  handle: &WorkerHandle,
+  cron: String,
+  scheduled_time: SystemTime,
+) -> Result<(), WorkerError> {
+  let (tx, rx) = oneshot::channel();
+  handle
+    .sender
+    .send(WorkerMessage::Scheduled(ScheduledRequest {
+      resp: tx,
+      cron,
+      scheduled_time,
+    }))
+    .map_err(|_| WorkerError::Unavailable)?;
+  rx.await.map_err(|_| WorkerError::Unavailable)?
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_due_runs() {
+    let hourly = Schedule::parse("@hourly").unwrap();
+    let hour = |n: u64| UNIX_EPOCH + Duration::from_secs(n * 3600);
+    // Down from just after 01:00 until 04:00:30.
+    let last_run = hour(1) + Duration::from_secs(30);
+    let now = hour(4) + Duration::from_secs(30);
+
+    let runs = |policy| due_runs(&hourly, last_run, now, policy);
+    assert_eq!(runs(MissedRunPolicy::All), vec![hour(2), hour(3), hour(4)]);
+    assert_eq!(runs(MissedRunPolicy::Once), vec![hour(4)]);
+    assert_eq!(runs(MissedRunPolicy::Skip), vec![hour(4)]);
+
+    // Restarted at 04:30, after the 04:00 run was missed too.
+    let now = hour(4) + Duration::from_secs(1800);
+    let runs = |policy| due_runs(&hourly, last_run, now, policy);
+    assert_eq!(runs(MissedRunPolicy::Once), vec![hour(4)]);
+    assert!(runs(MissedRunPolicy::Skip).is_empty());
+  }
+}
@@ -0,0 +1,48 @@
+import { ReadableStream } from "ext:deno_web/06_streams.js";
+
+const core = globalThis.Deno.core;
+
+const kReadSize = 64 * 1024;
+
+// Reads an asset file as the response body is consumed.
+function fileStream(rid) {
+    return new ReadableStream({
+        async pull(controller) {
+            const buf = new Uint8Array(kReadSize);
+            let n;
+            try {
+                n = await core.ops.op_assets_read(rid, buf);
+            } catch (err) {
+                core.tryClose(rid);
+                throw err;
+            }
+            if (n === 0) {
+                core.tryClose(rid);
+                controller.close();
+                return;
+            }
+            controller.enqueue(buf.subarray(0, n));
+        },
+        cancel() {
+            core.tryClose(rid);
+        },
+    });
+}
+
+// Serves the worker's static assets, so the worker can decide per request
+// whether to answer with an asset or with something of its own.
+class AssetsBinding {
+    async fetch(input, init) {
+        const request = new Request(input, init);
+        const url = new URL(request.url);
+        const res = await core.ops.op_assets_fetch({
+            method: request.method,
+            path: url.pathname,
+            headers: [...request.headers],
+        });
+        const body = res.rid === null ? res.body : fileStream(res.rid);
+        return new Response(body, { status: res.status, headers: res.headers });
+    }
+}
+
+export { AssetsBinding };
@@ -0,0 +1,170 @@
+use super::ExtensionTrait;
+use deno_core::error::CoreError;
+use deno_core::{
+  extension, op2, AsyncRefCell, CancelHandle, CancelTryFuture, Extension, JsBuffer, OpState, RcRef,
+  Resource, ResourceId, ToJsBuffer,
+};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::rc::Rc;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, Take};
+use worky_common::assets::{self, AssetBody};
+use worky_common::config::WorkerConfig;
+use worky_common::deno_error;
+
+/// What `env.ASSETS.fetch()` passes down from its `Request`.
+#[derive(Deserialize)]
+struct AssetRequest {
+  method: String,
+  path: String,
+  headers: Vec<(String, String)>,
+}
+
+/// Enough of a response for the JS side to build a `Response` from. Files
+/// are read through `rid` as the body is consumed.
+#[derive(Serialize)]
+struct AssetResponse {
+  status: u16,
+  headers: Vec<(String, String)>,
+  body: Option<ToJsBuffer>,
+  rid: Option<ResourceId>,
+}
+
+impl AssetResponse {
+  fn text(status: StatusCode, headers: Vec<(String, String)>) -> Self {
+    let reason = status.canonical_reason().unwrap_or_default();
+    let mut headers = headers;
+    headers.push((
+      "content-type".to_string(),
+      "text/plain;charset=UTF-8".to_string(),
+    ));
+    Self {
+      status: status.as_u16(),
+      headers,
+      body: Some(reason.as_bytes().to_vec().into()),
+      rid: None,
+    }
+  }
+}
+
+/// The part of an asset file a response still has to send.
+struct AssetFileResource {
+  file: AsyncRefCell<Take<File>>,
+  cancel: CancelHandle,
+}
+
+impl Resource for AssetFileResource {
+  fn name(&self) -> Cow<str> {
+    "workyAssetFile".into()
+  }
+
+  fn close(self: Rc<Self>) {
+    self.cancel.cancel();
+  }
+}
+
+extension!(
+  worky_assets,
+  deps = [worky_js],
+  ops = [op_assets_fetch, op_assets_read],
+  esm_entry_point = "ext:worky_assets/01_assets.js",
+  esm = [ dir "src/ext/assets", "01_assets.js" ],
+);
+impl ExtensionTrait<()> for worky_assets {
+  fn init((): ()) -> Extension {
+    worky_assets::init()
+  }
+}
+
+pub fn extensions(is_snapshot: bool) -> Vec<Extension> {
+  vec![worky_assets::build((), is_snapshot)]
+}
+
+fn assets_error(err: impl std::fmt::Display) -> CoreError {
+  deno_error!(err.to_string())
+}
+
+#[op2(async)]
+#[serde]
+async fn op_assets_fetch(
+  #[serde] req: AssetRequest,
+  state: Rc<RefCell<OpState>>,
+) -> Result<AssetResponse, CoreError> {
+  let config = state
+    .borrow()
+    .borrow::<WorkerConfig>()
+    .assets
+    .clone()
+    .ok_or_else(|| assets_error("no assets directory is configured"))?;
+
+  let method = Method::from_bytes(req.method.as_bytes()).map_err(assets_error)?;
+  if method != Method::GET && method != Method::HEAD {
+    let allow = vec![("allow".to_string(), "GET, HEAD".to_string())];
+    return Ok(AssetResponse::text(StatusCode::METHOD_NOT_ALLOWED, allow));
+  }
+
+  let mut headers = HeaderMap::new();
+  for (name, value) in &req.headers {
+    if let (Ok(name), Ok(value)) = (
+      HeaderName::from_bytes(name.as_bytes()),
+      HeaderValue::from_str(value),
+    ) {
+      headers.append(name, value);
+    }
+  }
+
+  let response = assets::serve(&config, &method, &req.path, &headers, true)
+    .await
+    .map_err(assets_error)?;
+  let Some(response) = response else {
+    return Ok(AssetResponse::text(StatusCode::NOT_FOUND, Vec::new()));
+  };
+
+  let (parts, body) = response.into_parts();
+  let rid = match body {
+    AssetBody::Empty => None,
+    AssetBody::File(file) => Some(state.borrow_mut().resource_table.add(AssetFileResource {
+      file: AsyncRefCell::new(file),
+      cancel: CancelHandle::new(),
+    })),
+  };
+  Ok(AssetResponse {
+    status: parts.status.as_u16(),
+    headers: parts
+      .headers
+      .iter()
+      .map(|(name, value)| {
+        let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+        (name.to_string(), value)
+      })
+      .collect(),
+    body: None,
+    rid,
+  })
+}
+
+/// Reads the next part of an asset file into `buf`, 0 at its end.
+#[op2(async)]
+async fn op_assets_read(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  #[buffer] mut buf: JsBuffer,
+) -> Result<u32, CoreError> {
+  let resource = state
+    .borrow()
+    .resource_table
+    .get::<AssetFileResource>(rid)
+    .map_err(assets_error)?;
+  let cancel = RcRef::map(&resource, |r| &r.cancel);
+  let mut file = RcRef::map(&resource, |r| &r.file).borrow_mut().await;
+  let n = file
+    .read(&mut *buf)
+    .try_or_cancel(cancel)
+    .await
+    .map_err(assets_error)?;
+  Ok(n as u32)
+}
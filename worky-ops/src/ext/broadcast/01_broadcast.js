@@ -0,0 +1,96 @@
+import { applyToGlobal, nonEnumerable, registerHostHook } from "ext:worky_js/utils.js";
+import { DOMException } from "ext:deno_web/01_dom_exception.js";
+import { defineEventHandler, EventTarget, MessageEvent } from "ext:deno_web/02_event.js";
+
+const core = globalThis.Deno.core;
+
+// Open channels of this isolate, by name.
+const channels = new Map();
+
+// Hands `data` to every open channel named `name` but `source`. Each gets its
+// own copy, a task later, as with `postMessage()` anywhere else.
+function dispatch(source, name, data) {
+    for (const channel of channels.get(name) ?? []) {
+        if (channel === source) {
+            continue;
+        }
+        setTimeout(() => {
+            // Closed in the meantime.
+            if (!channels.get(name)?.has(channel)) {
+                return;
+            }
+            let event;
+            try {
+                event = new MessageEvent("message", { data: core.deserialize(data) });
+            } catch {
+                event = new MessageEvent("messageerror");
+            }
+            channel.dispatchEvent(event);
+        }, 0);
+    }
+}
+
+// Reaches the channels of the same name in every isolate of the worker, and
+// of the workers in its broadcast group.
+class BroadcastChannel extends EventTarget {
+    #name;
+    #closed = false;
+
+    constructor(name) {
+        super();
+        if (arguments.length === 0) {
+            throw new TypeError("BroadcastChannel needs a name");
+        }
+        this.#name = String(name);
+
+        let open = channels.get(this.#name);
+        if (open === undefined) {
+            open = new Set();
+            channels.set(this.#name, open);
+            core.ops.op_broadcast_subscribe(this.#name);
+        }
+        open.add(this);
+    }
+
+    get name() {
+        return this.#name;
+    }
+
+    postMessage(message) {
+        if (this.#closed) {
+            throw new DOMException("The channel is closed", "InvalidStateError");
+        }
+        const data = core.serialize(message);
+        dispatch(this, this.#name, data);
+        core.ops.op_broadcast_post(this.#name, data);
+    }
+
+    close() {
+        if (this.#closed) {
+            return;
+        }
+        this.#closed = true;
+        const open = channels.get(this.#name);
+        open.delete(this);
+        if (open.size === 0) {
+            channels.delete(this.#name);
+            core.ops.op_broadcast_unsubscribe(this.#name);
+        }
+    }
+}
+
+defineEventHandler(BroadcastChannel.prototype, "message");
+defineEventHandler(BroadcastChannel.prototype, "messageerror");
+
+// Delivers a message posted on `name` in another isolate.
+function deliverBroadcast(name, data) {
+    dispatch(null, name, data);
+}
+
+registerHostHook("deliverBroadcast", deliverBroadcast);
+
+applyToGlobal({
+    BroadcastChannel: nonEnumerable(BroadcastChannel),
+});
+
+export { BroadcastChannel };
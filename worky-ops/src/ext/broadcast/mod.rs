@@ -0,0 +1,77 @@
+use super::durable::worker_key;
+use super::ExtensionTrait;
+use deno_core::{extension, op2, Extension, OpState};
+use tokio::sync::mpsc::WeakUnboundedSender;
+use worky_common::broadcast;
+use worky_common::config::WorkerConfig;
+use worky_common::workers::WorkerMessage;
+
+extension!(
+  worky_broadcast,
+  deps = [worky_js],
+  ops = [
+    op_broadcast_subscribe,
+    op_broadcast_unsubscribe,
+    op_broadcast_post,
+  ],
+  esm_entry_point = "ext:worky_broadcast/01_broadcast.js",
+  esm = [ dir "src/ext/broadcast", "01_broadcast.js" ],
+);
+impl ExtensionTrait<()> for worky_broadcast {
+  fn init((): ()) -> Extension {
+    worky_broadcast::init()
+  }
+}
+
+pub fn extensions(is_snapshot: bool) -> Vec<Extension> {
+  vec![worky_broadcast::build((), is_snapshot)]
+}
+
+/// Connects the isolate's channels to those of other isolates. The worker
+/// thread puts it in the `OpState` with the sender of its own messages;
+/// without it, channels reach only the isolate they were opened in.
+pub struct BroadcastHost {
+  isolate: u64,
+  sender: WeakUnboundedSender<WorkerMessage>,
+}
+
+impl BroadcastHost {
+  pub fn new(sender: WeakUnboundedSender<WorkerMessage>) -> Self {
+    Self {
+      isolate: broadcast::isolate_id(),
+      sender,
+    }
+  }
+}
+
+fn scope(state: &OpState) -> String {
+  let group = state.borrow::<WorkerConfig>().broadcast_group.as_deref();
+  broadcast::scope(group, &worker_key(state))
+}
+
+/// Called when the isolate opens its first channel named `channel`.
+#[op2(fast)]
+fn op_broadcast_subscribe(state: &mut OpState, #[string] channel: &str) {
+  let scope = scope(state);
+  if let Some(host) = state.try_borrow::<BroadcastHost>() {
+    broadcast::subscribe(&scope, channel, host.isolate, host.sender.clone());
+  }
+}
+
+/// Called when the isolate closed its last channel named `channel`.
+#[op2(fast)]
+fn op_broadcast_unsubscribe(state: &mut OpState, #[string] channel: &str) {
+  let scope = scope(state);
+  if let Some(host) = state.try_borrow::<BroadcastHost>() {
+    broadcast::unsubscribe(&scope, channel, host.isolate);
+  }
+}
+
+/// Sends a serialized message to the other isolates listening on `channel`.
+#[op2(fast)]
+fn op_broadcast_post(state: &mut OpState, #[string] channel: &str, #[buffer] data: &[u8]) {
+  let scope = scope(state);
+  if let Some(host) = state.try_borrow::<BroadcastHost>() {
+    broadcast::post(&scope, channel, host.isolate, data);
+  }
+}
@@ -1,25 +1,33 @@
+use std::collections::{HashMap, VecDeque};
 use std::sync::Mutex;
 
 use super::{worky::WorkerState, ExtensionTrait};
 use deno_core::{extension, op2, Extension, OpState};
 use once_cell::sync::Lazy;
+use worky_common::tail::TailLog;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LogType {
   Error,
   Info,
+  /// A line of a worker's access log.
+  Access,
 }
 
+/// How many entries the log store keeps; older ones make room for new ones,
+/// so a busy worker's access log does not grow the daemon without bound.
+pub const MAX_LOGS: usize = 10_000;
+
 pub static LOGS: Lazy<
   Mutex<
-    Vec<(
+    VecDeque<(
       String, /* addr */
       String, /* name */
       String, /* logs */
       LogType,
     )>,
   >,
-> = Lazy::new(|| Mutex::new(Vec::new()));
+> = Lazy::new(|| Mutex::new(VecDeque::new()));
 
 extension!(
     init_console,
@@ -48,32 +56,80 @@ pub fn extensions(is_snapshot: bool) -> Vec<Extension> {
     init_console::build((), is_snapshot),
   ]
 }
-#[op2(fast)]
-fn op_log_stdout(state: &mut OpState, #[string] out: String) {
-  let worker = state.borrow::<WorkerState>();
+/// Console output of the events the worker is handling, collected for their
+/// tail consumers. Connections of a `tcp` worker are traced while other events
+/// come and go, so each event has a buffer of its own, and a line goes to
+/// every event traced when it is written.
+#[derive(Default)]
+pub struct TailCapture {
+  next: u64,
+  buffers: HashMap<u64, Vec<TailLog>>,
+}
+
+impl TailCapture {
+  /// Starts collecting output for an event, returning the id to end it with.
+  pub fn begin(&mut self) -> u64 {
+    let id = self.next;
+    self.next += 1;
+    self.buffers.insert(id, Vec::new());
+    id
+  }
+
+  /// Stops collecting output for the event `id` and returns what it got.
+  pub fn end(&mut self, id: u64) -> Vec<TailLog> {
+    self.buffers.remove(&id).unwrap_or_default()
+  }
+}
 
+fn capture(state: &mut OpState, level: u32, out: &str) {
+  if let Some(capture) = state.try_borrow_mut::<TailCapture>() {
+    if capture.buffers.is_empty() {
+      return;
+    }
+    let log = TailLog::new(level, out);
+    for logs in capture.buffers.values_mut() {
+      logs.push(log.clone());
+    }
+  }
+}
+
+/// Appends an entry to the log store on behalf of a worker.
+pub fn push_log(addr: &str, name: &str, message: &str, level: LogType) {
   if let Ok(mut logs) = LOGS.lock() {
-    logs.push((
-      worker.worker_address.clone(),
-      worker.worker_name.clone(),
-      out.trim_end().to_owned(),
-      LogType::Info,
+    if logs.len() == MAX_LOGS {
+      logs.pop_front();
+    }
+    logs.push_back((
+      addr.to_owned(),
+      name.to_owned(),
+      message.trim_end().to_owned(),
+      level,
     ));
   }
 }
 
 #[op2(fast)]
-fn op_log_stderr(state: &mut OpState, #[string] out: String) {
+fn op_log_stdout(state: &mut OpState, #[string] out: String, level: u32) {
+  capture(state, level, &out);
   let worker = state.borrow::<WorkerState>();
+  push_log(
+    &worker.worker_address,
+    &worker.worker_name,
+    &out,
+    LogType::Info,
+  );
+}
 
-  if let Ok(mut logs) = LOGS.lock() {
-    logs.push((
-      worker.worker_address.clone(),
-      worker.worker_name.clone(),
-      out.trim_end().to_owned(),
-      LogType::Error,
-    ));
-  }
+#[op2(fast)]
+fn op_log_stderr(state: &mut OpState, #[string] out: String, level: u32) {
+  capture(state, level, &out);
+  let worker = state.borrow::<WorkerState>();
+  push_log(
+    &worker.worker_address,
+    &worker.worker_name,
+    &out,
+    LogType::Error,
+  );
 }
 
 pub fn get_logs(query: String) -> Vec<(String, String, String, LogType)> {
@@ -0,0 +1,56 @@
+import { applyToGlobal, nonEnumerable, registerHostHook } from "ext:worky_js/utils.js";
+
+// Every `waitUntil` task that has not settled yet, across all requests.
+const pending = new Set();
+const kPassThrough = Symbol("passThroughOnException");
+
+class ExecutionContext {
+  constructor() {
+    this[kPassThrough] = false;
+  }
+
+  waitUntil(promise) {
+    const task = Promise.resolve(promise)
+      .catch((err) => console.error("Uncaught (in waitUntil)", err))
+      .finally(() => pending.delete(task));
+    pending.add(task);
+  }
+
+  passThroughOnException() {
+    this[kPassThrough] = true;
+  }
+}
+
+// The event handed to `scheduled(controller, env, ctx)`.
+class ScheduledController {
+  constructor(cron, scheduledTime) {
+    this.cron = cron;
+    this.scheduledTime = scheduledTime;
+    this.type = "scheduled";
+  }
+
+  // Failed runs are never retried, so there is nothing to opt out of.
+  noRetry() {}
+}
+
+async function settleWaitUntil() {
+  // Tasks may schedule more tasks, so keep going until nothing is left.
+  while (pending.size > 0) {
+    await Promise.allSettled([...pending]);
+  }
+}
+
+registerHostHook("createContext", () => new ExecutionContext());
+registerHostHook("shouldPassThrough", (ctx) => ctx[kPassThrough] === true);
+registerHostHook("settleWaitUntil", settleWaitUntil);
+registerHostHook(
+  "createScheduledController",
+  (cron, scheduledTime) => new ScheduledController(cron, scheduledTime),
+);
+
+applyToGlobal({
+  ExecutionContext: nonEnumerable(ExecutionContext),
+  ScheduledController: nonEnumerable(ScheduledController),
+});
+
+export { ExecutionContext, ScheduledController };
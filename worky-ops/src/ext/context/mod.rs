@@ -0,0 +1,18 @@
+use super::ExtensionTrait;
+use deno_core::{extension, Extension};
+
+extension!(
+  worky_context,
+  deps = [worky_js],
+  esm_entry_point = "ext:worky_context/01_context.js",
+  esm = [ dir "src/ext/context", "01_context.js" ],
+);
+impl ExtensionTrait<()> for worky_context {
+  fn init((): ()) -> Extension {
+    worky_context::init()
+  }
+}
+
+pub fn extensions(is_snapshot: bool) -> Vec<Extension> {
+  vec![worky_context::build((), is_snapshot)]
+}
@@ -0,0 +1,529 @@
+import { applyToGlobal, nonEnumerable, registerHostHook } from "ext:worky_js/utils.js";
+import { ExecutionContext } from "ext:worky_context/01_context.js";
+import {
+    callRpc,
+    decodeResponse,
+    encodeOutcome,
+    encodeRequest,
+    runSteps,
+    withRpc,
+} from "ext:worky_service/01_service.js";
+import { acceptServer, forgetSocket, serverSocket, socketId } from "ext:worky_websocket/01_websocket.js";
+
+const core = globalThis.Deno.core;
+
+const kIdPattern = /^[0-9a-f]{64}$/;
+const kSync = Symbol("sync");
+const kCommit = Symbol("commit");
+
+// How long an object may sit idle before it is evicted. Its storage and
+// hibernatable sockets stay, and the next event builds it anew.
+const kEvictAfterMs = 10_000;
+
+// The worker's module exports and env, which objects are built from.
+let workerModule = null;
+
+// Live objects of this worker by `${className}:${id}`, one instance each.
+const objects = new Map();
+
+// Base class of durable objects that answer RPC calls.
+class DurableObject {
+    constructor(ctx, env) {
+        this.ctx = ctx;
+        this.env = env;
+    }
+}
+
+// Keeps new events away from an object while it waits on its storage, so
+// nothing interleaves with a read-modify-write.
+class InputGate {
+    #closed = 0;
+    #waiters = [];
+
+    async closeWhile(fn) {
+        this.#closed++;
+        try {
+            return await fn();
+        } finally {
+            this.#closed--;
+            if (this.#closed === 0 && this.#waiters.length > 0) {
+                const waiters = this.#waiters.splice(0);
+                // Let whatever closed the gate see its result first.
+                setTimeout(() => waiters.forEach((wake) => wake()), 0);
+            }
+        }
+    }
+
+    get closed() {
+        return this.#closed > 0;
+    }
+
+    // Resolves once the gate opens again, when it may well be closed by
+    // someone else already.
+    wait() {
+        return new Promise((wake) => this.#waiters.push(wake));
+    }
+}
+
+class DurableObjectId {
+    #id;
+
+    constructor(id, name) {
+        this.#id = id;
+        this.name = name;
+    }
+
+    toString() {
+        return this.#id;
+    }
+
+    equals(other) {
+        return other instanceof DurableObjectId && other.toString() === this.#id;
+    }
+}
+
+function keyList(keys) {
+    return Array.isArray(keys) ? keys.map(String) : [String(keys)];
+}
+
+function entryList(keyOrEntries, value) {
+    return typeof keyOrEntries === "object" && keyOrEntries !== null
+        ? Object.entries(keyOrEntries)
+        : [[String(keyOrEntries), value]];
+}
+
+function decodeEntries(entries) {
+    return new Map(entries.map(([key, value]) => [key, core.deserialize(value)]));
+}
+
+// Whether `key` falls in the range of a `list()` call.
+function inRange(key, { start, startAfter, end, prefix }) {
+    const afterStart = startAfter !== undefined
+        ? key > startAfter
+        : start === undefined || key >= start;
+    return afterStart &&
+        (end === undefined || key < end) &&
+        (prefix === undefined || key.startsWith(prefix));
+}
+
+// An object's own key-value storage, as `ctx.storage`. Values are
+// structured-cloned.
+class DurableObjectStorage {
+    #className;
+    #id;
+    // The name the id was derived from, stored with the alarm so an object
+    // it brings back keeps it.
+    #name;
+    #gate;
+    // Writes that have not finished yet, and whether any happened since the
+    // last sync.
+    #writes = new Set();
+    #dirty = false;
+
+    constructor(className, id, name, gate) {
+        this.#className = className;
+        this.#id = id;
+        this.#name = name;
+        this.#gate = gate;
+    }
+
+    #read(fn) {
+        return this.#gate.closeWhile(fn);
+    }
+
+    #write(fn) {
+        this.#dirty = true;
+        const write = this.#gate.closeWhile(fn);
+        const done = () => this.#writes.delete(write);
+        this.#writes.add(write);
+        write.then(done, done);
+        return write;
+    }
+
+    async get(keys) {
+        const list = keyList(keys);
+        const entries = await this.#read(() => core.ops.op_do_storage_get(this.#className, this.#id, list));
+        const values = decodeEntries(entries);
+        return Array.isArray(keys) ? values : values.get(list[0]);
+    }
+
+    async put(keyOrEntries, value) {
+        const writes = entryList(keyOrEntries, value).map(([key, value]) => [key, core.serialize(value)]);
+        await this.#write(() => core.ops.op_do_storage_apply(this.#className, this.#id, writes));
+    }
+
+    // Resolves to whether the key existed, or how many of them did.
+    async delete(keys) {
+        const list = keyList(keys);
+        const deleted = await this.#write(() => core.ops.op_do_storage_delete(this.#className, this.#id, list));
+        return Array.isArray(keys) ? deleted : deleted > 0;
+    }
+
+    async deleteAll() {
+        await this.#write(() => core.ops.op_do_storage_delete_all(this.#className, this.#id));
+    }
+
+    async list(options = {}) {
+        const entries = await this.#read(() => core.ops.op_do_storage_list(this.#className, this.#id, options));
+        return decodeEntries(entries);
+    }
+
+    // Runs `closure` with the gate closed throughout. Its writes land together
+    // when it returns, and not at all if it throws or rolls back.
+    transaction(closure) {
+        return this.#gate.closeWhile(async () => {
+            const txn = new DurableObjectTransaction(this);
+            const result = await closure(txn);
+            const writes = txn[kCommit]();
+            if (writes.length > 0) {
+                await this.#write(() => core.ops.op_do_storage_apply(this.#className, this.#id, writes));
+            }
+            return result;
+        });
+    }
+
+    // Resolves to when the alarm is due, in milliseconds since the epoch, or
+    // null if none is set.
+    async getAlarm() {
+        return await this.#read(() => core.ops.op_do_alarm_get(this.#className, this.#id));
+    }
+
+    // Calls the object's `alarm()` method at `scheduledTime`, a Date or
+    // milliseconds since the epoch, replacing any alarm set before.
+    async setAlarm(scheduledTime) {
+        const time = scheduledTime instanceof Date ? scheduledTime.getTime() : Number(scheduledTime);
+        if (!Number.isFinite(time)) {
+            throw new TypeError("setAlarm() takes a Date or a number of milliseconds");
+        }
+        await this.#write(() => core.ops.op_do_alarm_set(this.#className, this.#id, this.#name ?? null, time));
+    }
+
+    async deleteAlarm() {
+        await this.#write(() => core.ops.op_do_alarm_delete(this.#className, this.#id));
+    }
+
+    async sync() {
+        await this[kSync]();
+    }
+
+    // The output gate: an event's results are held back until the writes it
+    // made are on disk.
+    async [kSync]() {
+        while (this.#writes.size > 0) {
+            await Promise.allSettled([...this.#writes]);
+        }
+        if (this.#dirty) {
+            this.#dirty = false;
+            await core.ops.op_do_storage_sync(this.#className, this.#id);
+        }
+    }
+}
+
+// Reads and writes within `storage.transaction()`. Writes are buffered and
+// reads see them.
+class DurableObjectTransaction {
+    #storage;
+    // Serialized values by key, `null` for deletes.
+    #writes = new Map();
+    #rolledBack = false;
+
+    constructor(storage) {
+        this.#storage = storage;
+    }
+
+    #buffered(key) {
+        const value = this.#writes.get(key);
+        return value === null ? undefined : core.deserialize(value);
+    }
+
+    async get(keys) {
+        const list = keyList(keys);
+        const unbuffered = list.filter((key) => !this.#writes.has(key));
+        const stored = unbuffered.length > 0 ? await this.#storage.get(unbuffered) : new Map();
+        const values = new Map();
+        for (const key of list) {
+            const value = this.#writes.has(key) ? this.#buffered(key) : stored.get(key);
+            if (value !== undefined) {
+                values.set(key, value);
+            }
+        }
+        return Array.isArray(keys) ? values : values.get(list[0]);
+    }
+
+    async put(keyOrEntries, value) {
+        for (const [key, value] of entryList(keyOrEntries, value)) {
+            this.#writes.set(key, core.serialize(value));
+        }
+    }
+
+    async delete(keys) {
+        const list = keyList(keys);
+        const existing = await this.get(list);
+        for (const key of list) {
+            this.#writes.set(key, null);
+        }
+        return Array.isArray(keys) ? existing.size : existing.size > 0;
+    }
+
+    async list(options = {}) {
+        const { limit, reverse, ...range } = options;
+        const values = await this.#storage.list(range);
+        for (const key of this.#writes.keys()) {
+            if (!inRange(key, range)) {
+                continue;
+            }
+            const value = this.#buffered(key);
+            if (value === undefined) {
+                values.delete(key);
+            } else {
+                values.set(key, value);
+            }
+        }
+        const keys = [...values.keys()].sort();
+        if (reverse) {
+            keys.reverse();
+        }
+        return new Map(keys.slice(0, limit ?? keys.length).map((key) => [key, values.get(key)]));
+    }
+
+    rollback() {
+        this.#rolledBack = true;
+    }
+
+    [kCommit]() {
+        return this.#rolledBack ? [] : [...this.#writes];
+    }
+}
+
+// The `ctx` an object is constructed with.
+class DurableObjectState {
+    #className;
+    #gate;
+    #ctx = new ExecutionContext();
+
+    constructor(className, id, storage, gate) {
+        this.#className = className;
+        this.id = id;
+        this.storage = storage;
+        this.#gate = gate;
+    }
+
+    // Hands the server end of a WebSocketPair to the host, which keeps the
+    // connection open while the object is evicted. Its traffic comes in
+    // through the object's `webSocketMessage()` and `webSocketClose()`.
+    acceptWebSocket(ws, tags = []) {
+        const id = this.id.toString();
+        const name = this.id.name ?? null;
+        acceptServer(ws, () => core.ops.op_do_accept_websocket(this.#className, id, name, [...tags].map(String)));
+    }
+
+    getWebSockets(tag) {
+        const ids = core.ops.op_do_websockets(this.#className, this.id.toString(), tag ?? null);
+        return ids.map(serverSocket);
+    }
+
+    getTags(ws) {
+        return core.ops.op_do_websocket_tags(socketId(ws));
+    }
+
+    waitUntil(promise) {
+        this.#ctx.waitUntil(promise);
+    }
+
+    // Keeps events out until `callback` settles, e.g. while the constructor
+    // loads state.
+    blockConcurrencyWhile(callback) {
+        return this.#gate.closeWhile(callback);
+    }
+}
+
+function getObject(className, id, name) {
+    const key = `${className}:${id}`;
+    let object = objects.get(key);
+    if (object === undefined) {
+        const Class = workerModule?.exports[className];
+        if (typeof Class !== "function") {
+            throw new TypeError(`The ${className} export is not a Durable Object class`);
+        }
+        const gate = new InputGate();
+        const storage = new DurableObjectStorage(className, id, name, gate);
+        const state = new DurableObjectState(className, new DurableObjectId(id, name), storage, gate);
+        object = { key, gate, storage, instance: new Class(state, workerModule.env), active: 0, timer: null };
+        objects.set(key, object);
+    }
+    return object;
+}
+
+// Drops an object once it has been idle for a while. Its sockets stay open in
+// the host, so an object that only holds sockets does not keep its memory.
+function scheduleEviction(object) {
+    object.timer = setTimeout(() => {
+        if (object.active === 0 && !object.gate.closed && objects.get(object.key) === object) {
+            objects.delete(object.key);
+        }
+    }, kEvictAfterMs);
+}
+
+// Runs one event on an object once its input gate opens, and settles only
+// once its writes are on disk.
+async function deliver(object, event) {
+    // Checked right before the event starts, so it runs up to its first
+    // storage operation before anything else gets in.
+    clearTimeout(object.timer);
+    object.active++;
+    try {
+        while (object.gate.closed) {
+            await object.gate.wait();
+        }
+        return await event(object.instance);
+    } finally {
+        try {
+            await object.storage[kSync]();
+        } finally {
+            if (--object.active === 0) {
+                scheduleEviction(object);
+            }
+        }
+    }
+}
+
+function fetchObject(className, id, name, request) {
+    const object = getObject(className, id, name);
+    return deliver(object, (instance) => {
+        if (typeof instance.fetch !== "function") {
+            throw new TypeError(`${className} does not have a fetch() method`);
+        }
+        return instance.fetch(request);
+    });
+}
+
+// Runs the object's `alarm()` method. It throwing makes the host retry the
+// alarm later.
+function alarmObject(className, id, name, retryCount) {
+    const object = getObject(className, id, name);
+    return deliver(object, (instance) => {
+        if (typeof instance.alarm !== "function") {
+            throw new TypeError(`${className} set an alarm but has no alarm() method`);
+        }
+        return instance.alarm({ retryCount, isRetry: retryCount > 0 });
+    });
+}
+
+function rpcObject(className, id, name, payload) {
+    return encodeOutcome(() => {
+        const steps = core.deserialize(payload);
+        const object = getObject(className, id, name);
+        if (!(object.instance instanceof DurableObject)) {
+            throw new TypeError(`${className} must extend DurableObject to be called over RPC`);
+        }
+        return deliver(object, (instance) => runSteps(instance, instance.constructor, DurableObject, steps));
+    });
+}
+
+// Runs the object's handler for an event on one of its hibernatable sockets,
+// building the object anew if it was evicted.
+function socketObject(className, id, name, socketId, event) {
+    const ws = serverSocket(socketId);
+    if (event.type === "close") {
+        forgetSocket(socketId);
+    }
+    const object = getObject(className, id, name);
+    return deliver(object, (instance) => {
+        switch (event.type) {
+            case "message":
+                // Binary frames arrive as ArrayBuffers, as from a WebSocket.
+                return instance.webSocketMessage?.(
+                    ws,
+                    typeof event.data === "string" ? event.data : event.data.slice().buffer,
+                );
+            case "close":
+                return instance.webSocketClose?.(ws, event.code, event.reason, event.wasClean);
+            case "error":
+                return instance.webSocketError?.(ws, new Error(event.message));
+        }
+    });
+}
+
+// Calls an object through `stub.fetch()` or RPC methods on the stub.
+class DurableObjectStub {
+    #fetch;
+
+    constructor(id, fetch) {
+        this.id = id;
+        this.name = id.name;
+        this.#fetch = fetch;
+    }
+
+    fetch(input, init) {
+        return this.#fetch(new Request(input, init));
+    }
+}
+
+// The objects of a class, as `env.ROOMS`.
+class DurableObjectNamespace {
+    #binding;
+    #namespace;
+
+    constructor(binding) {
+        this.#binding = binding;
+        this.#namespace = core.ops.op_do_namespace(binding);
+    }
+
+    idFromName(name) {
+        name = String(name);
+        return new DurableObjectId(core.ops.op_do_id_from_name(this.#binding, name), name);
+    }
+
+    newUniqueId() {
+        return new DurableObjectId(core.ops.op_do_unique_id());
+    }
+
+    idFromString(id) {
+        if (!kIdPattern.test(id)) {
+            throw new TypeError(`Invalid Durable Object ID: ${id}`);
+        }
+        return new DurableObjectId(id);
+    }
+
+    get(id) {
+        if (!(id instanceof DurableObjectId)) {
+            throw new TypeError("get() takes a DurableObjectId");
+        }
+        const binding = this.#binding;
+        const { className, local } = this.#namespace;
+        const hex = id.toString();
+
+        // Objects of this worker are called right here; the host only
+        // carries calls to other workers.
+        let fetch;
+        let send;
+        if (local) {
+            fetch = (request) => fetchObject(className, hex, id.name, request);
+            send = (payload) => rpcObject(className, hex, id.name, payload);
+        } else {
+            fetch = async (request) =>
+                decodeResponse(await core.ops.op_do_fetch(binding, hex, id.name, await encodeRequest(request)));
+            send = (payload) => core.ops.op_do_rpc(binding, hex, id.name, payload);
+        }
+        const stub = new DurableObjectStub(id, fetch);
+        return withRpc(stub, (steps) => callRpc(`durable object ${binding}`, send, steps));
+    }
+
+    getByName(name) {
+        return this.get(this.idFromName(name));
+    }
+}
+
+registerHostHook("setWorkerModule", (exports, env) => {
+    workerModule = { exports, env };
+});
+registerHostHook("durableObjectFetch", fetchObject);
+registerHostHook("durableObjectRpc", rpcObject);
+registerHostHook("durableObjectAlarm", alarmObject);
+registerHostHook("durableObjectWebSocket", socketObject);
+
+applyToGlobal({
+    DurableObject: nonEnumerable(DurableObject),
+});
+
+export { DurableObject, DurableObjectNamespace };
@@ -0,0 +1,435 @@
+use super::service::{
+  collect_response, into_request, reply_timeout, ServiceRequest, ServiceResponse,
+};
+use super::{websocket::hold, worky::WorkerState, ExtensionTrait};
+use deno_core::error::CoreError;
+use deno_core::{extension, op2, Extension, JsBuffer, OpState, ToJsBuffer};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use worky_common::config::{DurableObjectBinding, WorkerConfig};
+use worky_common::deno_error;
+use worky_common::durable::{self, Alarms, ListOptions, ObjectStorage};
+use worky_common::error::WorkerError;
+use worky_common::websocket::{self, SocketOwner};
+use worky_common::workers::{
+  self, CallChain, DurableObjectCall, DurableObjectRequest, DurableObjectTarget, WorkerHandle,
+  WorkerMessage,
+};
+
+/// Most tags a socket may carry, and the longest one.
+const MAX_SOCKET_TAGS: usize = 10;
+const MAX_TAG_LENGTH: usize = 256;
+
+/// What the JS side needs to know about a binding to build its stubs.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Namespace {
+  class_name: String,
+  /// The objects live in this worker and are called without the host.
+  local: bool,
+}
+
+extension!(
+  worky_durable,
+  deps = [worky_js, worky_context, worky_service, worky_websocket],
+  ops = [
+    op_do_namespace,
+    op_do_id_from_name,
+    op_do_unique_id,
+    op_do_fetch,
+    op_do_rpc,
+    op_do_storage_get,
+    op_do_storage_apply,
+    op_do_storage_delete,
+    op_do_storage_delete_all,
+    op_do_storage_list,
+    op_do_storage_sync,
+    op_do_alarm_get,
+    op_do_alarm_set,
+    op_do_alarm_delete,
+    op_do_accept_websocket,
+    op_do_websockets,
+    op_do_websocket_tags,
+  ],
+  esm_entry_point = "ext:worky_durable/01_durable.js",
+  esm = [ dir "src/ext/durable", "01_durable.js" ],
+);
+impl ExtensionTrait<()> for worky_durable {
+  fn init((): ()) -> Extension {
+    worky_durable::init()
+  }
+}
+
+pub fn extensions(is_snapshot: bool) -> Vec<Extension> {
+  vec![worky_durable::build((), is_snapshot)]
+}
+
+fn durable_error(err: impl std::fmt::Display) -> CoreError {
+  deno_error!(err.to_string())
+}
+
+/// The name this worker's objects are stored under, its address if unnamed.
+pub(super) fn worker_key(state: &OpState) -> String {
+  let worker = state.borrow::<WorkerState>();
+  if worker.worker_name.is_empty() {
+    worker.worker_address.clone()
+  } else {
+    worker.worker_name.clone()
+  }
+}
+
+fn find_binding(state: &OpState, binding: &str) -> Result<DurableObjectBinding, CoreError> {
+  state
+    .borrow::<WorkerConfig>()
+    .durable_objects
+    .iter()
+    .find(|d| d.binding == binding)
+    .cloned()
+    .ok_or_else(|| durable_error(format!("no durable object namespace is bound to {binding}")))
+}
+
+fn is_local(state: &OpState, namespace: &DurableObjectBinding) -> bool {
+  let caller = &state.borrow::<WorkerState>().worker_name;
+  namespace.service.as_ref().is_none_or(|s| s == caller)
+}
+
+/// Looks up the worker the objects behind `binding` live in, along with the
+/// workers a call to it leaves waiting, the calling worker last.
+fn resolve_owner(
+  state: &OpState,
+  binding: &str,
+) -> Result<(Vec<String>, DurableObjectBinding, Arc<WorkerHandle>), CoreError> {
+  let worker = &state.borrow::<WorkerState>().worker_name;
+  let namespace = find_binding(state, binding)?;
+
+  // Objects of this worker are called in the isolate, never through its own
+  // queue, which would wait on itself.
+  let service = match &namespace.service {
+    Some(service) if service != worker => service.clone(),
+    _ => {
+      return Err(durable_error(format!(
+        "the objects of {binding} live in this worker"
+      )))
+    }
+  };
+  // Nor through the queue of a worker waiting on this one.
+  let callers = state
+    .try_borrow::<CallChain>()
+    .cloned()
+    .unwrap_or_default()
+    .extend(worker, &service)
+    .ok_or_else(|| {
+      durable_error(format!(
+        "the objects of {binding} live in {service}, which is waiting on this call"
+      ))
+    })?;
+  let owner = workers::service(&service)
+    .ok_or_else(|| durable_error(format!("service {service} is not loaded")))?;
+  Ok((callers, namespace, owner))
+}
+
+fn target(
+  namespace: &DurableObjectBinding,
+  id: String,
+  name: Option<String>,
+) -> Result<DurableObjectTarget, CoreError> {
+  if !durable::is_valid_id(&id) {
+    return Err(durable_error(format!("invalid durable object id: {id}")));
+  }
+  Ok(DurableObjectTarget {
+    class: namespace.class_name.clone(),
+    id,
+    name,
+  })
+}
+
+fn storage(state: &OpState, class: &str, id: &str) -> Result<ObjectStorage, CoreError> {
+  ObjectStorage::open(&worker_key(state), class, id).map_err(durable_error)
+}
+
+fn into_buffers(entries: Vec<(String, Vec<u8>)>) -> Vec<(String, ToJsBuffer)> {
+  entries
+    .into_iter()
+    .map(|(key, value)| (key, value.into()))
+    .collect()
+}
+
+#[op2]
+#[serde]
+fn op_do_namespace(state: &mut OpState, #[string] binding: &str) -> Result<Namespace, CoreError> {
+  let namespace = find_binding(state, binding)?;
+  Ok(Namespace {
+    local: is_local(state, &namespace),
+    class_name: namespace.class_name,
+  })
+}
+
+#[op2]
+#[string]
+fn op_do_id_from_name(
+  state: &mut OpState,
+  #[string] binding: &str,
+  #[string] name: &str,
+) -> Result<String, CoreError> {
+  let namespace = find_binding(state, binding)?;
+  Ok(durable::id_from_name(&namespace.class_name, name))
+}
+
+#[op2]
+#[string]
+fn op_do_unique_id() -> String {
+  durable::unique_id()
+}
+
+/// Hands a request to an object living in another worker and waits for the
+/// whole response.
+#[op2(async)]
+#[serde]
+async fn op_do_fetch(
+  #[string] binding: String,
+  #[string] id: String,
+  #[serde] name: Option<String>,
+  #[serde] req: ServiceRequest,
+  state: Rc<RefCell<OpState>>,
+) -> Result<ServiceResponse, CoreError> {
+  let (callers, namespace, owner) = resolve_owner(&state.borrow(), &binding)?;
+  let timeout = reply_timeout(&state.borrow());
+  let target = target(&namespace, id, name)?;
+  let request = into_request(req)?;
+
+  let (tx, rx) = tokio::sync::oneshot::channel();
+  owner
+    .sender
+    .send(WorkerMessage::DurableObject(DurableObjectRequest {
+      target,
+      call: DurableObjectCall::Fetch { request, resp: tx },
+      callers,
+    }))
+    .map_err(|_| durable_error(WorkerError::Unavailable))?;
+
+  let response = match tokio::time::timeout(timeout, rx).await {
+    Ok(Ok(Ok(response))) => response,
+    Ok(Ok(Err(e))) => return Err(durable_error(format!("{binding}: {e}"))),
+    Ok(Err(_)) => return Err(durable_error(WorkerError::Unavailable)),
+    Err(_) => return Err(durable_error(WorkerError::Timeout(timeout))),
+  };
+  let (response, _) = collect_response(response).await?;
+  if let Some(socket) = response.web_socket {
+    hold(&mut state.borrow_mut(), socket);
+  }
+  Ok(response)
+}
+
+/// Sends an encoded RPC pipeline to an object living in another worker.
+#[op2(async)]
+#[serde]
+async fn op_do_rpc(
+  #[string] binding: String,
+  #[string] id: String,
+  #[serde] name: Option<String>,
+  #[buffer(copy)] payload: Vec<u8>,
+  state: Rc<RefCell<OpState>>,
+) -> Result<ToJsBuffer, CoreError> {
+  let (callers, namespace, owner) = resolve_owner(&state.borrow(), &binding)?;
+  let timeout = reply_timeout(&state.borrow());
+  let target = target(&namespace, id, name)?;
+
+  let (tx, rx) = tokio::sync::oneshot::channel();
+  owner
+    .sender
+    .send(WorkerMessage::DurableObject(DurableObjectRequest {
+      target,
+      call: DurableObjectCall::Rpc { payload, resp: tx },
+      callers,
+    }))
+    .map_err(|_| durable_error(WorkerError::Unavailable))?;
+
+  match tokio::time::timeout(timeout, rx).await {
+    Ok(Ok(Ok(result))) => Ok(result.into()),
+    Ok(Ok(Err(e))) => Err(durable_error(format!("{binding}: {e}"))),
+    Ok(Err(_)) => Err(durable_error(WorkerError::Unavailable)),
+    Err(_) => Err(durable_error(WorkerError::Timeout(timeout))),
+  }
+}
+
+// Storage ops are synchronous: an object reads its own writes at once, and
+// the input gate keeps other events out while they run.
+
+#[op2]
+#[serde]
+fn op_do_storage_get(
+  state: &mut OpState,
+  #[string] class: &str,
+  #[string] id: &str,
+  #[serde] keys: Vec<String>,
+) -> Result<Vec<(String, ToJsBuffer)>, CoreError> {
+  let entries = storage(state, class, id)?
+    .get(&keys)
+    .map_err(durable_error)?;
+  Ok(into_buffers(entries))
+}
+
+/// Writes and deletes (`null` values) keys in one atomic batch.
+#[op2]
+fn op_do_storage_apply(
+  state: &mut OpState,
+  #[string] class: &str,
+  #[string] id: &str,
+  #[serde] writes: Vec<(String, Option<JsBuffer>)>,
+) -> Result<(), CoreError> {
+  let writes = writes
+    .into_iter()
+    .map(|(key, value)| (key, value.map(|v| v.to_vec())))
+    .collect();
+  storage(state, class, id)?
+    .apply(writes)
+    .map_err(durable_error)
+}
+
+/// Deletes `keys`, returning how many existed.
+#[op2]
+fn op_do_storage_delete(
+  state: &mut OpState,
+  #[string] class: &str,
+  #[string] id: &str,
+  #[serde] keys: Vec<String>,
+) -> Result<u32, CoreError> {
+  let deleted = storage(state, class, id)?
+    .delete(&keys)
+    .map_err(durable_error)?;
+  Ok(deleted as u32)
+}
+
+#[op2(fast)]
+fn op_do_storage_delete_all(
+  state: &mut OpState,
+  #[string] class: &str,
+  #[string] id: &str,
+) -> Result<(), CoreError> {
+  storage(state, class, id)?
+    .delete_all()
+    .map_err(durable_error)
+}
+
+#[op2]
+#[serde]
+fn op_do_storage_list(
+  state: &mut OpState,
+  #[string] class: &str,
+  #[string] id: &str,
+  #[serde] options: ListOptions,
+) -> Result<Vec<(String, ToJsBuffer)>, CoreError> {
+  let entries = storage(state, class, id)?
+    .list(&options)
+    .map_err(durable_error)?;
+  Ok(into_buffers(entries))
+}
+
+/// Resolves once the object's writes are on disk, which is what holds back
+/// its responses until then.
+#[op2(async)]
+async fn op_do_storage_sync(
+  #[string] class: String,
+  #[string] id: String,
+  state: Rc<RefCell<OpState>>,
+) -> Result<(), CoreError> {
+  let storage = storage(&state.borrow(), &class, &id)?;
+  storage.sync().await.map_err(durable_error)
+}
+
+fn alarms() -> Result<Alarms, CoreError> {
+  Alarms::open().map_err(durable_error)
+}
+
+/// When the object's alarm is due, in milliseconds since the epoch.
+#[op2]
+#[serde]
+fn op_do_alarm_get(
+  state: &mut OpState,
+  #[string] class: &str,
+  #[string] id: &str,
+) -> Result<Option<f64>, CoreError> {
+  let time = alarms()?
+    .get(&worker_key(state), class, id)
+    .map_err(durable_error)?;
+  Ok(time.map(|time| {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since.as_millis() as f64
+  }))
+}
+
+#[op2]
+fn op_do_alarm_set(
+  state: &mut OpState,
+  #[string] class: &str,
+  #[string] id: &str,
+  #[serde] name: Option<String>,
+  time: f64,
+) -> Result<(), CoreError> {
+  let time = UNIX_EPOCH + Duration::from_millis(time.max(0.0) as u64);
+  alarms()?
+    .set(&worker_key(state), class, id, name.as_deref(), time)
+    .map_err(durable_error)
+}
+
+#[op2(fast)]
+fn op_do_alarm_delete(
+  state: &mut OpState,
+  #[string] class: &str,
+  #[string] id: &str,
+) -> Result<(), CoreError> {
+  alarms()?
+    .delete(&worker_key(state), class, id)
+    .map_err(durable_error)
+}
+
+/// Registers a hibernatable socket of the object, returning its id. The
+/// connection is the host's from here on.
+#[op2]
+fn op_do_accept_websocket(
+  state: &mut OpState,
+  #[string] class: String,
+  #[string] id: String,
+  #[serde] name: Option<String>,
+  #[serde] tags: Vec<String>,
+) -> Result<u32, CoreError> {
+  if tags.len() > MAX_SOCKET_TAGS {
+    return Err(durable_error(format!(
+      "a WebSocket takes at most {MAX_SOCKET_TAGS} tags"
+    )));
+  }
+  if tags.iter().any(|tag| tag.len() > MAX_TAG_LENGTH) {
+    return Err(durable_error(format!(
+      "WebSocket tags are at most {MAX_TAG_LENGTH} bytes"
+    )));
+  }
+  let owner = SocketOwner {
+    worker: worker_key(state),
+    // Kept so an object its events bring back keeps its name.
+    object: DurableObjectTarget { class, id, name },
+  };
+  let socket = websocket::accept(owner, tags);
+  hold(state, socket);
+  Ok(socket)
+}
+
+/// The object's open sockets, those tagged `tag` if given.
+#[op2]
+#[serde]
+fn op_do_websockets(
+  state: &mut OpState,
+  #[string] class: &str,
+  #[string] id: &str,
+  #[serde] tag: Option<String>,
+) -> Vec<u32> {
+  websocket::of_object(&worker_key(state), class, id, tag.as_deref())
+}
+
+#[op2]
+#[serde]
+fn op_do_websocket_tags(state: &mut OpState, socket: u32) -> Vec<String> {
+  websocket::tags(socket, &worker_key(state))
+}
@@ -0,0 +1,44 @@
+import { registerHostHook } from "ext:worky_js/utils.js";
+import { KVNamespace } from "ext:worky_kv/01_kv.js";
+import { AssetsBinding } from "ext:worky_assets/01_assets.js";
+import { RateLimit } from "ext:worky_ratelimit/01_ratelimit.js";
+import { createFetcher } from "ext:worky_service/01_service.js";
+import { QueueProducer } from "ext:worky_queue/01_queue.js";
+import { DurableObjectNamespace } from "ext:worky_durable/01_durable.js";
+
+const core = globalThis.Deno.core;
+
+// Builds the `env` object handed to every handler from the worker's config.
+function createEnv() {
+  const bindings = core.ops.op_env_bindings();
+  const env = {};
+
+  for (const [name, value] of Object.entries(bindings.vars)) {
+    env[name] = value;
+  }
+  for (const name of bindings.secrets) {
+    env[name] = core.ops.op_secret_get(name) ?? undefined;
+  }
+  for (const { binding } of bindings.kvNamespaces) {
+    env[binding] = new KVNamespace(binding);
+  }
+  for (const binding of bindings.services) {
+    env[binding] = createFetcher(binding);
+  }
+  for (const binding of bindings.queues) {
+    env[binding] = new QueueProducer(binding);
+  }
+  for (const binding of bindings.durableObjects) {
+    env[binding] = new DurableObjectNamespace(binding);
+  }
+  for (const binding of bindings.rateLimiters) {
+    env[binding] = new RateLimit(binding);
+  }
+  if (bindings.assets) {
+    env[bindings.assets] = new AssetsBinding();
+  }
+
+  return env;
+}
+
+registerHostHook("createEnv", createEnv);
@@ -0,0 +1,65 @@
+use super::ExtensionTrait;
+use deno_core::{extension, op2, Extension, OpState};
+use serde::Serialize;
+use std::collections::HashMap;
+use worky_common::config::{KvNamespaceBinding, WorkerConfig};
+
+/// The parts of the worker's config that end up on `env`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EnvBindings {
+  vars: HashMap<String, String>,
+  secrets: Vec<String>,
+  kv_namespaces: Vec<KvNamespaceBinding>,
+  assets: Option<String>,
+  rate_limiters: Vec<String>,
+  services: Vec<String>,
+  queues: Vec<String>,
+  durable_objects: Vec<String>,
+}
+
+extension!(
+  worky_env,
+  deps = [worky_js, worky_kv, worky_secrets, worky_assets, worky_ratelimit, worky_service, worky_queue, worky_durable],
+  ops = [op_env_bindings],
+  esm_entry_point = "ext:worky_env/01_env.js",
+  esm = [ dir "src/ext/env", "01_env.js" ],
+);
+impl ExtensionTrait<()> for worky_env {
+  fn init((): ()) -> Extension {
+    worky_env::init()
+  }
+}
+
+pub fn extensions(is_snapshot: bool) -> Vec<Extension> {
+  vec![worky_env::build((), is_snapshot)]
+}
+
+#[op2]
+#[serde]
+fn op_env_bindings(state: &mut OpState) -> EnvBindings {
+  let config = state.borrow::<WorkerConfig>();
+  EnvBindings {
+    vars: config.vars.clone(),
+    secrets: config.secrets.clone(),
+    kv_namespaces: config.kv_namespaces.clone(),
+    assets: config.assets.as_ref().and_then(|a| a.binding.clone()),
+    rate_limiters: config
+      .rate_limiters
+      .iter()
+      .map(|b| b.binding.clone())
+      .collect(),
+    services: config.services.iter().map(|b| b.binding.clone()).collect(),
+    queues: config
+      .queues
+      .producers
+      .iter()
+      .map(|b| b.binding.clone())
+      .collect(),
+    durable_objects: config
+      .durable_objects
+      .iter()
+      .map(|b| b.binding.clone())
+      .collect(),
+  }
+}
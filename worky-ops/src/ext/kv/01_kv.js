@@ -1,17 +1,124 @@
 const core = globalThis.Deno.core;
 
-class KV {
-    static async get(key) {
-        return await core.ops.op_kv_get(key);
+const kTypes = ["text", "json", "arrayBuffer", "stream"];
+
+// Values are stored as bytes: strings as UTF-8, buffers as they are, streams
+// read to the end, and anything else as JSON.
+async function toBytes(value) {
+    if (typeof value === "string") {
+        return new TextEncoder().encode(value);
+    }
+    if (value instanceof ArrayBuffer) {
+        return new Uint8Array(value);
+    }
+    if (ArrayBuffer.isView(value)) {
+        return new Uint8Array(value.buffer, value.byteOffset, value.byteLength);
+    }
+    if (value instanceof globalThis.ReadableStream) {
+        const chunks = [];
+        let length = 0;
+        for await (const chunk of value) {
+            const bytes = await toBytes(chunk);
+            chunks.push(bytes);
+            length += bytes.byteLength;
+        }
+        const bytes = new Uint8Array(length);
+        let offset = 0;
+        for (const chunk of chunks) {
+            bytes.set(chunk, offset);
+            offset += chunk.byteLength;
+        }
+        return bytes;
+    }
+    if (value === undefined) {
+        throw new TypeError("KV values cannot be undefined");
+    }
+    return new TextEncoder().encode(JSON.stringify(value));
+}
+
+function fromBytes(bytes, type) {
+    switch (type) {
+        case "json":
+            return JSON.parse(new TextDecoder().decode(bytes));
+        case "arrayBuffer":
+            return bytes.buffer.slice(bytes.byteOffset, bytes.byteOffset + bytes.byteLength);
+        case "stream":
+            return new globalThis.ReadableStream({
+                start(controller) {
+                    controller.enqueue(bytes);
+                    controller.close();
+                },
+            });
+        default:
+            return new TextDecoder().decode(bytes);
+    }
+}
+
+// Takes `"json"` or `{ type: "json" }`; values read as text by default.
+function readType(options) {
+    const type = (typeof options === "string" ? options : options?.type) ?? "text";
+    if (!kTypes.includes(type)) {
+        throw new TypeError(`Invalid type: ${type}`);
+    }
+    return type;
+}
+
+function parseMetadata(metadata) {
+    return metadata == null ? null : JSON.parse(metadata);
+}
+
+// Reaches the namespace its worker binds to `binding`, or the default one.
+class KVNamespace {
+    #binding;
+
+    constructor(binding = "") {
+        this.#binding = binding;
+    }
+
+    async get(key, options) {
+        return (await this.getWithMetadata(key, options)).value;
+    }
+
+    // Resolves to `{ value, metadata }`, both null for missing keys.
+    async getWithMetadata(key, options) {
+        const type = readType(options);
+        const stored = await core.ops.op_kv_get(this.#binding, key);
+        if (stored === null) {
+            return { value: null, metadata: null };
+        }
+        return { value: fromBytes(stored.value, type), metadata: parseMetadata(stored.metadata) };
     }
 
-    static async put(key, value) {
-        return await core.ops.op_kv_put(key, value);
+    // `expiration` is in seconds since the epoch, `expirationTtl` in seconds
+    // from now. Expired keys read as missing. `metadata` is any value that
+    // serializes to at most 1024 bytes of JSON.
+    async put(key, value, { expiration, expirationTtl, metadata } = {}) {
+        const bytes = await toBytes(value);
+        const options = {
+            expiration,
+            expirationTtl,
+            metadata: metadata === undefined ? undefined : JSON.stringify(metadata),
+        };
+        return await core.ops.op_kv_put(this.#binding, key, bytes, options);
     }
 
-    static async delete(key) {
-        return await core.ops.op_kv_delete(key);
+    async delete(key) {
+        return await core.ops.op_kv_delete(this.#binding, key);
+    }
+
+    // Resolves to `{ keys, list_complete, cursor }`, keys in byte order. Pass
+    // `cursor` back to get the next page.
+    async list({ prefix, limit, cursor } = {}) {
+        const page = await core.ops.op_kv_list(this.#binding, { prefix, limit, cursor });
+        for (const key of page.keys) {
+            if (key.metadata !== undefined) {
+                key.metadata = parseMetadata(key.metadata);
+            }
+        }
+        return page;
     }
 }
 
-globalThis.KV = KV;
+globalThis.KV = new KVNamespace();
+
+export { KVNamespace };
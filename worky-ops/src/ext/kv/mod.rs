@@ -1,10 +1,18 @@
 use crate::ext::ExtensionTrait;
+use deno_core::error::CoreError;
 use deno_core::extension;
 use deno_core::op2;
 use deno_core::Extension;
+use deno_core::JsBuffer;
 use deno_core::OpState;
+use deno_core::ToJsBuffer;
+use serde::Serialize;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::SystemTime;
+use worky_common::config::WorkerConfig;
+use worky_common::deno_error;
+use worky_common::kv::{KvListOptions, KvListPage, KvNamespace, KvPutOptions};
 
 #[derive(Clone)]
 pub struct KvOptions {
@@ -13,7 +21,7 @@ pub struct KvOptions {
 
 extension!(
   worky_kv,
-  ops = [op_kv_get, op_kv_put, op_kv_delete],
+  ops = [op_kv_get, op_kv_put, op_kv_delete, op_kv_list],
   esm_entry_point = "ext:worky_kv/01_kv.js",
   esm = [ dir "src/ext/kv", "01_kv.js" ],
   options = {
@@ -32,41 +40,88 @@ impl ExtensionTrait<KvOptions> for worky_kv {
   }
 }
 
+fn kv_error(err: impl std::fmt::Display) -> CoreError {
+  deno_error!(err.to_string())
+}
+
+/// Opens the namespace behind `binding`, the empty binding being the default
+/// namespace behind the `KV` global. Workers only reach the namespaces their
+/// config binds.
+fn kv_namespace(state: &OpState, binding: &str) -> Result<KvNamespace, CoreError> {
+  let namespace = if binding.is_empty() {
+    ""
+  } else {
+    state
+      .borrow::<WorkerConfig>()
+      .kv_namespaces
+      .iter()
+      .find(|b| b.binding == binding)
+      .map(|b| b.id.as_str())
+      .ok_or_else(|| kv_error(format!("no KV namespace is bound to {binding}")))?
+  };
+  let db = state
+    .try_borrow::<sled::Db>()
+    .ok_or_else(|| kv_error("KV store is not available"))?;
+  KvNamespace::open(db, namespace).map_err(kv_error)
+}
+
+/// A value as `get()` hands it to JS, which decodes it as asked.
+#[derive(Serialize)]
+struct StoredValue {
+  value: ToJsBuffer,
+  /// As JSON.
+  metadata: Option<String>,
+}
+
 #[op2(async)]
-#[string]
+#[serde]
 pub async fn op_kv_get(
+  #[string] binding: String,
   #[string] key: String,
   state: Rc<RefCell<OpState>>,
-) -> Result<Option<String>, deno_core::error::CoreError> {
-  let state = state.borrow();
-  // TODO: Implement "?" error handling
-  let db = state.try_borrow::<sled::Db>().unwrap();
-  let value = db.get(key).unwrap();
-  Ok(value.map(|v| String::from_utf8(v.to_vec()).unwrap_or_default()))
+) -> Result<Option<StoredValue>, CoreError> {
+  let kv = kv_namespace(&state.borrow(), &binding)?;
+  let entry = kv.get(&key).map_err(kv_error)?;
+  Ok(entry.map(|entry| StoredValue {
+    value: entry.value.to_vec().into(),
+    metadata: entry.metadata,
+  }))
 }
 
 #[op2(async)]
-#[string]
 pub async fn op_kv_put(
+  #[string] binding: String,
   #[string] key: String,
-  #[string] value: String,
+  #[buffer] value: JsBuffer,
+  #[serde] options: KvPutOptions,
   state: Rc<RefCell<OpState>>,
-) -> Result<(), deno_core::error::CoreError> {
-  let state = state.borrow();
-  // TODO: Implement "?" error handling
-  let db = state.try_borrow::<sled::Db>().unwrap();
-  db.insert(key, value.as_bytes()).unwrap();
+) -> Result<(), CoreError> {
+  let kv = kv_namespace(&state.borrow(), &binding)?;
+  let expiration = options.expires_at(SystemTime::now()).map_err(kv_error)?;
+  let metadata = options.metadata().map_err(kv_error)?;
+  kv.put(&key, &value, expiration, metadata)
+    .map_err(kv_error)?;
   Ok(())
 }
 
 #[op2(async)]
 async fn op_kv_delete(
+  #[string] binding: String,
   #[string] key: String,
   state: Rc<RefCell<OpState>>,
-) -> Result<(), deno_core::error::CoreError> {
-  let state = state.borrow();
-  // TODO: Implement "?" error handling
-  let db = state.try_borrow::<sled::Db>().unwrap();
-  db.remove(key).unwrap();
+) -> Result<(), CoreError> {
+  let kv = kv_namespace(&state.borrow(), &binding)?;
+  kv.delete(&key).map_err(kv_error)?;
   Ok(())
 }
+
+#[op2(async)]
+#[serde]
+async fn op_kv_list(
+  #[string] binding: String,
+  #[serde] options: KvListOptions,
+  state: Rc<RefCell<OpState>>,
+) -> Result<KvListPage, CoreError> {
+  let kv = kv_namespace(&state.borrow(), &binding)?;
+  kv.list(&options).map_err(kv_error)
+}
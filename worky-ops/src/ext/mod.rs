@@ -28,3 +28,13 @@ pub mod webidl;
 pub mod worky;
 pub mod kv;
 pub mod secrets;
+pub mod context;
+pub mod env;
+pub mod assets;
+pub mod ratelimit;
+pub mod service;
+pub mod queue;
+pub mod durable;
+pub mod websocket;
+pub mod sockets;
+pub mod broadcast;
@@ -0,0 +1,77 @@
+import { registerHostHook } from "ext:worky_js/utils.js";
+
+const core = globalThis.Deno.core;
+
+const kOutcome = Symbol("outcome");
+
+// Sends messages to a queue, as `env.JOBS.send(message)`. Bodies are
+// structured-cloned, so anything `postMessage` accepts can be sent.
+class QueueProducer {
+    #binding;
+
+    constructor(binding) {
+        this.#binding = binding;
+    }
+
+    async send(body, options = {}) {
+        await this.sendBatch([{ body, delaySeconds: options.delaySeconds }]);
+    }
+
+    async sendBatch(messages, options = {}) {
+        const batch = [...messages].map(({ body, delaySeconds }) => ({
+            body: core.serialize(body),
+            delaySeconds: delaySeconds ?? options.delaySeconds ?? 0,
+        }));
+        await core.ops.op_queue_send(this.#binding, batch);
+    }
+}
+
+class Message {
+    constructor({ id, timestamp, body, attempts }) {
+        this.id = id;
+        this.timestamp = new Date(timestamp);
+        this.body = core.deserialize(body);
+        this.attempts = attempts;
+        this[kOutcome] = null;
+    }
+
+    // The first of `ack()` and `retry()` called on a message wins.
+    ack() {
+        this[kOutcome] ??= { retry: false };
+    }
+
+    retry(options = {}) {
+        this[kOutcome] ??= { retry: true, delaySeconds: options.delaySeconds };
+    }
+}
+
+// The batch handed to `queue(batch, env, ctx)`.
+class MessageBatch {
+    constructor(queue, messages) {
+        this.queue = queue;
+        this.messages = messages.map((message) => new Message(message));
+    }
+
+    ackAll() {
+        for (const message of this.messages) {
+            message.ack();
+        }
+    }
+
+    retryAll(options) {
+        for (const message of this.messages) {
+            message.retry(options);
+        }
+    }
+}
+
+// What becomes of each message once the handler is done. Messages it did not
+// settle are acknowledged if it succeeded and retried if it threw.
+function settleMessageBatch(batch, succeeded) {
+    return batch.messages.map((message) => message[kOutcome] ?? { retry: !succeeded });
+}
+
+registerHostHook("createMessageBatch", (queue, messages) => new MessageBatch(queue, messages));
+registerHostHook("settleMessageBatch", settleMessageBatch);
+
+export { MessageBatch, QueueProducer };
@@ -0,0 +1,81 @@
+use super::ExtensionTrait;
+use deno_core::error::CoreError;
+use deno_core::{extension, op2, Extension, JsBuffer, OpState};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+use worky_common::config::WorkerConfig;
+use worky_common::deno_error;
+use worky_common::queue::Queue;
+
+/// Most messages a single `sendBatch()` may carry.
+const MAX_BATCH_MESSAGES: usize = 100;
+/// Largest serialized message body.
+const MAX_MESSAGE_SIZE: usize = 128 * 1024;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OutgoingMessage {
+  body: JsBuffer,
+  delay_seconds: u64,
+}
+
+extension!(
+  worky_queue,
+  deps = [worky_js],
+  ops = [op_queue_send],
+  esm_entry_point = "ext:worky_queue/01_queue.js",
+  esm = [ dir "src/ext/queue", "01_queue.js" ],
+);
+impl ExtensionTrait<()> for worky_queue {
+  fn init((): ()) -> Extension {
+    worky_queue::init()
+  }
+}
+
+pub fn extensions(is_snapshot: bool) -> Vec<Extension> {
+  vec![worky_queue::build((), is_snapshot)]
+}
+
+fn queue_error(err: impl std::fmt::Display) -> CoreError {
+  deno_error!(err.to_string())
+}
+
+/// Appends messages to the queue behind the producer `binding`.
+#[op2(async)]
+async fn op_queue_send(
+  #[string] binding: String,
+  #[serde] messages: Vec<OutgoingMessage>,
+  state: Rc<RefCell<OpState>>,
+) -> Result<(), CoreError> {
+  let name = state
+    .borrow()
+    .borrow::<WorkerConfig>()
+    .queues
+    .producers
+    .iter()
+    .find(|p| p.binding == binding)
+    .map(|p| p.queue.clone())
+    .ok_or_else(|| queue_error(format!("no queue is bound to {binding}")))?;
+
+  if messages.len() > MAX_BATCH_MESSAGES {
+    return Err(queue_error(format!(
+      "a batch holds at most {MAX_BATCH_MESSAGES} messages"
+    )));
+  }
+  if messages.iter().any(|m| m.body.len() > MAX_MESSAGE_SIZE) {
+    return Err(queue_error(format!(
+      "messages may be at most {MAX_MESSAGE_SIZE} bytes"
+    )));
+  }
+
+  let queue = Queue::open(&name).map_err(queue_error)?;
+  queue
+    .send(
+      messages
+        .into_iter()
+        .map(|m| (m.body.to_vec(), Duration::from_secs(m.delay_seconds))),
+    )
+    .map_err(queue_error)
+}
@@ -0,0 +1,16 @@
+const core = globalThis.Deno.core;
+
+class RateLimit {
+    #binding;
+
+    constructor(binding) {
+        this.#binding = binding;
+    }
+
+    async limit({ key }) {
+        const success = core.ops.op_rate_limit(this.#binding, String(key));
+        return { success };
+    }
+}
+
+export { RateLimit };
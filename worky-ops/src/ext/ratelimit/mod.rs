@@ -0,0 +1,63 @@
+use super::ExtensionTrait;
+use deno_core::error::CoreError;
+use deno_core::{extension, op2, Extension, OpState};
+use std::collections::HashMap;
+use std::time::Duration;
+use worky_common::config::WorkerConfig;
+use worky_common::deno_error;
+use worky_common::ratelimit::RateLimiter;
+
+/// The limiters behind the worker's `rate_limiters` bindings, by binding name.
+struct RateLimiters(HashMap<String, RateLimiter>);
+
+extension!(
+  worky_ratelimit,
+  deps = [worky_js],
+  ops = [op_rate_limit],
+  esm_entry_point = "ext:worky_ratelimit/01_ratelimit.js",
+  esm = [ dir "src/ext/ratelimit", "01_ratelimit.js" ],
+);
+impl ExtensionTrait<()> for worky_ratelimit {
+  fn init((): ()) -> Extension {
+    worky_ratelimit::init()
+  }
+}
+
+pub fn extensions(is_snapshot: bool) -> Vec<Extension> {
+  vec![worky_ratelimit::build((), is_snapshot)]
+}
+
+fn ratelimit_error(err: impl std::fmt::Display) -> CoreError {
+  deno_error!(err.to_string())
+}
+
+/// Takes a token from the named limiter, `false` once `key` is over its limit.
+#[op2]
+fn op_rate_limit(
+  state: &mut OpState,
+  #[string] binding: &str,
+  #[string] key: &str,
+) -> Result<bool, CoreError> {
+  if !state.has::<RateLimiters>() {
+    let config = state.borrow::<WorkerConfig>();
+    let limiters = config
+      .rate_limiters
+      .iter()
+      .map(|b| {
+        let period = Duration::from_secs(b.period);
+        (
+          b.binding.clone(),
+          RateLimiter::new(b.limit, period, b.limit),
+        )
+      })
+      .collect();
+    state.put(RateLimiters(limiters));
+  }
+
+  let limiters = state.borrow::<RateLimiters>();
+  let limiter = limiters
+    .0
+    .get(binding)
+    .ok_or_else(|| ratelimit_error(format!("no rate limiter named {binding}")))?;
+  Ok(limiter.check(key).is_ok())
+}
@@ -0,0 +1,246 @@
+import { applyToGlobal, nonEnumerable, registerHostHook } from "ext:worky_js/utils.js";
+import { ExecutionContext } from "ext:worky_context/01_context.js";
+import { clientSocket } from "ext:worky_websocket/01_websocket.js";
+
+const core = globalThis.Deno.core;
+
+// Names that never resolve to an RPC method, so callers cannot reach the
+// entrypoint's internals or the prototype chain.
+const kReserved = new Set(["constructor", "env", "ctx", "__proto__", "prototype"]);
+
+class WorkerEntrypoint {
+    constructor(ctx, env) {
+        this.ctx = ctx;
+        this.env = env;
+    }
+}
+
+function isEntrypointClass(value) {
+    return typeof value === "function" &&
+        value.prototype instanceof WorkerEntrypoint;
+}
+
+// Finds the handler for an event on the module's default export, which is
+// either an object of handlers or a WorkerEntrypoint class instantiated per
+// event. Handlers are called as `handler(arg, env, ctx)`, or as
+// `handler(env, ctx)` for events without an argument, like `init`.
+function entrypointHandler(exports, name) {
+    const entry = exports.default;
+    if (isEntrypointClass(entry)) {
+        if (typeof entry.prototype[name] !== "function") {
+            return undefined;
+        }
+        return (...args) => {
+            const [env, ctx] = args.splice(-2);
+            return new entry(ctx, env)[name](...args);
+        };
+    }
+    const handler = entry?.[name];
+    return typeof handler === "function" ? handler.bind(entry) : undefined;
+}
+
+// Whether `name` is a method `entry` defines on top of `base`.
+function isRpcMethod(entry, base, name) {
+    if (kReserved.has(name) || name.startsWith("_")) {
+        return false;
+    }
+    for (let proto = entry.prototype; proto !== base.prototype; proto = Object.getPrototypeOf(proto)) {
+        if (Object.hasOwn(proto, name)) {
+            return typeof proto[name] === "function";
+        }
+    }
+    return false;
+}
+
+function remoteError({ name, message, stack }, label, callerStack) {
+    const Ctor = typeof globalThis[name] === "function" ? globalThis[name] : Error;
+    const err = new Ctor(message);
+    const local = callerStack.split("\n").slice(1).join("\n");
+    err.stack = `${stack ?? `${name}: ${message}`}\n    at <${label}>\n${local}`;
+    return err;
+}
+
+// Runs a pipelined call on the callee: `steps` walks from `target`, an
+// instance of `entry`, through property reads (`{ get }`) and calls
+// (`{ call }`), so `env.AUTH.getUser(id).name` takes a single round trip.
+async function runSteps(target, entry, base, steps) {
+    let self = undefined;
+    for (const [i, step] of steps.entries()) {
+        if ("get" in step) {
+            if (i === 0 ? !isRpcMethod(entry, base, step.get) : kReserved.has(step.get)) {
+                throw new TypeError(`${step.get} is not an RPC method`);
+            }
+            // The entrypoint itself is never a promise; not awaiting it lets
+            // the method start right away.
+            self = i === 0 ? target : await target;
+            // Only a call may follow a method: reading its `call`, `apply` or
+            // `bind` would let the caller pick what `this` is.
+            if (typeof self === "function") {
+                throw new TypeError(`${step.get} is not an RPC method`);
+            }
+            target = self[step.get];
+        } else {
+            if (typeof target !== "function") {
+                throw new TypeError("RPC target is not a function");
+            }
+            target = await target.apply(self, step.call);
+            self = undefined;
+        }
+    }
+    return await target;
+}
+
+// Runs `fn` and encodes its outcome, the value it returned or the error it
+// threw, for the caller to decode.
+async function encodeOutcome(fn) {
+    let result;
+    try {
+        result = { ok: await fn() };
+    } catch (err) {
+        result = {
+            error: {
+                name: err?.name ?? "Error",
+                message: String(err?.message ?? err),
+                stack: err?.stack,
+            },
+        };
+    }
+
+    try {
+        return core.serialize(result);
+    } catch (err) {
+        return core.serialize({
+            error: { name: "DataCloneError", message: `Could not serialize the RPC result: ${err.message}` },
+        });
+    }
+}
+
+function handleRpc(exports, entrypoint, payload, env) {
+    return encodeOutcome(() => {
+        const steps = core.deserialize(payload);
+        const name = entrypoint ?? "default";
+        const entry = exports[name];
+        if (!isEntrypointClass(entry)) {
+            throw new TypeError(`The ${name} export is not a WorkerEntrypoint`);
+        }
+        return runSteps(new entry(new ExecutionContext(), env), entry, WorkerEntrypoint, steps);
+    });
+}
+
+// Sends `steps` through `send`, which hands the encoded pipeline to the
+// callee and resolves to the encoded outcome. `label` names the callee in
+// the stack of errors it threw.
+async function callRpc(label, send, steps) {
+    const callerStack = new Error().stack;
+    const res = core.deserialize(await send(core.serialize(steps)));
+    if ("error" in res) {
+        throw remoteError(res.error, label, callerStack);
+    }
+    return res.ok;
+}
+
+// A pending RPC result. Reading a property or calling it extends the
+// pipeline; awaiting it sends the whole pipeline once through `call`.
+function rpcStub(call, steps) {
+    let sent = null;
+    const send = () => (sent ??= call(steps));
+    return new Proxy(function () {}, {
+        get(_, prop) {
+            switch (prop) {
+                case "then":
+                    return (onOk, onErr) => send().then(onOk, onErr);
+                case "catch":
+                    return (onErr) => send().catch(onErr);
+                case "finally":
+                    return (onFinally) => send().finally(onFinally);
+            }
+            if (typeof prop === "symbol") {
+                return undefined;
+            }
+            return rpcStub(call, [...steps, { get: prop }]);
+        },
+        apply(_, __, args) {
+            return rpcStub(call, [...steps, { call: args }]);
+        },
+    });
+}
+
+// Calls another loaded worker in-process, as `env.AUTH.fetch(request)`.
+class Fetcher {
+    #binding;
+
+    constructor(binding) {
+        this.#binding = binding;
+    }
+
+    async fetch(input, init) {
+        const request = await encodeRequest(new Request(input, init));
+        return decodeResponse(await core.ops.op_service_fetch(this.#binding, request));
+    }
+}
+
+// The parts of a request the host passes on to another worker.
+async function encodeRequest(request) {
+    const body = request.body === null
+        ? null
+        : new Uint8Array(await request.arrayBuffer());
+    return {
+        method: request.method,
+        url: request.url,
+        headers: [...request.headers],
+        body,
+    };
+}
+
+function decodeResponse(res) {
+    if (res.webSocket !== null) {
+        return new Response(null, {
+            status: res.status,
+            headers: res.headers,
+            webSocket: clientSocket(res.webSocket),
+        });
+    }
+    return new Response(res.body, { status: res.status, headers: res.headers });
+}
+
+// Exposes the members of `target` and turns anything else into an RPC method
+// on the other side, as in `env.AUTH.verify(token)`.
+function withRpc(target, call) {
+    return new Proxy(target, {
+        get(target, prop) {
+            if (typeof prop === "symbol" || prop in target) {
+                const value = Reflect.get(target, prop);
+                return typeof value === "function" ? value.bind(target) : value;
+            }
+            // Not a thenable, so the binding itself can be awaited or returned.
+            if (prop === "then") {
+                return undefined;
+            }
+            return rpcStub(call, [{ get: prop }]);
+        },
+    });
+}
+
+function createFetcher(binding) {
+    const send = (payload) => core.ops.op_service_rpc(binding, payload);
+    return withRpc(new Fetcher(binding), (steps) => callRpc(`service ${binding}`, send, steps));
+}
+
+registerHostHook("entrypointHandler", entrypointHandler);
+registerHostHook("handleRpc", handleRpc);
+
+applyToGlobal({
+    WorkerEntrypoint: nonEnumerable(WorkerEntrypoint),
+});
+
+export {
+    callRpc,
+    createFetcher,
+    decodeResponse,
+    encodeOutcome,
+    encodeRequest,
+    Fetcher,
+    runSteps,
+    withRpc,
+    WorkerEntrypoint,
+};
@@ -0,0 +1,250 @@
+use super::{websocket::hold, worky::WorkerState, ExtensionTrait};
+use crate::ext::console::{push_log, LogType};
+use deno_core::error::CoreError;
+use deno_core::{extension, op2, Extension, JsBuffer, OpState, ToJsBuffer};
+use http_body_util::BodyExt;
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue, HOST};
+use hyper::{Method, Request, Response, Uri};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use worky_common::access_log::{new_request_id, AccessEntry};
+use worky_common::config::ServiceBinding;
+use worky_common::config::WorkerConfig;
+use worky_common::deno_error;
+use worky_common::error::WorkerError;
+use worky_common::websocket::AcceptedSocket;
+use worky_common::workers::{
+  self, CallChain, RpcRequest, WorkerHandle, WorkerMessage, WorkerRequest,
+};
+
+/// The parts of a `Request` handed to `env.SERVICE.fetch()`.
+#[derive(Deserialize)]
+pub(crate) struct ServiceRequest {
+  method: String,
+  url: String,
+  headers: Vec<(String, String)>,
+  body: Option<JsBuffer>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ServiceResponse {
+  status: u16,
+  headers: Vec<(String, String)>,
+  body: Option<ToJsBuffer>,
+  /// The socket a `101` response hands to the client.
+  #[serde(rename = "webSocket")]
+  pub(crate) web_socket: Option<u32>,
+}
+
+extension!(
+  worky_service,
+  deps = [worky_js, worky_context, worky_websocket],
+  ops = [op_service_fetch, op_service_rpc],
+  esm_entry_point = "ext:worky_service/01_service.js",
+  esm = [ dir "src/ext/service", "01_service.js" ],
+);
+impl ExtensionTrait<()> for worky_service {
+  fn init((): ()) -> Extension {
+    worky_service::init()
+  }
+}
+
+pub fn extensions(is_snapshot: bool) -> Vec<Extension> {
+  vec![worky_service::build((), is_snapshot)]
+}
+
+pub(crate) fn service_error(err: impl std::fmt::Display) -> CoreError {
+  deno_error!(err.to_string())
+}
+
+/// Looks up the worker behind `binding`, along with the workers a call to it
+/// leaves waiting, the calling worker last.
+fn resolve_binding(
+  state: &OpState,
+  binding: &str,
+) -> Result<(Vec<String>, ServiceBinding, Arc<WorkerHandle>), CoreError> {
+  let worker = &state.borrow::<WorkerState>().worker_name;
+  let service = state
+    .borrow::<WorkerConfig>()
+    .services
+    .iter()
+    .find(|s| s.binding == binding)
+    .cloned()
+    .ok_or_else(|| service_error(format!("no service is bound to {binding}")))?;
+
+  // A worker handles one message at a time, so a call into a worker that is
+  // waiting on this one, or into this worker itself, would never finish.
+  let callers = state
+    .try_borrow::<CallChain>()
+    .cloned()
+    .unwrap_or_default()
+    .extend(worker, &service.service)
+    .ok_or_else(|| {
+      service_error(format!(
+        "{binding} would call {}, which is waiting on this call",
+        service.service
+      ))
+    })?;
+  let callee = workers::service(&service.service)
+    .ok_or_else(|| service_error(format!("service {} is not loaded", service.service)))?;
+  Ok((callers, service, callee))
+}
+
+/// How long a worker waits for the reply to a call into another worker.
+pub(crate) fn reply_timeout(state: &OpState) -> Duration {
+  Duration::from_millis(state.borrow::<WorkerConfig>().subrequest_timeout_ms)
+}
+
+/// Turns a request from JS into one a worker thread takes.
+pub(crate) fn into_request(req: ServiceRequest) -> Result<Request<Bytes>, CoreError> {
+  let url: Uri = req.url.parse().map_err(service_error)?;
+  let body = req
+    .body
+    .map(|body| Bytes::copy_from_slice(&body))
+    .unwrap_or_default();
+  let mut request = Request::new(body);
+  *request.method_mut() = Method::from_bytes(req.method.as_bytes()).map_err(service_error)?;
+  *request.uri_mut() = url
+    .path_and_query()
+    .map_or("/", |p| p.as_str())
+    .parse()
+    .map_err(service_error)?;
+  for (name, value) in &req.headers {
+    let name = HeaderName::from_bytes(name.as_bytes()).map_err(service_error)?;
+    let value = HeaderValue::from_str(value).map_err(service_error)?;
+    request.headers_mut().append(name, value);
+  }
+  if let Some(host) = url.authority() {
+    if !request.headers().contains_key(HOST) {
+      let host = HeaderValue::from_str(host.as_str()).map_err(service_error)?;
+      request.headers_mut().insert(HOST, host);
+    }
+  }
+  Ok(request)
+}
+
+/// Reads a worker's whole response for JS, along with the size of its body.
+pub(crate) async fn collect_response<B>(
+  response: Response<B>,
+) -> Result<(ServiceResponse, u64), CoreError>
+where
+  B: hyper::body::Body,
+  B::Error: std::fmt::Display,
+{
+  let (parts, body) = response.into_parts();
+  let body = body.collect().await.map_err(service_error)?.to_bytes();
+  let response = ServiceResponse {
+    status: parts.status.as_u16(),
+    headers: parts
+      .headers
+      .iter()
+      .map(|(name, value)| {
+        let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+        (name.to_string(), value)
+      })
+      .collect(),
+    body: (!body.is_empty()).then(|| body.to_vec().into()),
+    web_socket: parts.extensions.get::<AcceptedSocket>().map(|s| s.0),
+  };
+  Ok((response, body.len() as u64))
+}
+
+/// Hands a request straight to the worker behind `binding` and waits for the
+/// whole response.
+#[op2(async)]
+#[serde]
+async fn op_service_fetch(
+  #[string] binding: String,
+  #[serde] req: ServiceRequest,
+  state: Rc<RefCell<OpState>>,
+) -> Result<ServiceResponse, CoreError> {
+  let (callers, _, callee) = resolve_binding(&state.borrow(), &binding)?;
+  let timeout = reply_timeout(&state.borrow());
+  let request = into_request(req)?;
+
+  let start = Instant::now();
+  let mut entry = AccessEntry {
+    time: SystemTime::now(),
+    request_id: request
+      .headers()
+      .get("x-request-id")
+      .and_then(|v| v.to_str().ok())
+      .map_or_else(new_request_id, str::to_owned),
+    client_ip: None,
+    caller: callers.last().cloned(),
+    method: request.method().to_string(),
+    path: request.uri().to_string(),
+    version: format!("{:?}", request.version()),
+    status: 0,
+    bytes: 0,
+    duration: Default::default(),
+    referer: None,
+    user_agent: None,
+  };
+
+  let (tx, rx) = tokio::sync::oneshot::channel();
+  let sent = callee.sender.send(WorkerMessage::Fetch(WorkerRequest {
+    resp: tx,
+    request_data: Some(request),
+    callers,
+  }));
+  let response = match sent {
+    Ok(()) => match tokio::time::timeout(timeout, rx).await {
+      Ok(Ok(Ok(response))) => response,
+      Ok(Ok(Err(e))) => e.to_response(callee.config.dev),
+      Ok(Err(_)) => WorkerError::Unavailable.to_response(callee.config.dev),
+      Err(_) => WorkerError::Timeout(timeout).to_response(callee.config.dev),
+    },
+    Err(_) => WorkerError::Unavailable.to_response(callee.config.dev),
+  };
+
+  let (response, bytes) = collect_response(response).await?;
+  if let Some(socket) = response.web_socket {
+    hold(&mut state.borrow_mut(), socket);
+  }
+
+  if let Some(log) = &callee.access_log {
+    entry.status = response.status;
+    entry.bytes = bytes;
+    entry.duration = start.elapsed();
+    let line = log.record(&entry);
+    push_log(&callee.addr, &callee.name, &line, LogType::Access);
+  }
+
+  Ok(response)
+}
+
+/// Sends an encoded RPC pipeline to the entrypoint behind `binding`. The
+/// encoded outcome, a value or the error thrown, is decoded by the caller.
+#[op2(async)]
+#[serde]
+async fn op_service_rpc(
+  #[string] binding: String,
+  #[buffer(copy)] payload: Vec<u8>,
+  state: Rc<RefCell<OpState>>,
+) -> Result<ToJsBuffer, CoreError> {
+  let (callers, service, callee) = resolve_binding(&state.borrow(), &binding)?;
+  let timeout = reply_timeout(&state.borrow());
+
+  let (tx, rx) = tokio::sync::oneshot::channel();
+  callee
+    .sender
+    .send(WorkerMessage::Rpc(RpcRequest {
+      resp: tx,
+      entrypoint: service.entrypoint,
+      payload,
+      callers,
+    }))
+    .map_err(|_| service_error(WorkerError::Unavailable))?;
+
+  match tokio::time::timeout(timeout, rx).await {
+    Ok(Ok(Ok(result))) => Ok(result.into()),
+    Ok(Ok(Err(e))) => Err(service_error(format!("{binding}: {e}"))),
+    Ok(Err(_)) => Err(service_error(WorkerError::Unavailable)),
+    Err(_) => Err(service_error(WorkerError::Timeout(timeout))),
+  }
+}
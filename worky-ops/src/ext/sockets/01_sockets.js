@@ -0,0 +1,221 @@
+import { applyToGlobal, nonEnumerable, registerHostHook } from "ext:worky_js/utils.js";
+import { ReadableStream, WritableStream } from "ext:deno_web/06_streams.js";
+
+const core = globalThis.Deno.core;
+
+const kReadSize = 64 * 1024;
+const kSecureTransports = ["off", "on", "starttls"];
+
+const encoder = new TextEncoder();
+
+// Takes `{ hostname, port }`, or `"host:port"` with IPv6 hosts in brackets.
+function parseAddress(address) {
+    let hostname;
+    let port;
+    if (typeof address === "string") {
+        const match = /^(?:\[([^\]]+)\]|([^:]+)):(\d+)$/.exec(address);
+        if (match === null) {
+            throw new TypeError(`Invalid address: ${address}`);
+        }
+        hostname = match[1] ?? match[2];
+        port = Number(match[3]);
+    } else {
+        hostname = address?.hostname;
+        port = Number(address?.port);
+    }
+    if (typeof hostname !== "string" || hostname === "") {
+        throw new TypeError("connect() needs a hostname");
+    }
+    if (!Number.isInteger(port) || port < 1 || port > 65535) {
+        throw new TypeError(`Invalid port: ${address?.port ?? address}`);
+    }
+    return { hostname, port };
+}
+
+function toBytes(chunk) {
+    if (typeof chunk === "string") {
+        return encoder.encode(chunk);
+    }
+    if (chunk instanceof ArrayBuffer) {
+        return new Uint8Array(chunk);
+    }
+    if (ArrayBuffer.isView(chunk)) {
+        return new Uint8Array(chunk.buffer, chunk.byteOffset, chunk.byteLength);
+    }
+    throw new TypeError("Sockets take strings, ArrayBuffers and ArrayBuffer views");
+}
+
+// An outbound TCP connection, as returned by `connect()`.
+class Socket {
+    #hostname;
+    #secureTransport;
+    #allowHalfOpen;
+    // Resolves to the socket's resource id and addresses once connected.
+    #info;
+    #closed = false;
+    #upgraded = false;
+    #resolveClosed;
+    #rejectClosed;
+
+    constructor(hostname, info, { secureTransport, allowHalfOpen }) {
+        this.#hostname = hostname;
+        this.#secureTransport = secureTransport;
+        this.#allowHalfOpen = allowHalfOpen;
+        this.#info = info;
+
+        this.opened = info.then(({ remoteAddress, localAddress }) => ({ remoteAddress, localAddress }));
+        this.closed = new Promise((resolve, reject) => {
+            this.#resolveClosed = resolve;
+            this.#rejectClosed = reject;
+        });
+        // Failures surface through the streams; these are only for those
+        // who ask.
+        this.opened.catch(() => {});
+        this.closed.catch(() => {});
+        info.catch((err) => this.#fail(err));
+
+        this.readable = new ReadableStream({
+            pull: (controller) => this.#pull(controller),
+            cancel: () => {
+                this.close();
+            },
+        });
+        this.writable = new WritableStream({
+            write: (chunk) => this.#write(chunk),
+            close: () => this.#shutdown(),
+            abort: () => {
+                this.close();
+            },
+        });
+    }
+
+    #fail(err) {
+        if (!this.#closed) {
+            this.#closed = true;
+            this.#rejectClosed(err);
+        }
+    }
+
+    async #pull(controller) {
+        const { rid } = await this.#info;
+        const buf = new Uint8Array(kReadSize);
+        let n;
+        try {
+            n = await core.ops.op_sockets_read(rid, buf);
+        } catch (err) {
+            // Reads cut short by `close()` or `startTls()` just end the stream.
+            if (this.#closed || this.#upgraded) {
+                controller.close();
+                return;
+            }
+            this.#fail(err);
+            throw err;
+        }
+        if (n === 0) {
+            controller.close();
+            if (!this.#allowHalfOpen) {
+                this.close();
+            }
+            return;
+        }
+        controller.enqueue(buf.subarray(0, n));
+    }
+
+    async #write(chunk) {
+        const bytes = toBytes(chunk);
+        const { rid } = await this.#info;
+        try {
+            await core.ops.op_sockets_write(rid, bytes);
+        } catch (err) {
+            this.#fail(err);
+            throw err;
+        }
+    }
+
+    async #shutdown() {
+        const { rid } = await this.#info;
+        await core.ops.op_sockets_shutdown(rid);
+        if (!this.#allowHalfOpen) {
+            this.close();
+        }
+    }
+
+    close() {
+        if (!this.#closed) {
+            this.#closed = true;
+            this.#info.then(
+                ({ rid }) => {
+                    if (!this.#upgraded) {
+                        core.tryClose(rid);
+                    }
+                },
+                () => {},
+            );
+            this.#resolveClosed();
+        }
+        return this.closed;
+    }
+
+    // Upgrades a connection opened with `secureTransport: "starttls"` to TLS.
+    // The returned socket replaces this one, whose streams stop working.
+    startTls() {
+        if (this.#secureTransport !== "starttls") {
+            throw new TypeError('startTls() needs a socket opened with secureTransport: "starttls"');
+        }
+        if (this.#upgraded || this.#closed) {
+            throw new TypeError("The socket was already upgraded or closed");
+        }
+        this.#upgraded = true;
+        const hostname = this.#hostname;
+        const info = this.#info.then(async (info) => ({
+            ...info,
+            rid: await core.ops.op_sockets_start_tls(info.rid, hostname),
+        }));
+        this.#closed = true;
+        this.#resolveClosed();
+        return new Socket(hostname, info, { secureTransport: "on", allowHalfOpen: this.#allowHalfOpen });
+    }
+}
+
+// Opens a TCP connection to `address`, subject to the worker's network
+// permissions. `secureTransport` is "off", "on" for TLS from the start, or
+// "starttls" to upgrade later with `socket.startTls()`.
+function connect(address, options = {}) {
+    const { hostname, port } = parseAddress(address);
+    const secureTransport = options.secureTransport ?? "off";
+    if (!kSecureTransports.includes(secureTransport)) {
+        throw new TypeError(`Invalid secureTransport: ${secureTransport}`);
+    }
+    const info = core.ops.op_sockets_connect(hostname, port, secureTransport === "on");
+    return new Socket(hostname, info, { secureTransport, allowHalfOpen: Boolean(options.allowHalfOpen) });
+}
+
+// Runs a `tcp` worker's `connect()` handler on a connection its listener
+// accepted. Nothing waits for it, so connections are served side by side;
+// the worker hears of the connection again once the handler settled and the
+// socket closed, with what the handler threw.
+function acceptConnection(handler, rid, remoteAddress, localAddress, env, ctx) {
+    const info = Promise.resolve({ rid, remoteAddress, localAddress });
+    const socket = new Socket(null, info, { secureTransport: "off", allowHalfOpen: false });
+    (async () => {
+        let error = null;
+        try {
+            await handler(socket, env, ctx);
+        } catch (err) {
+            error = { message: `Uncaught ${String(err)}`, stack: err?.stack ?? null };
+            socket.close();
+        }
+        await socket.closed.catch(() => {});
+        // A socket that failed is closed without `close()`.
+        core.tryClose(rid);
+        core.ops.op_sockets_connection_end(rid, error);
+    })();
+}
+
+registerHostHook("acceptConnection", acceptConnection);
+
+applyToGlobal({
+    connect: nonEnumerable(connect),
+});
+
+export { connect, Socket };
@@ -0,0 +1,366 @@
+use super::web::PermissionsContainer;
+use super::ExtensionTrait;
+use deno_core::error::CoreError;
+use deno_core::{
+  extension, op2, AsyncRefCell, CancelHandle, CancelTryFuture, Extension, JsBuffer, OpState, RcRef,
+  Resource, ResourceId,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::WeakUnboundedSender;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use worky_common::deno_error;
+use worky_common::error::WorkerError;
+use worky_common::workers::{ConnectionEnd, WorkerMessage};
+
+/// Verifies servers against the bundled Mozilla roots, the same for every
+/// worker.
+static TLS_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
+  let mut roots = RootCertStore::empty();
+  roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+  let config = ClientConfig::builder()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+  Arc::new(config)
+});
+
+extension!(
+  worky_sockets,
+  deps = [worky_js],
+  ops = [
+    op_sockets_connect,
+    op_sockets_read,
+    op_sockets_write,
+    op_sockets_shutdown,
+    op_sockets_start_tls,
+    op_sockets_connection_end,
+  ],
+  esm_entry_point = "ext:worky_sockets/01_sockets.js",
+  esm = [ dir "src/ext/sockets", "01_sockets.js" ],
+);
+impl ExtensionTrait<()> for worky_sockets {
+  fn init((): ()) -> Extension {
+    worky_sockets::init()
+  }
+}
+
+pub fn extensions(is_snapshot: bool) -> Vec<Extension> {
+  vec![worky_sockets::build((), is_snapshot)]
+}
+
+fn sockets_error(err: impl std::fmt::Display) -> CoreError {
+  deno_error!(err.to_string())
+}
+
+/// A connection opened with `connect()`, before or after `startTls()`.
+enum Connection {
+  Tcp(TcpStream),
+  Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Connection {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+      Connection::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+    }
+  }
+}
+
+impl AsyncWrite for Connection {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    match self.get_mut() {
+      Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+      Connection::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+    }
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+      Connection::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+    }
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+      Connection::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+    }
+  }
+}
+
+/// The halves are apart so a pending read does not hold up writes.
+struct SocketResource {
+  read: AsyncRefCell<Option<ReadHalf<Connection>>>,
+  write: AsyncRefCell<Option<WriteHalf<Connection>>>,
+  cancel: CancelHandle,
+  sent: BytesSent,
+  /// Counts accepted connections against the worker's limit while open.
+  _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl SocketResource {
+  fn new(connection: Connection, permit: Option<OwnedSemaphorePermit>, sent: BytesSent) -> Self {
+    let (read, write) = tokio::io::split(connection);
+    Self {
+      read: AsyncRefCell::new(Some(read)),
+      write: AsyncRefCell::new(Some(write)),
+      cancel: CancelHandle::new(),
+      sent,
+      _permit: permit,
+    }
+  }
+}
+
+/// How many bytes were written to a socket, readable after it closed.
+#[derive(Clone, Default)]
+pub struct BytesSent(Rc<Cell<u64>>);
+
+impl BytesSent {
+  pub fn get(&self) -> u64 {
+    self.0.get()
+  }
+
+  fn count(&self, n: usize) {
+    self.0.set(self.0.get() + n as u64);
+  }
+}
+
+/// Makes a connection accepted by the worker's listener usable from JS as a
+/// socket, returning its resource id. `sent` counts what is written to it.
+pub fn accept_socket(
+  state: &mut OpState,
+  stream: TcpStream,
+  permit: OwnedSemaphorePermit,
+  sent: BytesSent,
+) -> ResourceId {
+  let socket = SocketResource::new(Connection::Tcp(stream), Some(permit), sent);
+  state.resource_table.add(socket)
+}
+
+/// Lets the worker thread know when a `connect()` handler is done with a
+/// connection. The thread puts it in the `OpState` with the sender of its own
+/// messages; without it, as in tests, nobody is told.
+pub struct ConnectionHost(pub WeakUnboundedSender<WorkerMessage>);
+
+impl Resource for SocketResource {
+  fn name(&self) -> Cow<str> {
+    "workySocket".into()
+  }
+
+  fn close(self: Rc<Self>) {
+    self.cancel.cancel();
+  }
+}
+
+fn socket(state: &Rc<RefCell<OpState>>, rid: ResourceId) -> Result<Rc<SocketResource>, CoreError> {
+  state
+    .borrow()
+    .resource_table
+    .get::<SocketResource>(rid)
+    .map_err(|_| closed())
+}
+
+fn closed() -> CoreError {
+  sockets_error("the socket is closed")
+}
+
+/// What `socket.opened` resolves to.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SocketInfo {
+  rid: ResourceId,
+  remote_address: String,
+  local_address: String,
+}
+
+async fn start_tls(stream: TcpStream, hostname: &str) -> Result<Connection, CoreError> {
+  let server_name = ServerName::try_from(hostname.to_string())
+    .map_err(|_| sockets_error(format!("invalid TLS server name: {hostname}")))?;
+  let stream = TlsConnector::from(TLS_CONFIG.clone())
+    .connect(server_name, stream)
+    .await
+    .map_err(sockets_error)?;
+  Ok(Connection::Tls(Box::new(stream)))
+}
+
+/// Opens a TCP connection, if the worker's permissions let it reach the host.
+#[op2(async)]
+#[serde]
+async fn op_sockets_connect(
+  state: Rc<RefCell<OpState>>,
+  #[string] hostname: String,
+  port: u32,
+  secure: bool,
+) -> Result<SocketInfo, CoreError> {
+  let port = u16::try_from(port)
+    .ok()
+    .filter(|port| *port != 0)
+    .ok_or_else(|| sockets_error(format!("invalid port {port}")))?;
+  let permissions = state.borrow().borrow::<PermissionsContainer>().0.clone();
+  permissions
+    .check_host(&hostname, Some(port), "connect()")
+    .map_err(sockets_error)?;
+
+  // The name may lead anywhere, so each address it resolves to is checked,
+  // and only those are connected to.
+  let addrs: Vec<SocketAddr> = tokio::net::lookup_host((hostname.as_str(), port))
+    .await
+    .map_err(sockets_error)?
+    .collect();
+  for addr in &addrs {
+    permissions
+      .check_host(&addr.ip().to_string(), Some(port), "connect()")
+      .map_err(sockets_error)?;
+  }
+  let stream = TcpStream::connect(&addrs[..])
+    .await
+    .map_err(sockets_error)?;
+  let remote_address = stream.peer_addr().map_err(sockets_error)?.to_string();
+  let local_address = stream.local_addr().map_err(sockets_error)?.to_string();
+  let connection = if secure {
+    start_tls(stream, &hostname).await?
+  } else {
+    Connection::Tcp(stream)
+  };
+
+  let socket = SocketResource::new(connection, None, BytesSent::default());
+  let rid = state.borrow_mut().resource_table.add(socket);
+  Ok(SocketInfo {
+    rid,
+    remote_address,
+    local_address,
+  })
+}
+
+/// Reads into `buf`, 0 once the server is done sending.
+#[op2(async)]
+async fn op_sockets_read(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  #[buffer] mut buf: JsBuffer,
+) -> Result<u32, CoreError> {
+  let socket = socket(&state, rid)?;
+  let cancel = RcRef::map(&socket, |s| &s.cancel);
+  let mut read = RcRef::map(&socket, |s| &s.read).borrow_mut().await;
+  let read = read.as_mut().ok_or_else(closed)?;
+  let n = read
+    .read(&mut *buf)
+    .try_or_cancel(cancel)
+    .await
+    .map_err(sockets_error)?;
+  Ok(n as u32)
+}
+
+#[op2(async)]
+async fn op_sockets_write(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  #[buffer] data: JsBuffer,
+) -> Result<(), CoreError> {
+  let socket = socket(&state, rid)?;
+  let cancel = RcRef::map(&socket, |s| &s.cancel);
+  let mut write = RcRef::map(&socket, |s| &s.write).borrow_mut().await;
+  let write = write.as_mut().ok_or_else(closed)?;
+  write
+    .write_all(&data)
+    .try_or_cancel(cancel)
+    .await
+    .map_err(sockets_error)?;
+  socket.sent.count(data.len());
+  Ok(())
+}
+
+/// Tells the server nothing more is coming, keeping the socket readable.
+#[op2(async)]
+async fn op_sockets_shutdown(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+) -> Result<(), CoreError> {
+  let socket = socket(&state, rid)?;
+  let mut write = RcRef::map(&socket, |s| &s.write).borrow_mut().await;
+  let write = write.as_mut().ok_or_else(closed)?;
+  write.shutdown().await.map_err(sockets_error)
+}
+
+/// Upgrades a plain connection to TLS, returning the socket that replaces it.
+#[op2(async)]
+#[smi]
+async fn op_sockets_start_tls(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  #[string] hostname: String,
+) -> Result<ResourceId, CoreError> {
+  let socket = state
+    .borrow_mut()
+    .resource_table
+    .take::<SocketResource>(rid)
+    .map_err(|_| closed())?;
+  // Reads still waiting on the plain connection would never see the data.
+  socket.cancel.cancel();
+  let read = RcRef::map(&socket, |s| &s.read).borrow_mut().await.take();
+  let write = RcRef::map(&socket, |s| &s.write).borrow_mut().await.take();
+  let (Some(read), Some(write)) = (read, write) else {
+    return Err(closed());
+  };
+  let Connection::Tcp(stream) = read.unsplit(write) else {
+    return Err(sockets_error("the socket already uses TLS"));
+  };
+
+  let connection = start_tls(stream, &hostname).await?;
+  let sent = socket.sent.clone();
+  Ok(
+    state
+      .borrow_mut()
+      .resource_table
+      .add(SocketResource::new(connection, None, sent)),
+  )
+}
+
+/// What a `connect()` handler threw.
+#[derive(Deserialize)]
+struct HandlerError {
+  message: String,
+  stack: Option<String>,
+}
+
+/// Called once a `connect()` handler settled and its socket closed.
+#[op2]
+fn op_sockets_connection_end(
+  state: &mut OpState,
+  #[smi] rid: ResourceId,
+  #[serde] error: Option<HandlerError>,
+) {
+  let Some(ConnectionHost(sender)) = state.try_borrow::<ConnectionHost>() else {
+    return;
+  };
+  if let Some(sender) = sender.upgrade() {
+    let error = error.map(|e| WorkerError::Exception {
+      message: e.message,
+      stack: e.stack,
+    });
+    let _ = sender.send(WorkerMessage::ConnectionEnd(ConnectionEnd {
+      socket: rid,
+      error,
+    }));
+  }
+}
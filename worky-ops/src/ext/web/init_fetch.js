@@ -6,9 +6,59 @@ import * as response from "ext:deno_fetch/23_response.js";
 import * as fetch from "ext:deno_fetch/26_fetch.js";
 import * as eventSource from "ext:deno_fetch/27_eventsource.js";
 
+import { ReadableStreamPrototype } from "ext:deno_web/06_streams.js";
+import { core, primordials } from "ext:core/mod.js";
+
+const { ObjectPrototypeIsPrototypeOf, TypedArrayPrototypeGetByteLength } = primordials;
+
 Deno.core.setWasmStreamingCallback(fetch.handleWasmStreaming);
 
-import { applyToGlobal, writeable, nonEnumerable } from "ext:worky_js/utils.js";
+import {
+  applyToGlobal,
+  writeable,
+  nonEnumerable,
+  registerHostHook,
+} from "ext:worky_js/utils.js";
+
+// Lays a Response out for the host: its status, header list and body, the
+// body being either the bytes deno_fetch already extracted or a reader over
+// its stream. Returns null for anything that is not a Response.
+function extractResponse(res) {
+  if (!ObjectPrototypeIsPrototypeOf(response.ResponsePrototype, res)) {
+    return null;
+  }
+
+  const inner = response.toInnerResponse(res);
+  let body = null;
+  let reader = null;
+  let length = null;
+
+  if (inner.body !== null) {
+    if (inner.body.unusable()) {
+      throw new TypeError("Response body has already been used");
+    }
+
+    const stream = inner.body.streamOrStatic;
+    if (ObjectPrototypeIsPrototypeOf(ReadableStreamPrototype, stream)) {
+      reader = stream.getReader();
+      length = inner.body.length;
+    } else {
+      body = typeof stream.body === "string" ? core.encode(stream.body) : stream.body;
+      stream.consumed = true;
+      length = TypedArrayPrototypeGetByteLength(body);
+    }
+  }
+
+  return {
+    status: inner.status,
+    headers: inner.headerList,
+    body,
+    reader,
+    length,
+  };
+}
+
+registerHostHook("extractResponse", extractResponse);
 
 applyToGlobal({
   fetch: writeable(fetch.fetch),
@@ -610,25 +610,33 @@ pub struct RestrictedWebPermissions;
 
 impl RestrictedWebPermissions {
   fn is_blocked(&self, host: &str) -> bool {
-    if host == "localhost" {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if host == "localhost" || host.ends_with(".localhost") {
       return true;
     }
-    if let Ok(ip) = host.parse::<std::net::IpAddr>() {
-      if ip.is_loopback() || ip.is_unspecified() {
-        return true;
-      }
-      match ip {
-        std::net::IpAddr::V4(ipv4) => ipv4.is_private() || ipv4.is_link_local(),
-        std::net::IpAddr::V6(ipv6) => {
-          (ipv6.segments()[0] & 0xfe00) == 0xfc00 || ipv6.is_unicast_link_local()
-        }
-      }
+    let ip = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = ip.parse::<std::net::IpAddr>() {
+      is_blocked_ip(ip)
     } else {
       false
     }
   }
 }
 
+/// Whether `ip` is on this machine or a private network.
+fn is_blocked_ip(ip: std::net::IpAddr) -> bool {
+  if ip.is_loopback() || ip.is_unspecified() {
+    return true;
+  }
+  match ip {
+    std::net::IpAddr::V4(ipv4) => ipv4.is_private() || ipv4.is_link_local(),
+    std::net::IpAddr::V6(ipv6) => match ipv6.to_ipv4_mapped() {
+      Some(ipv4) => is_blocked_ip(ipv4.into()),
+      None => (ipv6.segments()[0] & 0xfe00) == 0xfc00 || ipv6.is_unicast_link_local(),
+    },
+  }
+}
+
 impl WebPermissions for RestrictedWebPermissions {
   fn allow_hrtime(&self) -> bool {
     false
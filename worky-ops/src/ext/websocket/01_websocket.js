@@ -0,0 +1,162 @@
+import { applyToGlobal, nonEnumerable, registerHostHook } from "ext:worky_js/utils.js";
+import { Headers } from "ext:deno_fetch/20_headers.js";
+import * as response from "ext:deno_fetch/23_response.js";
+
+const core = globalThis.Deno.core;
+
+// Socket ids by socket, for both ends once the server end is accepted.
+const socketIds = new WeakMap();
+// The client end of each pair whose server end has not been accepted yet.
+const peers = new WeakMap();
+// Server ends by socket id, so a socket keeps its identity across events.
+const servers = new Map();
+// Responses that hand a socket to the client.
+const upgrades = new WeakMap();
+
+function socketId(ws) {
+    const id = socketIds.get(ws);
+    if (id === undefined) {
+        throw new TypeError("The WebSocket has not been accepted");
+    }
+    return id;
+}
+
+// One end of a WebSocket between the worker and a client, as made by
+// `new WebSocketPair()`. The host holds the connection, so the server end is
+// usable once a durable object accepted it with `ctx.acceptWebSocket()`.
+class WorkerWebSocket {
+    static CONNECTING = 0;
+    static OPEN = 1;
+    static CLOSING = 2;
+    static CLOSED = 3;
+
+    get readyState() {
+        const id = socketIds.get(this);
+        return id === undefined ? WorkerWebSocket.CONNECTING : core.ops.op_ws_ready_state(id);
+    }
+
+    accept() {
+        throw new TypeError("WebSockets are accepted by durable objects, with ctx.acceptWebSocket()");
+    }
+
+    send(message) {
+        const id = socketId(this);
+        if (typeof message === "string") {
+            core.ops.op_ws_send_text(id, message);
+        } else if (ArrayBuffer.isView(message)) {
+            core.ops.op_ws_send_binary(id, new Uint8Array(message.buffer, message.byteOffset, message.byteLength));
+        } else if (message instanceof ArrayBuffer) {
+            core.ops.op_ws_send_binary(id, new Uint8Array(message));
+        } else {
+            throw new TypeError("send() takes a string, an ArrayBuffer or an ArrayBuffer view");
+        }
+    }
+
+    close(code = 1000, reason = "") {
+        core.ops.op_ws_close(socketId(this), code, String(reason));
+    }
+
+    // Keeps `value` with the socket, where it outlives the object's eviction.
+    serializeAttachment(value) {
+        const bytes = value === undefined ? null : core.serialize(value);
+        core.ops.op_ws_set_attachment(socketId(this), bytes);
+    }
+
+    deserializeAttachment() {
+        const bytes = core.ops.op_ws_get_attachment(socketId(this));
+        return bytes === null ? null : core.deserialize(bytes);
+    }
+}
+
+class WebSocketPair {
+    constructor() {
+        const client = new WorkerWebSocket();
+        const server = new WorkerWebSocket();
+        peers.set(server, client);
+        this[0] = client;
+        this[1] = server;
+    }
+}
+
+// Binds the server end of a pair to the socket `register()` sets up in the
+// host, which returns its id.
+function acceptServer(server, register) {
+    const client = peers.get(server);
+    if (client === undefined) {
+        throw new TypeError("acceptWebSocket() takes the server end of a new WebSocketPair");
+    }
+    const id = register();
+    peers.delete(server);
+    socketIds.set(server, id);
+    socketIds.set(client, id);
+    servers.set(id, server);
+}
+
+// The server end of socket `id`.
+function serverSocket(id) {
+    let ws = servers.get(id);
+    if (ws === undefined) {
+        ws = new WorkerWebSocket();
+        socketIds.set(ws, id);
+        servers.set(id, ws);
+    }
+    return ws;
+}
+
+function forgetSocket(id) {
+    servers.delete(id);
+}
+
+// The client end of socket `id`, for a response passed on from another
+// worker.
+function clientSocket(id) {
+    const ws = new WorkerWebSocket();
+    socketIds.set(ws, id);
+    return ws;
+}
+
+// `new Response(null, { status: 101, webSocket })` hands the client end of an
+// accepted pair to the client.
+function upgradeResponse(init) {
+    const { webSocket } = init;
+    if (init.status !== 101) {
+        throw new RangeError("A Response with a webSocket must have status 101");
+    }
+    if (!socketIds.has(webSocket) || servers.get(socketIds.get(webSocket)) === webSocket) {
+        throw new TypeError("webSocket must be the client end of an accepted WebSocketPair");
+    }
+    const inner = response.newInnerResponse(101);
+    inner.headerList = [...new Headers(init.headers)];
+    const res = response.fromInnerResponse(inner, "immutable");
+    upgrades.set(res, webSocket);
+    return res;
+}
+
+const Response = new Proxy(response.Response, {
+    construct(target, args, newTarget) {
+        if (args[1]?.webSocket == null) {
+            return Reflect.construct(target, args, newTarget);
+        }
+        return upgradeResponse(args[1]);
+    },
+});
+
+Object.defineProperty(response.Response.prototype, "webSocket", {
+    get() {
+        return upgrades.get(this) ?? null;
+    },
+    configurable: true,
+});
+
+// The id of the socket a response hands to the client, if any.
+registerHostHook("responseWebSocket", (res) => {
+    const ws = upgrades.get(res);
+    return ws === undefined ? null : socketIds.get(ws);
+});
+
+applyToGlobal({
+    Response: nonEnumerable(Response),
+    WebSocketPair: nonEnumerable(WebSocketPair),
+});
+
+export { acceptServer, clientSocket, forgetSocket, serverSocket, socketId, WorkerWebSocket };
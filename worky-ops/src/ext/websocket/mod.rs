@@ -0,0 +1,143 @@
+use super::{durable::worker_key, ExtensionTrait};
+use deno_core::error::CoreError;
+use deno_core::{extension, op2, Extension, JsBuffer, OpState, ToJsBuffer};
+use worky_common::deno_error;
+use worky_common::websocket::{self, HeldSockets, SocketCommand, SocketMessage};
+
+/// Largest attachment `ws.serializeAttachment()` takes, once serialized.
+const MAX_ATTACHMENT_SIZE: usize = 2048;
+
+extension!(
+  worky_websocket,
+  deps = [worky_js],
+  ops = [
+    op_ws_send_text,
+    op_ws_send_binary,
+    op_ws_close,
+    op_ws_ready_state,
+    op_ws_get_attachment,
+    op_ws_set_attachment,
+  ],
+  esm_entry_point = "ext:worky_websocket/01_websocket.js",
+  esm = [ dir "src/ext/websocket", "01_websocket.js" ],
+  state = |state| state.put(HeldSockets::default()),
+);
+impl ExtensionTrait<()> for worky_websocket {
+  fn init((): ()) -> Extension {
+    worky_websocket::init()
+  }
+}
+
+pub fn extensions(is_snapshot: bool) -> Vec<Extension> {
+  vec![worky_websocket::build((), is_snapshot)]
+}
+
+fn websocket_error(err: impl std::fmt::Display) -> CoreError {
+  deno_error!(err.to_string())
+}
+
+/// Records a socket the worker took on while handling the current message,
+/// see [`HeldSockets`].
+pub(crate) fn hold(state: &mut OpState, socket: u32) {
+  state.borrow_mut::<HeldSockets>().0.push(socket);
+}
+
+/// Sockets are only reachable from the worker whose object accepted them.
+fn send(state: &OpState, socket: u32, command: SocketCommand) -> Result<(), CoreError> {
+  if websocket::send(socket, &worker_key(state), command) {
+    Ok(())
+  } else {
+    Err(websocket_error("the WebSocket is closed"))
+  }
+}
+
+#[op2(fast)]
+fn op_ws_send_text(
+  state: &mut OpState,
+  socket: u32,
+  #[string] text: String,
+) -> Result<(), CoreError> {
+  send(
+    state,
+    socket,
+    SocketCommand::Send(SocketMessage::Text(text)),
+  )
+}
+
+#[op2(fast)]
+fn op_ws_send_binary(
+  state: &mut OpState,
+  socket: u32,
+  #[buffer] data: &[u8],
+) -> Result<(), CoreError> {
+  send(
+    state,
+    socket,
+    SocketCommand::Send(SocketMessage::Binary(data.to_vec())),
+  )
+}
+
+#[op2(fast)]
+fn op_ws_close(
+  state: &mut OpState,
+  socket: u32,
+  code: u32,
+  #[string] reason: String,
+) -> Result<(), CoreError> {
+  if code != 1000 && !(3000..=4999).contains(&code) {
+    return Err(websocket_error(format!(
+      "invalid close code {code}, expected 1000 or 3000-4999"
+    )));
+  }
+  if reason.len() > 123 {
+    return Err(websocket_error("close reasons are at most 123 bytes"));
+  }
+  // Closing a socket that is already closed does nothing.
+  websocket::send(
+    socket,
+    &worker_key(state),
+    SocketCommand::Close {
+      code: code as u16,
+      reason,
+    },
+  );
+  Ok(())
+}
+
+/// `WebSocket.OPEN` while the host holds the socket, `WebSocket.CLOSED` after.
+#[op2(fast)]
+fn op_ws_ready_state(state: &mut OpState, socket: u32) -> u32 {
+  if websocket::is_open(socket, &worker_key(state)) {
+    1
+  } else {
+    3
+  }
+}
+
+#[op2]
+#[serde]
+fn op_ws_get_attachment(state: &mut OpState, socket: u32) -> Option<ToJsBuffer> {
+  websocket::attachment(socket, &worker_key(state)).map(Into::into)
+}
+
+#[op2]
+fn op_ws_set_attachment(
+  state: &mut OpState,
+  socket: u32,
+  #[serde] attachment: Option<JsBuffer>,
+) -> Result<(), CoreError> {
+  let attachment = attachment.map(|a| a.to_vec());
+  if attachment
+    .as_ref()
+    .is_some_and(|a| a.len() > MAX_ATTACHMENT_SIZE)
+  {
+    return Err(websocket_error(format!(
+      "attachments are at most {MAX_ATTACHMENT_SIZE} bytes once serialized"
+    )));
+  }
+  if websocket::set_attachment(socket, &worker_key(state), attachment) {
+    Ok(())
+  } else {
+    Err(websocket_error("the WebSocket is closed"))
+  }
+}
@@ -1,5 +1,6 @@
 use super::ExtensionTrait;
 use deno_core::{extension, op2, Extension, OpState};
+use worky_common::config::WorkerConfig;
 
 #[derive(Default, Clone)]
 pub struct WorkyInitOptions {
@@ -7,6 +8,7 @@ pub struct WorkyInitOptions {
   pub worker_address: String,
   pub kv_db: Option<sled::Db>,
   pub secrets: std::collections::HashMap<String, String>,
+  pub config: WorkerConfig,
 }
 
 pub struct WorkerState {
@@ -21,10 +23,13 @@ extension!(
   options = {
     opts: WorkyInitOptions
   },
-  state = |state, config| state.put(WorkerState {
-    worker_name: config.opts.worker_name.clone(),
-    worker_address: config.opts.worker_address.clone(),
-  })
+  state = |state, config| {
+    state.put(WorkerState {
+      worker_name: config.opts.worker_name.clone(),
+      worker_address: config.opts.worker_address.clone(),
+    });
+    state.put(config.opts.config);
+  }
 );
 impl ExtensionTrait<WorkyInitOptions> for worky_js {
   fn init(opts: WorkyInitOptions) -> Extension {
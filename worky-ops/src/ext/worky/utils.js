@@ -24,4 +24,29 @@ const getterOnly = (getter) => {
 const applyToGlobal = (properties) =>
   Object.defineProperties(globalThis, properties);
 
-export { nonEnumerable, readOnly, writeable, getterOnly, applyToGlobal };
+// Functions the host (worky-api) calls into, looked up on `globalThis.__worky`.
+// The host trusts them, so user code can neither replace nor delete them.
+const hostHooks = Object.create(null);
+Object.defineProperty(globalThis, "__worky", {
+  value: hostHooks,
+  writable: false,
+  enumerable: false,
+  configurable: false,
+});
+const registerHostHook = (name, hook) => {
+  Object.defineProperty(hostHooks, name, {
+    value: hook,
+    writable: false,
+    enumerable: false,
+    configurable: false,
+  });
+};
+
+export {
+  nonEnumerable,
+  readOnly,
+  writeable,
+  getterOnly,
+  applyToGlobal,
+  registerHostHook,
+};
@@ -19,12 +19,22 @@ pub fn init_ops(opts: WorkyInitOptions) -> Vec<Extension> {
     false,
   ));
   extensions.extend(ext::telemetry::extensions(false));
+  extensions.extend(ext::context::extensions(false));
   extensions.extend(vec![ext::kv::worky_kv::init(ext::kv::KvOptions {
     db: kvdb,
   })]);
   extensions.extend(vec![ext::secrets::worky_secrets::init(
     ext::secrets::SecretsOptions { secrets: secrets },
   )]);
+  extensions.extend(ext::assets::extensions(false));
+  extensions.extend(ext::ratelimit::extensions(false));
+  extensions.extend(ext::sockets::extensions(false));
+  extensions.extend(ext::websocket::extensions(false));
+  extensions.extend(ext::service::extensions(false));
+  extensions.extend(ext::queue::extensions(false));
+  extensions.extend(ext::durable::extensions(false));
+  extensions.extend(ext::broadcast::extensions(false));
+  extensions.extend(ext::env::extensions(false));
   // extensions.extend(ext::networking::extensions(false));
 
   extensions
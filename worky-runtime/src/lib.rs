@@ -11,6 +11,7 @@ use deno_core::RuntimeOptions;
 use std::fs;
 use std::path::Path;
 use std::rc::Rc;
+use worky_common::config::WorkerConfig;
 
 pub struct WorkyRuntime {
   pub js_runtime: JsRuntime,
@@ -18,16 +19,18 @@ pub struct WorkyRuntime {
 
 impl WorkyRuntime {
   pub fn new(addr: Option<String>, name: Option<String>) -> Self {
+    Self::with_config(addr, name, WorkerConfig::default())
+  }
+
+  pub fn with_config(addr: Option<String>, name: Option<String>, config: WorkerConfig) -> Self {
     let loader = Rc::new(loader::FsModuleLoader);
     let mut options = RuntimeOptions::default();
     options.module_loader = Some(loader);
-    // TODO: Implement a better DB system
-    // Instead of opening "worky_kv.db"
-    // Make a proper DB path
-    let kv_db = sled::open("worky_kv.db").ok();
+    let kv_db = worky_common::db::db();
     // For now, load secrets from env vars starting with WORKY_SECRET_
-    let secrets: std::collections::HashMap<String, String> =
-      std::env::vars().map(|(k, v)| (k, v)).collect();
+    let secrets: std::collections::HashMap<String, String> = std::env::vars()
+      .filter_map(|(k, v)| k.strip_prefix("WORKY_SECRET_").map(|k| (k.to_string(), v)))
+      .collect();
 
     options.extensions = worky_ops::init_ops(if addr.is_some() && name.is_some() {
       worky_ops::WorkyInitOptions {
@@ -35,12 +38,14 @@ impl WorkyRuntime {
         worker_name: name.unwrap(),
         kv_db,
         secrets,
+        config,
       }
     } else if addr.is_some() {
       worky_ops::WorkyInitOptions {
         worker_address: addr.unwrap(),
         kv_db,
         secrets,
+        config,
         ..Default::default()
       }
     } else if name.is_some() {
@@ -48,12 +53,14 @@ impl WorkyRuntime {
         worker_name: name.unwrap(),
         kv_db,
         secrets,
+        config,
         ..Default::default()
       }
     } else {
       worky_ops::WorkyInitOptions {
         kv_db,
         secrets,
+        config,
         ..Default::default()
       }
     });
@@ -183,4 +190,249 @@ mod tests {
     }
     assert!(result.is_ok());
   }
+
+  #[tokio::test]
+  async fn test_env_bindings() {
+    let mut config = WorkerConfig::default();
+    config
+      .vars
+      .insert("GREETING".to_string(), "hello".to_string());
+    config
+      .kv_namespaces
+      .push(worky_common::config::KvNamespaceBinding {
+        binding: "SESSIONS".to_string(),
+        id: "sessions".to_string(),
+      });
+
+    let mut runtime = WorkyRuntime::with_config(None, None, config);
+    let code = r#"
+        const env = __worky.createEnv();
+        if (env.GREETING !== "hello") throw new Error(`vars failed: got '${env.GREETING}'`);
+
+        await env.SESSIONS.put("env_key", "session_value");
+        const val = await env.SESSIONS.get("env_key");
+        if (val !== "session_value") throw new Error(`KV binding failed: got '${val}'`);
+        if (await KV.get("env_key")) throw new Error("KV namespaces are not isolated");
+        await env.SESSIONS.delete("env_key");
+    "#;
+    let result = runtime.run(code).await;
+    match &result {
+      Err(err) => eprintln!("{err}"),
+      _ => {}
+    }
+    assert!(result.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_connect_permissions() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+        for (const address of ["127.0.0.1:6379", "127.1:6379", "localhost.:6379", "[::ffff:127.0.0.1]:6379"]) {
+            const socket = connect(address);
+            try {
+                await socket.opened;
+                throw new Error(`connect() reached ${address}`);
+            } catch (e) {
+                if (!e.message.includes("blocked")) throw e;
+            }
+        }
+        try {
+            connect({ hostname: "example.com", port: 70000 });
+            throw new Error("connect() took an invalid port");
+        } catch (e) {
+            if (!(e instanceof TypeError)) throw e;
+        }
+    "#;
+    let result = runtime.run(code).await;
+    match &result {
+      Err(err) => eprintln!("{err}"),
+      _ => {}
+    }
+    assert!(result.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_broadcast_channel() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+        const sender = new BroadcastChannel("cache");
+        const receiver = new BroadcastChannel("cache");
+        const other = new BroadcastChannel("sessions");
+        other.onmessage = () => { throw new Error("message crossed channels"); };
+        sender.onmessage = () => { throw new Error("message came back to its sender"); };
+
+        const received = new Promise((resolve) => { receiver.onmessage = (e) => resolve(e.data); });
+        const message = { key: "user:1", at: new Date(0) };
+        sender.postMessage(message);
+        const data = await received;
+        if (data === message || data.key !== "user:1" || !(data.at instanceof Date)) {
+            throw new Error(`message was not cloned: ${JSON.stringify(data)}`);
+        }
+
+        receiver.close();
+        try {
+            receiver.postMessage("late");
+            throw new Error("a closed channel posted");
+        } catch (e) {
+            if (e.name !== "InvalidStateError") throw e;
+        }
+        sender.close();
+        other.close();
+    "#;
+    let result = runtime.run(code).await;
+    match &result {
+      Err(err) => eprintln!("{err}"),
+      _ => {}
+    }
+    assert!(result.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_kv_list() {
+    let mut config = WorkerConfig::default();
+    config
+      .kv_namespaces
+      .push(worky_common::config::KvNamespaceBinding {
+        binding: "INDEX".to_string(),
+        id: "list_test".to_string(),
+      });
+
+    let mut runtime = WorkyRuntime::with_config(None, None, config);
+    let code = r#"
+        const env = __worky.createEnv();
+        for (const key of ["session:1", "session:2", "session:3", "user:1"]) {
+            await env.INDEX.put(key, "x");
+        }
+
+        const first = await env.INDEX.list({ prefix: "session:", limit: 2 });
+        const names = (page) => page.keys.map((key) => key.name).join();
+        if (names(first) !== "session:1,session:2" || first.list_complete) {
+            throw new Error(`first page: ${JSON.stringify(first)}`);
+        }
+        const rest = await env.INDEX.list({ prefix: "session:", cursor: first.cursor });
+        if (names(rest) !== "session:3" || !rest.list_complete) {
+            throw new Error(`second page: ${JSON.stringify(rest)}`);
+        }
+
+        for (const { name } of (await env.INDEX.list()).keys) {
+            await env.INDEX.delete(name);
+        }
+    "#;
+    let result = runtime.run(code).await;
+    match &result {
+      Err(err) => eprintln!("{err}"),
+      _ => {}
+    }
+    assert!(result.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_kv_typed_values() {
+    let mut config = WorkerConfig::default();
+    config
+      .kv_namespaces
+      .push(worky_common::config::KvNamespaceBinding {
+        binding: "ASSETS".to_string(),
+        id: "typed_test".to_string(),
+      });
+
+    let mut runtime = WorkyRuntime::with_config(None, None, config);
+    let code = r#"
+        const env = __worky.createEnv();
+        await env.ASSETS.put("bytes", new Uint8Array([0, 159, 255]));
+        const buffer = await env.ASSETS.get("bytes", { type: "arrayBuffer" });
+        if (new Uint8Array(buffer).join() !== "0,159,255") {
+            throw new Error(`arrayBuffer: ${new Uint8Array(buffer).join()}`);
+        }
+        const stream = await env.ASSETS.get("bytes", "stream");
+        const { value: chunk } = await stream.getReader().read();
+        if (chunk.join() !== "0,159,255") {
+            throw new Error("stream");
+        }
+
+        await env.ASSETS.put("config", { retries: 3 }, { metadata: { owner: "ops" } });
+        if ((await env.ASSETS.get("config", "json")).retries !== 3) {
+            throw new Error("json");
+        }
+        const { value, metadata } = await env.ASSETS.getWithMetadata("config", { type: "json" });
+        if (value.retries !== 3 || metadata.owner !== "ops") {
+            throw new Error(`getWithMetadata: ${JSON.stringify({ value, metadata })}`);
+        }
+        const [key] = (await env.ASSETS.list({ prefix: "config" })).keys;
+        if (key.metadata?.owner !== "ops") {
+            throw new Error(`list: ${JSON.stringify(key)}`);
+        }
+
+        const missing = await env.ASSETS.getWithMetadata("missing");
+        if (missing.value !== null || missing.metadata !== null) {
+            throw new Error("missing");
+        }
+        await env.ASSETS.delete("bytes");
+        await env.ASSETS.delete("config");
+    "#;
+    let result = runtime.run(code).await;
+    match &result {
+      Err(err) => eprintln!("{err}"),
+      _ => {}
+    }
+    assert!(result.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_host_hooks_are_read_only() {
+    let mut runtime = WorkyRuntime::new(None, None);
+    let code = r#"
+        const hook = __worky.settleWaitUntil;
+        if (Reflect.set(__worky, "settleWaitUntil", () => {})) {
+            throw new Error("settleWaitUntil was replaced");
+        }
+        if (Reflect.deleteProperty(__worky, "extractResponse")) {
+            throw new Error("extractResponse was deleted");
+        }
+        if (__worky.settleWaitUntil !== hook) {
+            throw new Error("settleWaitUntil changed");
+        }
+    "#;
+    let result = runtime.run(code).await;
+    match &result {
+      Err(err) => eprintln!("{err}"),
+      _ => {}
+    }
+    assert!(result.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_kv_ops_need_a_binding() {
+    let mut config = WorkerConfig::default();
+    config
+      .kv_namespaces
+      .push(worky_common::config::KvNamespaceBinding {
+        binding: "CACHE".to_string(),
+        id: "bound_test".to_string(),
+      });
+
+    let mut runtime = WorkyRuntime::with_config(None, None, config);
+    let code = r#"
+        const env = __worky.createEnv();
+        await env.CACHE.put("key", "bound");
+        if (await env.CACHE.get("key") !== "bound") {
+            throw new Error("bound namespace");
+        }
+        try {
+            await Deno.core.ops.op_kv_get("bound_test", "key");
+            throw new Error("reached a namespace by its id");
+        } catch (err) {
+            if (!String(err.message).includes("no KV namespace is bound")) {
+                throw err;
+            }
+        }
+        await env.CACHE.delete("key");
+    "#;
+    let result = runtime.run(code).await;
+    match &result {
+      Err(err) => eprintln!("{err}"),
+      _ => {}
+    }
+    assert!(result.is_ok());
+  }
 }
@@ -1,6 +1,7 @@
 use interprocess::local_socket::{prelude::*, GenericNamespaced, ListenerOptions, Stream};
 use std::io::{self, prelude::*, BufReader};
 use std::sync::Mutex;
+use std::time::SystemTime;
 use tokio::task::JoinHandle;
 
 lazy_static::lazy_static! {
@@ -32,6 +33,23 @@ pub fn run() -> Result<(), anyhow::Error> {
 
   println!("Daemon running on {}", addr);
 
+  TOK_ASYNC_HANDLES
+    .lock()
+    .unwrap()
+    .push(worky_daemon::scheduler::spawn());
+  TOK_ASYNC_HANDLES
+    .lock()
+    .unwrap()
+    .push(worky_daemon::queues::spawn());
+  TOK_ASYNC_HANDLES
+    .lock()
+    .unwrap()
+    .push(worky_daemon::alarms::spawn());
+  TOK_ASYNC_HANDLES
+    .lock()
+    .unwrap()
+    .push(worky_daemon::kv::spawn());
+
   TOK_ASYNC_HANDLES
     .lock()
     .unwrap()
@@ -64,18 +82,21 @@ async fn handle_request(req: Request) -> Response {
       status: "ok".into(),
       message: Some("Started".into()),
       error: None,
+      data: None,
     },
 
     Request::Stop {} => Response {
       status: "ok".into(),
       message: Some("Stopping daemon…".into()),
       error: None,
+      data: None,
     },
 
     Request::Restart {} => Response {
       status: "ok".into(),
       message: Some("Restarting daemon…".into()),
       error: None,
+      data: None,
     },
 
     Request::Load {
@@ -83,6 +104,7 @@ async fn handle_request(req: Request) -> Response {
       path,
       refresh,
       name,
+      config,
     } => {
       println!(
         "LOAD request:
@@ -93,12 +115,19 @@ async fn handle_request(req: Request) -> Response {
         address, path, refresh, name
       );
 
-      worky_store::register_worker(address, path, name).await;
-
-      Response {
-        status: "ok".into(),
-        message: Some("Load complete".into()),
-        error: None,
+      match worky_store::register_worker(address, path, name, config).await {
+        Ok(()) => Response {
+          status: "ok".into(),
+          message: Some("Load complete".into()),
+          error: None,
+          data: None,
+        },
+        Err(e) => Response {
+          status: "err".into(),
+          message: None,
+          error: Some(e.to_string()),
+          data: None,
+        },
       }
     }
 
@@ -110,24 +139,70 @@ async fn handle_request(req: Request) -> Response {
           status: "ok".into(),
           message: Some("Unload complete".into()),
           error: None,
+          data: None,
         }
       } else {
         Response {
           status: "err".into(),
           message: None,
           error: Some("Worker not found".into()),
+          data: None,
+        }
+      }
+    }
+
+    Request::Status { address } => {
+      let status = worky_store::worker_status(address.as_deref());
+      Response {
+        status: "ok".into(),
+        message: None,
+        error: None,
+        data: serde_json::to_value(status).ok(),
+      }
+    }
+
+    Request::Trigger {
+      worker,
+      event,
+      cron,
+    } => {
+      println!("TRIGGER request: worker: {worker}, event: {event}");
+      let Some(handle) = worky_store::find_worker(&worker) else {
+        return error_response("Worker not found");
+      };
+      let result = match event.as_str() {
+        "scheduled" => {
+          let cron = cron
+            .or_else(|| handle.config.triggers.crons.first().cloned())
+            .unwrap_or_default();
+          worky_daemon::scheduler::trigger(&handle, cron, SystemTime::now()).await
         }
+        _ => return error_response(&format!("Unknown event {event}")),
+      };
+      match result {
+        Ok(()) => Response {
+          status: "ok".into(),
+          message: Some("Trigger complete".into()),
+          error: None,
+          data: None,
+        },
+        Err(e) => error_response(&e.to_string()),
       }
     }
   }
 }
 
-fn send_error(stream: &mut impl Write, msg: &str) {
-  let resp = Response {
+fn error_response(msg: &str) -> Response {
+  Response {
     status: "err".into(),
     message: None,
     error: Some(msg.into()),
-  };
+    data: None,
+  }
+}
+
+fn send_error(stream: &mut impl Write, msg: &str) {
+  let resp = error_response(msg);
   let _ = stream.write_all(serde_json::to_string(&resp).unwrap().as_bytes());
 }
 
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use worky_common::config::WorkerConfig;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "action", rename_all = "lowercase")]
@@ -14,10 +15,25 @@ pub enum Request {
     refresh: Option<bool>,
     #[serde(default)]
     name: Option<String>,
+    #[serde(default)]
+    config: WorkerConfig,
   },
   Unload {
     address: String,
   },
+  Status {
+    #[serde(default)]
+    address: Option<String>,
+  },
+  /// Invokes a worker's handler for `event` by hand.
+  Trigger {
+    /// Name or address of the worker.
+    worker: String,
+    event: String,
+    /// The `controller.cron` a scheduled handler sees.
+    #[serde(default)]
+    cron: Option<String>,
+  },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -25,4 +41,6 @@ pub struct Response {
   pub status: String,
   pub message: Option<String>,
   pub error: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub data: Option<serde_json::Value>,
 }
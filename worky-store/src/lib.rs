@@ -3,33 +3,134 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use tokio::task::JoinHandle;
-use worky_api::{listen_to_addr, spawn_worker};
-use worky_common::workers::WorkerHandle;
+use worky_api::{bind, serve, spawn_worker};
+use worky_common::config::{Protocol, WorkerConfig};
+use worky_common::cron::Schedule;
+use worky_common::workers::{self, WorkerHandle, WorkerStatus};
 
 pub static WORKERS: Lazy<Mutex<HashMap<String, Arc<WorkerHandle>>>> =
   Lazy::new(|| Mutex::new(HashMap::new()));
 lazy_static::lazy_static! {
-  pub static ref LISTENER_HANDLES: Mutex<HashMap<String, JoinHandle<()>>> = Mutex::new(HashMap::new());
+  pub static ref LISTENER_HANDLES: Mutex<HashMap<String, Vec<JoinHandle<()>>>> = Mutex::new(HashMap::new());
 }
 
-pub async fn register_worker(addr: String, path: PathBuf, name: Option<String>) {
+/// Starts the worker and serves it on `addr` and every address in
+/// `config.listen`. Fails without starting anything if one of them cannot be
+/// bound, the config is invalid or the worker's `init()` handler fails.
+pub async fn register_worker(
+  addr: String,
+  path: PathBuf,
+  name: Option<String>,
+  config: WorkerConfig,
+) -> anyhow::Result<()> {
+  validate(&addr, &config)?;
+
+  let mut listeners = Vec::new();
+  for listen_addr in std::iter::once(&addr).chain(&config.listen) {
+    let listener = bind(listen_addr)
+      .await
+      .map_err(|e| anyhow::anyhow!("could not listen on {listen_addr}: {e}"))?;
+    listeners.push(listener);
+  }
+
+  let handle = spawn_worker(addr.clone(), path.clone(), name.clone(), config)
+    .await
+    .map_err(|e| anyhow::anyhow!("worker failed to start: {e}"))?;
   println!("Worker registered {addr} from {path:?} as  {name:?}!!");
-  let handle = spawn_worker(addr.clone(), path, name);
   let handle = Arc::new(handle);
   WORKERS.lock().unwrap().insert(addr.clone(), handle.clone());
+  workers::register_service(handle.clone());
+
+  let tasks = listeners
+    .into_iter()
+    .map(|listener| tokio::spawn(serve(listener, handle.clone())))
+    .collect();
+  LISTENER_HANDLES.lock().unwrap().insert(addr, tasks);
+  Ok(())
+}
+
+fn validate(addr: &str, config: &WorkerConfig) -> anyhow::Result<()> {
+  for cron in &config.triggers.crons {
+    Schedule::parse(cron)?;
+  }
+  for namespace in &config.durable_objects {
+    if namespace.class_name.is_empty() {
+      anyhow::bail!(
+        "durable object binding {} names no class",
+        namespace.binding
+      );
+    }
+  }
+  if config.tail_consumers.iter().any(|c| c.service.is_empty()) {
+    anyhow::bail!("tail consumers must name the worker they send to");
+  }
+  if config.protocol == Protocol::Tcp {
+    let mut listen_addrs = std::iter::once(addr).chain(config.listen.iter().map(String::as_str));
+    if let Some(unix) = listen_addrs.find(|a| a.starts_with("unix:")) {
+      anyhow::bail!("a tcp worker cannot listen on {unix}");
+    }
+  }
 
-  listen_to_addr(addr, handle).await;
+  let workers = WORKERS.lock().unwrap();
+  for consumer in &config.queues.consumers {
+    if consumer.dead_letter_queue.as_ref() == Some(&consumer.queue) {
+      anyhow::bail!(
+        "queue {} cannot be its own dead-letter queue",
+        consumer.queue
+      );
+    }
+    let taken = workers.values().find(|handle| {
+      let consumers = &handle.config.queues.consumers;
+      handle.addr != addr && consumers.iter().any(|c| c.queue == consumer.queue)
+    });
+    if let Some(handle) = taken {
+      anyhow::bail!(
+        "queue {} is already consumed by the worker at {}",
+        consumer.queue,
+        handle.addr
+      );
+    }
+  }
+  Ok(())
 }
 
 pub fn unregister_worker(addr: String) -> bool {
-  // TODO: We need a way to stop the listener.
-  // For now, we just remove it from the map, but the listener task is still running.
-  // In a real implementation, we would need a shutdown signal.
-  // However, since listen_to_addr binds to a port, we can't easily stop it without a signal.
-  // Let's assume for this iteration we just remove it from tracking.
-  // Actually, `listen_to_addr` runs `axum::serve`, which can be graceful shutdown.
-  // But `worky-api` doesn't expose a shutdown mechanism yet.
-  // I will add a TODO and just remove from map for now.
-  LISTENER_HANDLES.lock().unwrap().get(&addr).unwrap().abort();
-  WORKERS.lock().unwrap().remove(&addr).is_some()
+  // Once the listeners and the handle are gone, so are the last senders of
+  // the worker's channel, which stops the worker thread.
+  for task in LISTENER_HANDLES
+    .lock()
+    .unwrap()
+    .remove(&addr)
+    .unwrap_or_default()
+  {
+    task.abort();
+  }
+
+  workers::unregister_service(&addr);
+  let Some(handle) = WORKERS.lock().unwrap().remove(&addr) else {
+    return false;
+  };
+  for listen_addr in std::iter::once(&handle.addr).chain(&handle.config.listen) {
+    if let Some(path) = listen_addr.strip_prefix("unix:") {
+      let _ = std::fs::remove_file(path);
+    }
+  }
+  true
+}
+
+/// Status of the worker loaded at `addr`, or of every worker.
+pub fn worker_status(addr: Option<&str>) -> Vec<WorkerStatus> {
+  let workers = WORKERS.lock().unwrap();
+  let mut status: Vec<_> = workers
+    .values()
+    .filter(|handle| addr.is_none_or(|addr| handle.addr == addr))
+    .map(|handle| handle.status())
+    .collect();
+  status.sort_by(|a, b| a.address.cmp(&b.address));
+  status
+}
+
+/// The worker loaded with the name `worker`, or else at the address `worker`.
+pub fn find_worker(worker: &str) -> Option<Arc<WorkerHandle>> {
+  workers::service(worker).or_else(|| WORKERS.lock().unwrap().get(worker).cloned())
 }